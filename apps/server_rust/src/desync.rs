@@ -0,0 +1,318 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::ptr;
+
+use tokio::io::Interest;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{self, Duration, Instant};
+
+/// How long to wait for the kernel to put the decoy on the wire before its
+/// buffer is rewritten with the real bytes.
+const SEND_WAIT: Duration = Duration::from_millis(500);
+const MAX_PROBE_TTL: u8 = 32;
+
+/// Sends `fake` with a hop limit of `ttl`, so that it passes the DPI box but
+/// expires before reaching the server, while the same TCP sequence range is
+/// retransmitted later with the contents of `real`.
+///
+/// The decoy is handed to the socket by reference through vmsplice/splice.
+/// Once it has left, the shared page is overwritten with `real`, so the
+/// kernel's retransmission (at the restored TTL) carries the real bytes.
+pub async fn send_fake(stream: &TcpStream, fake: &[u8], real: &[u8], ttl: u8) -> io::Result<()> {
+    if fake.len() != real.len() || fake.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "decoy and real data must have the same non-zero length",
+        ));
+    }
+    let fd = stream.as_raw_fd();
+    let ipv6 = stream.peer_addr()?.is_ipv6();
+
+    let buffer = SharedBuffer::new(fake)?;
+    let pipe = Pipe::new()?;
+    pipe.fill(&buffer)?;
+
+    let original_ttl = get_hop_limit(fd, ipv6)?;
+    set_hop_limit(fd, ipv6, ttl as i32)?;
+    let sent = splice_all(stream, &pipe, fake.len()).await;
+    let waited = if sent.is_ok() { wait_sent(fd).await } else { Ok(()) };
+    buffer.overwrite(real);
+    set_hop_limit(fd, ipv6, original_ttl)?;
+    sent.and(waited)
+}
+
+/// Finds the hop distance to `target` by connecting with increasing TTLs.
+/// Returns the smallest TTL for which the handshake completes.
+pub async fn probe_hops(target: SocketAddr, local: Option<IpAddr>, timeout: Duration) -> Option<u8> {
+    let (mut low, mut high) = (1u8, MAX_PROBE_TTL);
+    if !probe_connect(target, local, high, timeout).await {
+        return None;
+    }
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if probe_connect(target, local, mid, timeout).await {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Some(high)
+}
+
+async fn probe_connect(target: SocketAddr, local: Option<IpAddr>, ttl: u8, timeout: Duration) -> bool {
+    let socket = match if target.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    } {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    if let Some(ip) = local {
+        if socket.bind(SocketAddr::new(ip, 0)).is_err() {
+            return false;
+        }
+    }
+    if set_hop_limit(socket.as_raw_fd(), target.is_ipv6(), ttl as i32).is_err() {
+        return false;
+    }
+    matches!(time::timeout(timeout, socket.connect(target)).await, Ok(Ok(_)))
+}
+
+async fn splice_all(stream: &TcpStream, pipe: &Pipe, len: usize) -> io::Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        stream.writable().await?;
+        let n = stream.try_io(Interest::WRITABLE, || {
+            // SAFETY: both descriptors are valid for the duration of the call.
+            let n = unsafe {
+                libc::splice(
+                    pipe.read,
+                    ptr::null_mut(),
+                    stream.as_raw_fd(),
+                    ptr::null_mut(),
+                    remaining,
+                    libc::SPLICE_F_NONBLOCK,
+                )
+            };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        });
+        match n {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "splice wrote nothing")),
+            Ok(n) => remaining -= n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Waits until the socket has no unsent bytes left in its send queue.
+async fn wait_sent(fd: RawFd) -> io::Result<()> {
+    let deadline = Instant::now() + SEND_WAIT;
+    loop {
+        let mut unsent: libc::c_int = 0;
+        // SAFETY: SIOCOUTQNSD writes a single int.
+        if unsafe { libc::ioctl(fd, libc::SIOCOUTQNSD as _, &mut unsent) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsent == 0 {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "decoy was not sent in time"));
+        }
+        time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+fn hop_limit_option(ipv6: bool) -> (libc::c_int, libc::c_int) {
+    if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TTL)
+    }
+}
+
+fn get_hop_limit(fd: RawFd, ipv6: bool) -> io::Result<i32> {
+    let (level, name) = hop_limit_option(ipv6);
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len point to properly sized locals.
+    let rc = unsafe { libc::getsockopt(fd, level, name, &mut value as *mut _ as *mut _, &mut len) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn set_hop_limit(fd: RawFd, ipv6: bool, value: i32) -> io::Result<()> {
+    let (level, name) = hop_limit_option(ipv6);
+    let value: libc::c_int = value;
+    // SAFETY: value points to a properly sized local.
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const _,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Page-aligned anonymous memory whose pages can be shared with the kernel.
+struct SharedBuffer {
+    ptr: *mut u8,
+    len: usize,
+    mapped: usize,
+}
+
+// SAFETY: the mapping is owned exclusively by this value.
+unsafe impl Send for SharedBuffer {}
+
+impl SharedBuffer {
+    fn new(data: &[u8]) -> io::Result<Self> {
+        // SAFETY: sysconf has no preconditions.
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+        let mapped = data.len().div_ceil(page) * page;
+        // SAFETY: anonymous private mapping, checked for MAP_FAILED below.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                mapped,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let buffer = Self {
+            ptr: ptr as *mut u8,
+            len: data.len(),
+            mapped,
+        };
+        buffer.overwrite(data);
+        Ok(buffer)
+    }
+
+    fn overwrite(&self, data: &[u8]) {
+        let len = data.len().min(self.len);
+        // SAFETY: the mapping is at least `self.len` bytes long.
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr, len) };
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        // Pages still referenced by queued segments stay alive in the kernel.
+        // SAFETY: ptr/mapped come from a successful mmap.
+        unsafe { libc::munmap(self.ptr as *mut _, self.mapped) };
+    }
+}
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0 as libc::c_int; 2];
+        // SAFETY: fds has room for two descriptors.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+
+    /// Maps the buffer's pages into the pipe without copying them.
+    fn fill(&self, buffer: &SharedBuffer) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < buffer.len {
+            let iov = libc::iovec {
+                // SAFETY: offset stays within the mapping.
+                iov_base: unsafe { buffer.ptr.add(offset) } as *mut _,
+                iov_len: buffer.len - offset,
+            };
+            // SAFETY: iov describes memory owned by `buffer`.
+            let n = unsafe { libc::vmsplice(self.write, &iov, 1, 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            offset += n as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: both descriptors were returned by pipe2 and are owned here.
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::tls;
+
+    /// On loopback the decoy is not dropped, but it shares its page with the
+    /// socket: a reader that comes after the overwrite sees the real bytes.
+    #[tokio::test]
+    async fn loopback_peer_receives_the_real_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let real = tls::build_client_hello("www.example.com");
+        let decoy = tls::build_client_hello("www.iana.org");
+        let first = decoy.len();
+        assert!(real.len() > first);
+
+        send_fake(&client, &decoy, &real[..first], 1).await.unwrap();
+        let (_reader, mut writer) = client.into_split();
+        writer.write_all(&real[first..]).await.unwrap();
+
+        let mut received = vec![0u8; real.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, real);
+    }
+
+    #[tokio::test]
+    async fn decoy_and_real_lengths_must_match() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let err = send_fake(&client, b"short", b"longer", 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn probes_the_loopback_in_one_hop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        assert_eq!(probe_hops(target, None, Duration::from_secs(1)).await, Some(1));
+    }
+}
@@ -0,0 +1,1115 @@
+//! Name resolution for outbound connections, selected with `--dns`: the
+//! system resolver, DNS-over-HTTPS (RFC 8484) or DNS-over-TLS (RFC 7858).
+//! The encrypted backends send wire-format queries for A and AAAA records
+//! and keep working where the system resolver is poisoned. In front of the
+//! backend sit the names pinned with `--hosts` and a cache of lookups.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use reqwest::Client;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::OnceCell;
+use tokio::time::{self, Duration, Instant};
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// How long a lookup may take, connecting to the server included.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long answers of the system resolver are cached, as it does not give
+/// their TTL.
+const SYSTEM_TTL: Duration = Duration::from_secs(60);
+const DOT_PORT: u16 = 853;
+const DNS_MESSAGE: &str = "application/dns-message";
+
+const HEADER_LEN: usize = 12;
+/// What a client that does not announce EDNS accepts over UDP.
+const MIN_UDP_PAYLOAD: usize = 512;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+
+/// The addresses of one record type and their lowest TTL.
+type AddressResult = io::Result<(Vec<IpAddr>, Option<u32>)>;
+/// The outcome of a lookup, shared by the callers waiting on it.
+type SharedResult = Result<Vec<IpAddr>, (io::ErrorKind, String)>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DohMethod {
+    Get,
+    Post,
+}
+
+impl DohMethod {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "get" => Ok(DohMethod::Get),
+            "post" => Ok(DohMethod::Post),
+            other => Err(format!("unknown DoH method '{}'", other)),
+        }
+    }
+}
+
+/// Resolves host names for the proxy, in front of the backend: names
+/// pinned by the hosts file are answered from it and the rest go through
+/// the cache. Clones share the cache and the lookups in flight.
+#[derive(Clone)]
+pub struct Resolver {
+    backend: Arc<Backend>,
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    cache: Option<Arc<Cache>>,
+    in_flight: Arc<Mutex<HashMap<String, Arc<OnceCell<SharedResult>>>>>,
+}
+
+enum Backend {
+    System,
+    Doh {
+        url: String,
+        method: DohMethod,
+        client: Client,
+    },
+    Dot {
+        /// The name the server certificate is checked against.
+        name: String,
+        port: u16,
+        /// Addresses to connect to. When empty the name is looked up with
+        /// the system resolver.
+        addrs: Vec<IpAddr>,
+        tls: TlsConnector,
+    },
+}
+
+impl Resolver {
+    /// Builds the resolver for `spec`: `system`, `https://host/path` for
+    /// DoH or `tls://host[:port]` for DoT. `http://` is also taken for DoH,
+    /// which is only useful for a server on the local host. `bootstrap`
+    /// gives the addresses of the DoH or DoT server, so that its own name
+    /// does not have to go through the system resolver.
+    pub fn new(spec: &str, method: DohMethod, bootstrap: &[IpAddr]) -> Result<Self, String> {
+        if spec == "system" {
+            return Ok(Self::with_backend(Backend::System));
+        }
+        let (scheme, rest) = spec
+            .split_once("://")
+            .ok_or_else(|| format!("invalid DNS server '{}'", spec))?;
+        match scheme.to_ascii_lowercase().as_str() {
+            "https" | "http" => {
+                let url = reqwest::Url::parse(spec).map_err(|err| format!("invalid DNS server '{}': {}", spec, err))?;
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| format!("DNS server '{}' needs a host", spec))?;
+                let port = url.port_or_known_default().unwrap_or(443);
+                let addrs: Vec<SocketAddr> = bootstrap.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                let mut builder = Client::builder().timeout(DNS_TIMEOUT).no_proxy();
+                if !addrs.is_empty() {
+                    builder = builder.resolve_to_addrs(host, &addrs);
+                }
+                let client = builder
+                    .build()
+                    .map_err(|err| format!("cannot set up DoH client: {}", err))?;
+                Ok(Self::with_backend(Backend::Doh {
+                    url: spec.to_string(),
+                    method,
+                    client,
+                }))
+            }
+            "tls" => {
+                let authority = rest.strip_suffix('/').unwrap_or(rest);
+                let (host, port) = crate::parse_host_port(authority, DOT_PORT);
+                let name = host.trim_matches(['[', ']']).to_string();
+                if name.is_empty() || port == 0 || authority.contains('/') {
+                    return Err(format!("invalid DNS server '{}'", spec));
+                }
+                let addrs = match name.parse::<IpAddr>() {
+                    Ok(ip) if bootstrap.is_empty() => vec![ip],
+                    _ => bootstrap.to_vec(),
+                };
+                Ok(Self::with_backend(Backend::Dot {
+                    name,
+                    port,
+                    addrs,
+                    tls: tls_connector(),
+                }))
+            }
+            other => Err(format!("unsupported DNS scheme '{}'", other)),
+        }
+    }
+
+    fn with_backend(backend: Backend) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            hosts: Arc::new(HashMap::new()),
+            cache: None,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_hosts(&mut self, hosts: HashMap<String, Vec<IpAddr>>) {
+        self.hosts = Arc::new(hosts);
+    }
+
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(Arc::new(cache));
+    }
+
+    pub fn cache(&self) -> Option<Arc<Cache>> {
+        self.cache.clone()
+    }
+
+    pub fn is_system(&self) -> bool {
+        matches!(*self.backend, Backend::System)
+    }
+
+    pub fn pinned_len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    /// The addresses the hosts file pins `name` to.
+    pub fn pinned(&self, name: &str) -> Option<&[IpAddr]> {
+        self.hosts.get(name).map(Vec::as_slice)
+    }
+
+    /// Resolves `host` to the addresses to try for `port`. IP literals are
+    /// returned as they are. A name that does not exist is reported as
+    /// `ErrorKind::NotFound`.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        let ips = match self.pinned(&name) {
+            Some(ips) => ips.to_vec(),
+            None => match self.cache.as_ref().and_then(|cache| cache.get(&name)) {
+                Some(ips) => ips?,
+                None => self.resolve(&name).await?,
+            },
+        };
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    /// Asks the backend for `name` and caches the outcome. Concurrent
+    /// lookups of the same name wait for the first one instead of sending
+    /// queries of their own.
+    async fn resolve(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let cell = Arc::clone(self.in_flight.lock().unwrap().entry(name.to_string()).or_default());
+        let result = cell
+            .get_or_init(|| async {
+                let result = self.backend.resolve(name).await;
+                if let Some(cache) = &self.cache {
+                    cache.insert(name, &result);
+                }
+                result.map(|(ips, _)| ips).map_err(|err| (err.kind(), err.to_string()))
+            })
+            .await
+            .clone();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(name).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+            in_flight.remove(name);
+        }
+        result.map_err(|(kind, err)| io::Error::new(kind, err))
+    }
+
+    /// Sends a wire-format DNS message to the server and returns its reply.
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        self.backend.exchange(query).await
+    }
+}
+
+impl fmt::Display for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.backend)
+    }
+}
+
+impl Backend {
+    /// Looks up the addresses of `name` and how long they may be cached,
+    /// which the system resolver does not tell.
+    async fn resolve(&self, name: &str) -> io::Result<(Vec<IpAddr>, Option<u32>)> {
+        let (ips, ttl) = if let Backend::System = self {
+            let addrs = lookup_host((name, 0)).await.map_err(system_error)?;
+            (addrs.map(|addr| addr.ip()).collect(), None)
+        } else {
+            match self.query_both(name).await {
+                (Ok((mut v4, ttl4)), Ok((v6, ttl6))) => {
+                    v4.extend(v6);
+                    (v4, ttl4.into_iter().chain(ttl6).min())
+                }
+                // An empty answer only shows that the name has no addresses
+                // when the other query did not fail.
+                (Ok(answer), Err(err)) | (Err(err), Ok(answer)) => {
+                    if answer.0.is_empty() {
+                        return Err(err);
+                    }
+                    answer
+                }
+                (Err(v4), Err(v6)) => {
+                    return Err(if v4.kind() == io::ErrorKind::NotFound { v6 } else { v4 });
+                }
+            }
+        };
+        if ips.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no addresses", name),
+            ));
+        }
+        Ok((ips, ttl))
+    }
+
+    /// Queries the A and AAAA records of `name` at once. DoH requests share
+    /// the client's pooled connections; DoT sends both queries on a single
+    /// connection.
+    async fn query_both(&self, name: &str) -> (AddressResult, AddressResult) {
+        let Backend::Dot { .. } = self else {
+            return tokio::join!(self.query(name, TYPE_A), self.query(name, TYPE_AAAA));
+        };
+        let id: u16 = rand::random();
+        let ids = [id, id.wrapping_add(1)];
+        let queries = match (build_query(ids[0], name, TYPE_A), build_query(ids[1], name, TYPE_AAAA)) {
+            (Ok(v4), Ok(v6)) => [v4, v6],
+            (Err(err), _) | (_, Err(err)) => return (Err(io::Error::new(err.kind(), err.to_string())), Err(err)),
+        };
+        match time::timeout(DNS_TIMEOUT, self.dot_exchange(&queries)).await {
+            Ok(Ok(replies)) => (
+                parse_addresses(&replies[0], ids[0], TYPE_A),
+                parse_addresses(&replies[1], ids[1], TYPE_AAAA),
+            ),
+            Ok(Err(err)) => (Err(io::Error::new(err.kind(), err.to_string())), Err(err)),
+            Err(_) => {
+                let timed_out = || io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", self));
+                (Err(timed_out()), Err(timed_out()))
+            }
+        }
+    }
+
+    async fn query(&self, host: &str, qtype: u16) -> AddressResult {
+        // RFC 8484 asks for ID 0 over HTTPS so that responses can be cached.
+        let id = if matches!(self, Backend::Doh { .. }) { 0 } else { rand::random() };
+        let query = build_query(id, host, qtype)?;
+        let response = self.exchange(&query).await?;
+        parse_addresses(&response, id, qtype)
+    }
+
+    async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        match time::timeout(DNS_TIMEOUT, self.exchange_inner(query)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", self))),
+        }
+    }
+
+    async fn exchange_inner(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Backend::System => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the system resolver cannot send DNS messages",
+            )),
+            Backend::Doh { url, method, client } => {
+                let request = match method {
+                    DohMethod::Get => {
+                        let separator = if url.contains('?') { '&' } else { '?' };
+                        client.get(format!("{}{}dns={}", url, separator, URL_SAFE_NO_PAD.encode(query)))
+                    }
+                    DohMethod::Post => client
+                        .post(url.as_str())
+                        .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+                        .body(query.to_vec()),
+                };
+                let response = request
+                    .header(reqwest::header::ACCEPT, DNS_MESSAGE)
+                    .send()
+                    .await
+                    .map_err(|err| io::Error::other(format!("DoH request failed: {}", err)))?;
+                if !response.status().is_success() {
+                    return Err(io::Error::other(format!("DoH server answered {}", response.status())));
+                }
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|err| io::Error::other(format!("DoH response failed: {}", err)))?;
+                Ok(body.to_vec())
+            }
+            Backend::Dot { .. } => {
+                let mut replies = self.dot_exchange(&[query.to_vec()]).await?;
+                Ok(replies.remove(0))
+            }
+        }
+    }
+
+    /// Opens a DoT connection and exchanges `queries` on it.
+    async fn dot_exchange(&self, queries: &[Vec<u8>]) -> io::Result<Vec<Vec<u8>>> {
+        let Backend::Dot { name, port, addrs, tls } = self else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not a DoT server"));
+        };
+        let stream = connect_any(name, *port, addrs).await?;
+        let server_name = ServerName::try_from(name.as_str())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid server name '{}'", name)))?;
+        let mut stream = tls.connect(server_name, stream).await?;
+        exchange_stream(&mut stream, queries).await
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::System => write!(f, "the system resolver"),
+            Backend::Doh { url, method, .. } => {
+                let method = if *method == DohMethod::Post { "POST" } else { "GET" };
+                write!(f, "DoH {} ({})", url, method)
+            }
+            Backend::Dot { name, port, .. } => write!(f, "DoT {}:{}", name, port),
+        }
+    }
+}
+
+/// Reports a name the system resolver does not know as
+/// `ErrorKind::NotFound`, like the other backends do. getaddrinfo only
+/// says so in its message, worded by glibc, musl and the BSDs in turn;
+/// Windows gives WSAHOST_NOT_FOUND or WSANO_DATA.
+fn system_error(err: io::Error) -> io::Error {
+    const MISSING: &[&str] = &[
+        "Name or service not known",
+        "No address associated with hostname",
+        "Name does not resolve",
+        "nodename nor servname provided",
+    ];
+    let message = err.to_string();
+    let missing = MISSING.iter().any(|text| message.contains(text))
+        || (cfg!(windows) && matches!(err.raw_os_error(), Some(11001) | Some(11004)));
+    if missing {
+        io::Error::new(io::ErrorKind::NotFound, message)
+    } else {
+        err
+    }
+}
+
+/// Host names resolved by the proxy, kept for the TTL of their records.
+/// Names that do not exist are kept too, for the negative TTL.
+pub struct Cache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    size: usize,
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CacheEntry {
+    /// The addresses, or the error for a name that does not exist.
+    result: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+}
+
+impl Cache {
+    pub fn new(size: usize, negative_ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            size,
+            negative_ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<io::Result<Vec<IpAddr>>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(name) {
+            Some(entry) if entry.expires > Instant::now() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(
+                    entry
+                        .result
+                        .clone()
+                        .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err)),
+                );
+            }
+            Some(_) => {
+                entries.remove(name);
+            }
+            None => {}
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// Keeps the outcome of a lookup. Failures other than a name that does
+    /// not exist are not kept, nor are records with a TTL of 0. When the
+    /// cache is full, expired entries are dropped first, then the one
+    /// closest to expiry.
+    fn insert(&self, name: &str, result: &io::Result<(Vec<IpAddr>, Option<u32>)>) {
+        let (result, ttl) = match result {
+            Ok((ips, ttl)) => (
+                Ok(ips.clone()),
+                ttl.map_or(SYSTEM_TTL, |ttl| Duration::from_secs(ttl as u64)),
+            ),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (Err(err.to_string()), self.negative_ttl),
+            Err(_) => return,
+        };
+        if ttl.is_zero() || self.size == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.size && !entries.contains_key(name) {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= self.size && !entries.contains_key(name) {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(name, _)| name.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            name.to_string(),
+            CacheEntry {
+                result,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+/// Loads a hosts-style file: an address followed by the names it is for
+/// on each line, `#` starting a comment.
+pub fn load_hosts(path: &str) -> io::Result<HashMap<String, Vec<IpAddr>>> {
+    let data = fs::read_to_string(path)?;
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut fields = line.split_whitespace();
+        let Some(addr) = fields.next() else {
+            continue;
+        };
+        let ip = addr.parse::<IpAddr>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: invalid address '{}'", path, n + 1, addr),
+            )
+        })?;
+        let mut names = fields.peekable();
+        if names.peek().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: no host name for {}", path, n + 1, ip),
+            ));
+        }
+        for name in names {
+            let ips = hosts.entry(name.trim_end_matches('.').to_ascii_lowercase()).or_default();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Sends `queries` over a DNS stream connection (RFC 7766) and returns the
+/// replies in the same order. The queries are pipelined and the server may
+/// answer them in any order, so replies are matched by ID.
+async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    queries: &[Vec<u8>],
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut message = Vec::new();
+    for query in queries {
+        let len = u16::try_from(query.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS message is too large"))?;
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(query);
+    }
+    stream.write_all(&message).await?;
+    let mut replies = vec![Vec::new(); queries.len()];
+    for _ in 0..queries.len() {
+        let len = stream.read_u16().await?;
+        let mut reply = vec![0u8; len as usize];
+        stream.read_exact(&mut reply).await?;
+        let slot = queries
+            .iter()
+            .position(|query| query.get(..2) == reply.get(..2))
+            .filter(|i| replies[*i].is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "DNS reply does not match any query"))?;
+        replies[slot] = reply;
+    }
+    Ok(replies)
+}
+
+/// Connects to the first of `addrs` that answers, or to the addresses of
+/// `name` from the system resolver when none are given.
+async fn connect_any(name: &str, port: u16, addrs: &[IpAddr]) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = if addrs.is_empty() {
+        lookup_host((name, port)).await?.collect()
+    } else {
+        addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect()
+    };
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", name));
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = io::Error::new(err.kind(), format!("DoT server {}: {}", addr, err)),
+        }
+    }
+    Err(last_err)
+}
+
+/// Builds a recursive query for `name` with a single question.
+pub fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid host name '{}'", name));
+    let mut message = Vec::with_capacity(name.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    // RD set, one question.
+    message.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 || !label.is_ascii() {
+            return Err(invalid());
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    if message.len() > HEADER_LEN + 255 + 1 {
+        return Err(invalid());
+    }
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Takes the `qtype` addresses from the answer section of the reply to the
+/// query with `id`, with the lowest TTL in that section. CNAME records are
+/// skipped; recursive servers put the records of the canonical name in the
+/// same answer.
+fn parse_addresses(message: &[u8], id: u16, qtype: u16) -> AddressResult {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if read_u16(message, 0) != Some(id) || message.get(2).is_none_or(|flags| flags & 0x80 == 0) {
+        return Err(invalid("DNS reply does not match the query"));
+    }
+    match rcode(message) {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+        rcode => return Err(io::Error::other(format!("DNS server answered with error {}", rcode))),
+    }
+    let records = records(message).ok_or_else(|| invalid("truncated DNS reply"))?;
+    let answers = read_u16(message, 6).unwrap_or(0) as usize;
+    let mut ips = Vec::new();
+    let mut ttl: Option<u32> = None;
+    for pos in records.into_iter().take(answers) {
+        let record_ttl = read_u32(message, pos + 4).unwrap_or(0);
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        let rtype = read_u16(message, pos).unwrap_or(0);
+        let class = read_u16(message, pos + 2).unwrap_or(0);
+        let len = read_u16(message, pos + 8).unwrap_or(0) as usize;
+        if rtype != qtype || class != CLASS_IN {
+            continue;
+        }
+        let data = &message[pos + 10..pos + 10 + len];
+        match (rtype, data.len()) {
+            (TYPE_A, 4) => ips.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, 16) => ips.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => return Err(invalid("malformed address record")),
+        }
+    }
+    Ok((ips, ttl))
+}
+
+/// The question of a query.
+pub struct Question {
+    /// Lowercase and without the trailing dot.
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    /// Where the question section ends.
+    pub end: usize,
+}
+
+/// Parses the question of a message that has exactly one.
+pub fn parse_question(message: &[u8]) -> Option<Question> {
+    if read_u16(message, 4)? != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *message.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Also rules out compression pointers, which a question cannot use.
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(message.get(pos..pos + len)?).to_ascii_lowercase());
+        pos += len;
+    }
+    Some(Question {
+        name: labels.join("."),
+        qtype: read_u16(message, pos)?,
+        qclass: read_u16(message, pos + 2)?,
+        end: pos + 4,
+    })
+}
+
+pub fn rcode(message: &[u8]) -> u8 {
+    message.get(3).map_or(0, |flags| flags & 0x0f)
+}
+
+/// Returns where the TYPE field of each resource record is, over the
+/// answer, authority and additional sections, or `None` if the message is
+/// cut short.
+fn records(message: &[u8]) -> Option<Vec<usize>> {
+    let questions = read_u16(message, 4)?;
+    let count = read_u16(message, 6)? as usize + read_u16(message, 8)? as usize + read_u16(message, 10)? as usize;
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        pos = skip_name(message, pos)?;
+        let len = read_u16(message, pos + 8)? as usize;
+        message.get(pos..pos + 10 + len)?;
+        records.push(pos);
+        pos += 10 + len;
+    }
+    Some(records)
+}
+
+/// The lowest TTL in a reply, which is how long it may be cached. `None`
+/// for a reply without records.
+pub fn min_ttl(message: &[u8]) -> Option<u32> {
+    records(message)?
+        .into_iter()
+        .filter(|pos| read_u16(message, *pos) != Some(TYPE_OPT))
+        .filter_map(|pos| read_u32(message, pos + 4))
+        .min()
+}
+
+/// Counts `elapsed` seconds off every TTL in a reply taken from a cache.
+/// The TTL field of an OPT record holds flags and is left alone.
+pub fn age_ttls(message: &mut [u8], elapsed: u32) {
+    let Some(records) = records(message) else {
+        return;
+    };
+    for pos in records {
+        if read_u16(message, pos) == Some(TYPE_OPT) {
+            continue;
+        }
+        if let Some(ttl) = read_u32(message, pos + 4) {
+            message[pos + 4..pos + 8].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+    }
+}
+
+/// The largest UDP reply the sender of `query` accepts, as announced in its
+/// OPT record.
+pub fn udp_payload_size(query: &[u8]) -> usize {
+    records(query)
+        .and_then(|records| records.into_iter().find(|pos| read_u16(query, *pos) == Some(TYPE_OPT)))
+        .and_then(|pos| read_u16(query, pos + 2))
+        .map_or(MIN_UDP_PAYLOAD, |size| (size as usize).max(MIN_UDP_PAYLOAD))
+}
+
+pub fn set_id(message: &mut [u8], id: u16) {
+    if message.len() >= 2 {
+        message[..2].copy_from_slice(&id.to_be_bytes());
+    }
+}
+
+/// Builds the reply to `query` with `rcode` and one A or AAAA record for
+/// each of `ips`. The question is repeated when it could be parsed.
+pub fn build_reply(query: &[u8], question: Option<&Question>, rcode: u8, ips: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_LEN + 64 + ips.len() * 28);
+    message.extend_from_slice(query.get(..2).unwrap_or(&[0, 0]));
+    // QR, the opcode and RD of the query; RA.
+    message.push(0x80 | query.get(2).map_or(0, |flags| flags & 0x79));
+    message.push(0x80 | rcode);
+    message.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    message.extend_from_slice(&(ips.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(question) = question {
+        message.extend_from_slice(&query[HEADER_LEN..question.end]);
+    }
+    for ip in ips {
+        // The name is a pointer to the one in the question.
+        message.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        let (rtype, data) = match ip {
+            IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+        message.extend_from_slice(&rtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&data);
+    }
+    message
+}
+
+/// Cuts a reply that does not fit into a UDP datagram down to its header
+/// and question with TC set, so that the client retries over TCP.
+pub fn truncate(reply: &[u8]) -> Vec<u8> {
+    let end = parse_question(reply).map_or(HEADER_LEN, |question| question.end);
+    let mut message = reply[..end.min(reply.len())].to_vec();
+    if message.len() < HEADER_LEN {
+        return message;
+    }
+    message[2] |= 0x02;
+    message[4..6].copy_from_slice(&((end > HEADER_LEN) as u16).to_be_bytes());
+    message[6..HEADER_LEN].fill(0);
+    message
+}
+
+/// Returns the position after the name at `pos`, which may end in a
+/// compression pointer.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len & 0xc0 {
+            0xc0 => return message.get(pos + 1).map(|_| pos + 2),
+            0x00 if len == 0 => return Some(pos + 1),
+            0x00 => pos += 1 + len,
+            _ => return None,
+        }
+    }
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Option<u16> {
+    message.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(message: &[u8], pos: usize) -> Option<u32> {
+    message
+        .get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Answers `name` like a recursive server would: `example.test` has an
+    /// IPv4 and an IPv6 address, `v4.test` only IPv4, `nx.test` does not
+    /// exist and the AAAA query for `flaky.test` fails.
+    fn answer(query: &[u8]) -> Option<Vec<u8>> {
+        let question = parse_question(query)?;
+        let reply = match (question.name.as_str(), question.qtype) {
+            ("nx.test", _) => build_reply(query, Some(&question), RCODE_NXDOMAIN, &[], 0),
+            ("flaky.test", TYPE_AAAA) => return None,
+            ("example.test", TYPE_A) | ("v4.test", TYPE_A) => {
+                build_reply(query, Some(&question), 0, &["192.0.2.1".parse().unwrap()], 300)
+            }
+            ("example.test", TYPE_AAAA) => {
+                build_reply(query, Some(&question), 0, &["2001:db8::1".parse().unwrap()], 60)
+            }
+            _ => build_reply(query, Some(&question), 0, &[], 0),
+        };
+        Some(reply)
+    }
+
+    /// A DoH stand-in over plain HTTP that notes the method of each request.
+    async fn doh_server() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        let methods = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&methods);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let seen = Arc::clone(&seen);
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut line = String::new();
+                        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut fields = line.split_whitespace();
+                        let method = fields.next().unwrap_or_default().to_string();
+                        let target = fields.next().unwrap_or_default().to_string();
+                        let mut length = 0;
+                        loop {
+                            let mut header = String::new();
+                            stream.read_line(&mut header).await.unwrap();
+                            if header.trim().is_empty() {
+                                break;
+                            }
+                            if let Some((name, value)) = header.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let query = if method == "POST" {
+                            let mut body = vec![0u8; length];
+                            stream.read_exact(&mut body).await.unwrap();
+                            body
+                        } else {
+                            let encoded = target.split_once("dns=").unwrap().1;
+                            URL_SAFE_NO_PAD.decode(encoded).unwrap()
+                        };
+                        seen.lock().unwrap().push(method);
+                        let response = match answer(&query) {
+                            Some(reply) => {
+                                let mut response = format!(
+                                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                                    DNS_MESSAGE,
+                                    reply.len()
+                                )
+                                .into_bytes();
+                                response.extend_from_slice(&reply);
+                                response
+                            }
+                            None => b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                        };
+                        stream.get_mut().write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, methods)
+    }
+
+    async fn lookup_ips(resolver: &Resolver, name: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs = resolver.lookup(name, 443).await?;
+        Ok(addrs.into_iter().map(|addr| addr.ip()).collect())
+    }
+
+    #[test]
+    fn builds_queries() {
+        let query = build_query(0xabcd, "www.Example.com.", TYPE_AAAA).unwrap();
+        assert_eq!(query[..4], [0xab, 0xcd, 0x01, 0x00]);
+        let question = parse_question(&query).unwrap();
+        assert_eq!(question.name, "www.example.com");
+        assert_eq!(question.qtype, TYPE_AAAA);
+        assert_eq!(question.qclass, CLASS_IN);
+        assert_eq!(question.end, query.len());
+
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+        assert!(build_query(1, &"a".repeat(64), TYPE_A).is_err());
+        assert!(build_query(1, &vec!["a".repeat(63); 5].join("."), TYPE_A).is_err());
+        assert!(build_query(1, "bücher.de", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let query = build_query(7, "example.test", TYPE_A).unwrap();
+        let reply = answer(&query).unwrap();
+        let (ips, ttl) = parse_addresses(&reply, 7, TYPE_A).unwrap();
+        assert_eq!(ips, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(ttl, Some(300));
+
+        assert_eq!(parse_addresses(&reply, 8, TYPE_A).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse_addresses(&query, 7, TYPE_A).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            parse_addresses(&reply[..reply.len() - 2], 7, TYPE_A).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let query = build_query(9, "nx.test", TYPE_A).unwrap();
+        let reply = answer(&query).unwrap();
+        assert_eq!(parse_addresses(&reply, 9, TYPE_A).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let mut reply = build_reply(&query, parse_question(&query).as_ref(), RCODE_SERVFAIL, &[], 0);
+        assert_eq!(parse_addresses(&reply, 9, TYPE_A).unwrap_err().kind(), io::ErrorKind::Other);
+        reply[3] &= 0xf0;
+        assert_eq!(parse_addresses(&reply, 9, TYPE_A).unwrap(), (Vec::new(), None));
+    }
+
+    #[tokio::test]
+    async fn resolves_over_doh_with_get_and_post() {
+        for method in [DohMethod::Get, DohMethod::Post] {
+            let (url, methods) = doh_server().await;
+            let resolver = Resolver::new(&url, method, &[]).unwrap();
+            let mut ips = lookup_ips(&resolver, "example.test").await.unwrap();
+            ips.sort();
+            assert_eq!(ips, vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "2001:db8::1".parse().unwrap()]);
+            assert_eq!(lookup_ips(&resolver, "v4.test").await.unwrap().len(), 1);
+            assert_eq!(
+                lookup_ips(&resolver, "nx.test").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+            let expected = if method == DohMethod::Get { "GET" } else { "POST" };
+            let methods = methods.lock().unwrap();
+            assert_eq!(methods.len(), 6);
+            assert!(methods.iter().all(|seen| seen == expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_query_beside_an_empty_answer_is_not_not_found() {
+        let (url, _) = doh_server().await;
+        let resolver = Resolver::new(&url, DohMethod::Get, &[]).unwrap();
+        let err = lookup_ips(&resolver, "flaky.test").await.unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            lookup_ips(&resolver, "empty.test").await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn pipelines_queries_on_one_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut queries = Vec::new();
+            for _ in 0..2 {
+                let len = stream.read_u16().await.unwrap();
+                let mut query = vec![0u8; len as usize];
+                stream.read_exact(&mut query).await.unwrap();
+                queries.push(query);
+            }
+            // Answered out of order, as RFC 7766 allows.
+            for query in queries.iter().rev() {
+                let reply = answer(query).unwrap();
+                stream.write_u16(reply.len() as u16).await.unwrap();
+                stream.write_all(&reply).await.unwrap();
+            }
+            // A second connection would never be answered.
+            let _ = listener.accept().await;
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let queries = [
+            build_query(1, "example.test", TYPE_A).unwrap(),
+            build_query(2, "example.test", TYPE_AAAA).unwrap(),
+        ];
+        let replies = exchange_stream(&mut stream, &queries).await.unwrap();
+        assert_eq!(parse_addresses(&replies[0], 1, TYPE_A).unwrap().1, Some(300));
+        assert_eq!(parse_addresses(&replies[1], 2, TYPE_AAAA).unwrap().1, Some(60));
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_share_one_query() {
+        let (url, methods) = doh_server().await;
+        let resolver = Resolver::new(&url, DohMethod::Post, &[]).unwrap();
+        let (a, b, c) = tokio::join!(
+            lookup_ips(&resolver, "example.test"),
+            lookup_ips(&resolver, "example.test"),
+            lookup_ips(&resolver, "example.test"),
+        );
+        assert_eq!(a.unwrap().len(), 2);
+        assert_eq!(b.unwrap().len(), 2);
+        assert_eq!(c.unwrap().len(), 2);
+        assert_eq!(methods.lock().unwrap().len(), 2);
+        assert!(resolver.in_flight.lock().unwrap().is_empty());
+
+        lookup_ips(&resolver, "example.test").await.unwrap();
+        assert_eq!(methods.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn unknown_system_names_are_not_found() {
+        let err = system_error(io::Error::other(
+            "failed to lookup address information: Name or service not known",
+        ));
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = system_error(io::Error::other(
+            "failed to lookup address information: Temporary failure in name resolution",
+        ));
+        assert_ne!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    fn addresses(ip: &str, ttl: Option<u32>) -> AddressResult {
+        Ok((vec![ip.parse().unwrap()], ttl))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_entries_expire_with_their_ttl() {
+        let cache = Cache::new(8, Duration::from_secs(30));
+        cache.insert("short.test", &addresses("192.0.2.1", Some(10)));
+        cache.insert("system.test", &addresses("192.0.2.2", None));
+        cache.insert("zero.test", &addresses("192.0.2.3", Some(0)));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("short.test").unwrap().is_ok());
+
+        time::advance(Duration::from_secs(11)).await;
+        assert!(cache.get("short.test").is_none());
+        assert!(cache.get("system.test").is_some());
+        time::advance(SYSTEM_TTL).await;
+        assert!(cache.get("system.test").is_none());
+        assert_eq!(cache.len(), 0);
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_only_missing_names_negatively() {
+        let cache = Cache::new(8, Duration::from_secs(30));
+        cache.insert("nx.test", &Err(io::Error::new(io::ErrorKind::NotFound, "no such host")));
+        cache.insert("down.test", &Err(io::Error::other("connection refused")));
+        assert_eq!(cache.get("nx.test").unwrap().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(cache.get("down.test").is_none());
+
+        time::advance(Duration::from_secs(31)).await;
+        assert!(cache.get("nx.test").is_none());
+
+        let cache = Cache::new(8, Duration::ZERO);
+        cache.insert("nx.test", &Err(io::Error::new(io::ErrorKind::NotFound, "no such host")));
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_cache_drops_expired_then_soonest_to_expire() {
+        let cache = Cache::new(2, Duration::from_secs(30));
+        cache.insert("a.test", &addresses("192.0.2.1", Some(100)));
+        cache.insert("b.test", &addresses("192.0.2.2", Some(10)));
+        cache.insert("c.test", &addresses("192.0.2.3", Some(50)));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b.test").is_none());
+        assert!(cache.get("a.test").is_some());
+
+        // Refreshing a cached name evicts nothing.
+        cache.insert("c.test", &addresses("192.0.2.4", Some(5)));
+        assert_eq!(cache.len(), 2);
+
+        time::advance(Duration::from_secs(6)).await;
+        cache.insert("d.test", &addresses("192.0.2.5", Some(1)));
+        assert!(cache.get("a.test").is_some());
+        assert!(cache.get("c.test").is_none());
+        assert!(cache.get("d.test").is_some());
+
+        let cache = Cache::new(0, Duration::from_secs(30));
+        cache.insert("a.test", &addresses("192.0.2.1", Some(100)));
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,310 @@
+//! The `explain` subcommand: shows how every fragmentation method would cut
+//! a captured ClientHello, without opening any connection.
+
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::tls::{self, ClientHello, Extension};
+use crate::fragment::{fragment_rng, prepare_client_hello, read_initial_tls_data, InitialTlsData};
+use crate::strategy::{fragment_method_name, FragmentMethod, Strategy};
+use crate::Config;
+
+const METHODS: [FragmentMethod; 5] = [
+    FragmentMethod::None,
+    FragmentMethod::Random,
+    FragmentMethod::Sni,
+    FragmentMethod::TcpSplit,
+    FragmentMethod::Fake,
+];
+
+/// A record of the produced stream: where it sits in the stream and which
+/// bytes of the handshake payload it carries.
+struct RecordSpan {
+    stream: Range<usize>,
+    payload: Range<usize>,
+}
+
+/// `input` is a file holding the raw bytes or their hex dump, or the hex
+/// dump itself. Either TLS records or a bare handshake message are accepted.
+pub async fn run(input: &str, config: &Config) -> Result<(), String> {
+    let data = load_input(input)?;
+    let records = if data.first() == Some(&tls::HANDSHAKE_CLIENT_HELLO) {
+        wrap_in_records(&data)
+    } else {
+        data
+    };
+    let initial = read_initial_tls_data(&mut records.as_slice(), &mut Vec::new())
+        .await
+        .map_err(|err| format!("cannot read ClientHello: {}", err))?;
+    let hello = tls::parse_client_hello(&initial.hello).map_err(|err| err.to_string())?;
+
+    print_hello(&initial, &hello);
+    let sni = hello.sni.as_ref().map(|sni| sni.name.clone());
+    for method in METHODS {
+        let strategy = Strategy {
+            method,
+            ..config.strategy.clone()
+        };
+        let (decoy, segments) = cut(&initial, strategy, &config.fake_sni, config.fragment_seed);
+        println!();
+        println!("method {}:", fragment_method_name(method));
+        if let Some(decoy) = decoy {
+            println!(
+                "  decoy: {} bytes with SNI '{}', cut to segment #0 and sent with a low TTL",
+                decoy.len(),
+                config.fake_sni
+            );
+        }
+        print_layout(&segments, sni.as_ref());
+    }
+    Ok(())
+}
+
+/// Cuts the hello as a connection with `seed` for its fragment RNG would.
+fn cut(initial: &InitialTlsData, strategy: Strategy, fake_sni: &str, seed: Option<u64>) -> (Option<Vec<u8>>, Vec<Vec<u8>>) {
+    prepare_client_hello(initial, strategy, fake_sni, &mut fragment_rng(seed))
+}
+
+fn load_input(input: &str) -> Result<Vec<u8>, String> {
+    if !Path::new(input).is_file() {
+        return decode_hex(input).ok_or_else(|| format!("'{}' is neither a file nor hex", input));
+    }
+    let bytes = fs::read(input).map_err(|err| format!("{}: {}", input, err))?;
+    Ok(std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(decode_hex)
+        .unwrap_or(bytes))
+}
+
+/// Decodes hex digits, ignoring whitespace and `:` separators.
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b':')
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn wrap_in_records(handshake: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(handshake.len() + tls::RECORD_HEADER_LEN);
+    for chunk in handshake.chunks(tls::MAX_RECORD_LEN) {
+        out.extend_from_slice(&[tls::CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+        out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+fn print_hello(initial: &InitialTlsData, hello: &ClientHello) {
+    println!(
+        "ClientHello: {} bytes, legacy version {:02x}{:02x}, record version {:02x}{:02x}",
+        hello.length, hello.legacy_version[0], hello.legacy_version[1], initial.head[1], initial.head[2]
+    );
+    println!(
+        "  session id: {} bytes, cipher suites: {}, extensions: {}",
+        hello.session_id.len(),
+        hello.cipher_suites.len() / 2,
+        hello.extensions.len()
+    );
+    if !initial.extra.is_empty() {
+        println!("  followed by {} bytes of other handshake data", initial.extra.len());
+    }
+    println!("  offsets are within the handshake message:");
+    for ext in &hello.extensions {
+        println!(
+            "    {:#06x} at {}..{} ({} byte body)",
+            ext.ext_type,
+            ext.start,
+            ext.end,
+            ext.end - ext.body
+        );
+    }
+    match &hello.sni {
+        Some(sni) => println!(
+            "  SNI '{}' at {}..{} (extension at {}..{})",
+            sni.host, sni.name.start, sni.name.end, sni.extension.start, sni.extension.end
+        ),
+        None => println!("  no SNI"),
+    }
+    let notable: [(&str, &Option<Extension>); 5] = [
+        ("ALPN", &hello.alpn),
+        ("supported_versions", &hello.supported_versions),
+        ("key_share", &hello.key_share),
+        ("padding", &hello.padding),
+        ("ECH", &hello.ech),
+    ];
+    for (name, ext) in notable {
+        if let Some(ext) = ext {
+            println!("  {} at {}..{}", name, ext.start, ext.end);
+        }
+    }
+}
+
+fn print_layout(segments: &[Vec<u8>], sni: Option<&Range<usize>>) {
+    let stream: Vec<u8> = segments.concat();
+    let records = parse_records(&stream);
+
+    println!("  records:");
+    for (i, record) in records.iter().enumerate() {
+        let head = &stream[record.stream.start..record.stream.start + tls::RECORD_HEADER_LEN];
+        println!(
+            "    #{:<3} stream {:>5}..{:<5} header {:02x} {:02x}{:02x} len {:<5} payload {}..{}{}",
+            i,
+            record.stream.start,
+            record.stream.end,
+            head[0],
+            head[1],
+            head[2],
+            record.payload.len(),
+            record.payload.start,
+            record.payload.end,
+            sni_note(sni, overlap(&record.payload, sni))
+        );
+    }
+
+    println!("  segments:");
+    let mut start = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        let range = start..start + segment.len();
+        let sni_bytes = records
+            .iter()
+            .filter_map(|record| {
+                let part = overlap(&record.stream, Some(&range))?;
+                let body = record.stream.start + tls::RECORD_HEADER_LEN;
+                if part.end <= body {
+                    return None;
+                }
+                let part = part.start.max(body)..part.end;
+                let payload = record.payload.start + (part.start - body)..record.payload.start + (part.end - body);
+                overlap(&payload, sni)
+            })
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end));
+        println!(
+            "    #{:<3} stream {:>5}..{:<5} {:>5} bytes{}",
+            i,
+            range.start,
+            range.end,
+            segment.len(),
+            sni_note(sni, sni_bytes)
+        );
+        start = range.end;
+    }
+}
+
+/// Walks the records of `stream`, numbering payload bytes continuously
+/// across records.
+fn parse_records(stream: &[u8]) -> Vec<RecordSpan> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    let mut payload = 0;
+    while pos + tls::RECORD_HEADER_LEN <= stream.len() {
+        let len = u16::from_be_bytes([stream[pos + 3], stream[pos + 4]]) as usize;
+        let end = (pos + tls::RECORD_HEADER_LEN + len).min(stream.len());
+        let body = end - pos - tls::RECORD_HEADER_LEN;
+        records.push(RecordSpan {
+            stream: pos..end,
+            payload: payload..payload + body,
+        });
+        payload += body;
+        pos = end;
+    }
+    records
+}
+
+fn overlap(a: &Range<usize>, b: Option<&Range<usize>>) -> Option<Range<usize>> {
+    let b = b?;
+    let range = a.start.max(b.start)..a.end.min(b.end);
+    (!range.is_empty()).then_some(range)
+}
+
+fn sni_note(sni: Option<&Range<usize>>, part: Option<Range<usize>>) -> String {
+    match (sni, part) {
+        (Some(sni), Some(part)) if part == *sni => "  [SNI]".to_string(),
+        (Some(_), Some(part)) => format!("  [SNI {}..{}]", part.start, part.end),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::RecordVersion;
+    use crate::DEFAULT_FAKE_SNI;
+
+    async fn initial_tls_data(records: &[u8]) -> InitialTlsData {
+        read_initial_tls_data(&mut &records[..], &mut Vec::new()).await.unwrap()
+    }
+
+    #[test]
+    fn decodes_hex_with_separators() {
+        assert_eq!(decode_hex("16 03:01\n00\tAb"), Some(vec![0x16, 0x03, 0x01, 0x00, 0xab]));
+        assert_eq!(decode_hex("16:03:0"), None);
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex(" : "), None);
+    }
+
+    #[test]
+    fn wraps_a_handshake_in_as_few_records_as_fit() {
+        let handshake: Vec<u8> = (0..tls::MAX_RECORD_LEN + 10).map(|i| i as u8).collect();
+        let records = wrap_in_records(&handshake);
+        let second = tls::RECORD_HEADER_LEN + tls::MAX_RECORD_LEN;
+        assert_eq!(records.len(), handshake.len() + 2 * tls::RECORD_HEADER_LEN);
+        assert_eq!(records[..3], [tls::CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+        assert_eq!(records[3..5], (tls::MAX_RECORD_LEN as u16).to_be_bytes());
+        assert_eq!(records[second..second + 5], [tls::CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0x00, 10]);
+        assert_eq!(records[second + 5..], handshake[tls::MAX_RECORD_LEN..]);
+    }
+
+    #[tokio::test]
+    async fn numbers_record_payloads_across_the_stream() {
+        let hello = tls::build_client_hello("www.example.com");
+        let spans = parse_records(&hello);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].stream, 0..hello.len());
+        assert_eq!(spans[0].payload, 0..hello.len() - tls::RECORD_HEADER_LEN);
+
+        let initial = initial_tls_data(&hello).await;
+        let strategy = Strategy {
+            method: FragmentMethod::Sni,
+            ..Strategy::direct()
+        };
+        let (_, segments) = cut(&initial, strategy, DEFAULT_FAKE_SNI, Some(1));
+        let stream = segments.concat();
+        let spans = parse_records(&stream);
+        assert!(spans.len() > 1);
+        let mut end = (0, 0);
+        for span in &spans {
+            assert_eq!((span.stream.start, span.payload.start), end);
+            assert_eq!(span.stream.len(), span.payload.len() + tls::RECORD_HEADER_LEN);
+            let body = span.stream.start + tls::RECORD_HEADER_LEN..span.stream.end;
+            assert_eq!(stream[body], initial.hello[span.payload.clone()]);
+            end = (span.stream.end, span.payload.end);
+        }
+        assert_eq!(end, (stream.len(), initial.hello.len()));
+
+        // A stream cut short ends with a short record.
+        let spans = parse_records(&stream[..stream.len() - 1]);
+        assert_eq!(spans.last().unwrap().payload.end, initial.hello.len() - 1);
+    }
+
+    #[tokio::test]
+    async fn the_same_seed_cuts_the_same_segments() {
+        let initial = initial_tls_data(&tls::build_client_hello("www.example.com")).await;
+        let strategy = Strategy {
+            method: FragmentMethod::Random,
+            record_version: Some(RecordVersion::Random),
+            ..Strategy::direct()
+        };
+        let first = cut(&initial, strategy.clone(), DEFAULT_FAKE_SNI, Some(42));
+        assert_eq!(cut(&initial, strategy.clone(), DEFAULT_FAKE_SNI, Some(42)), first);
+        assert_ne!(cut(&initial, strategy, DEFAULT_FAKE_SNI, Some(43)), first);
+    }
+}
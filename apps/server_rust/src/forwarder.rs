@@ -0,0 +1,385 @@
+//! The DNS forwarder enabled with `--dns-listen`. It answers queries from
+//! the LAN over UDP and TCP by passing them on to the DoH or DoT server of
+//! `--dns`, so that clients get the same unpoisoned answers as the proxy.
+//! Replies are cached for their TTL, and names pinned with `--hosts` or
+//! given with `--dns-override` are answered locally; both match names
+//! exactly.
+//!
+//! Only clients from loopback, private and link-local addresses are
+//! answered, unless `--dns-allow` lists the networks to serve instead, so
+//! that a forwarder listening on all interfaces is no open resolver.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::time::{self, Duration, Instant};
+
+use crate::dns::{self, Question};
+use crate::routing::Cidr;
+use crate::Logger;
+
+/// Replies kept in the cache at most.
+const CACHE_SIZE: usize = 4096;
+/// TTL of the records answered from `--dns-override`.
+const OVERRIDE_TTL: u32 = 300;
+/// How long a TCP client may stay silent between queries.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest UDP query accepted.
+const MAX_UDP_QUERY: usize = 4096;
+/// UDP queries answered at once; more are dropped, and clients retry.
+const MAX_UDP_IN_FLIGHT: usize = 256;
+/// TCP clients served at once; more are turned away.
+const MAX_TCP_CLIENTS: usize = 64;
+/// The networks served when `--dns-allow` is not given.
+const LOCAL_NETWORKS: &[&str] = &[
+    "127.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "100.64.0.0/10",
+    "169.254.0.0/16",
+    "::1",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+struct CachedReply {
+    reply: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+pub struct Forwarder {
+    resolver: dns::Resolver,
+    overrides: HashMap<String, Vec<IpAddr>>,
+    /// The networks whose clients are answered.
+    allowed: Vec<Cidr>,
+    cache: Mutex<HashMap<(String, u16, u16), CachedReply>>,
+    logger: Arc<Logger>,
+}
+
+impl Forwarder {
+    /// Builds the forwarder. An empty `allowed` serves the local networks.
+    pub fn new(
+        resolver: dns::Resolver,
+        overrides: HashMap<String, Vec<IpAddr>>,
+        allowed: Vec<Cidr>,
+        logger: Arc<Logger>,
+    ) -> Self {
+        let allowed = if allowed.is_empty() {
+            LOCAL_NETWORKS
+                .iter()
+                .filter_map(|cidr| Cidr::parse(cidr).ok())
+                .collect()
+        } else {
+            allowed
+        };
+        Self {
+            resolver,
+            overrides,
+            allowed,
+            cache: Mutex::new(HashMap::new()),
+            logger,
+        }
+    }
+
+    fn is_allowed(&self, client: IpAddr) -> bool {
+        self.allowed.iter().any(|cidr| cidr.contains(client))
+    }
+
+    /// Binds `addr` over UDP and TCP and serves both in the background.
+    pub async fn spawn(self: Arc<Self>, addr: SocketAddr) -> io::Result<()> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(Arc::clone(&self).serve_udp(socket));
+        tokio::spawn(self.serve_tcp(listener));
+        Ok(())
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: Arc<UdpSocket>) {
+        let in_flight = Arc::new(Semaphore::new(MAX_UDP_IN_FLIGHT));
+        let mut buf = vec![0u8; MAX_UDP_QUERY];
+        loop {
+            let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            if !self.is_allowed(from.ip()) {
+                continue;
+            }
+            let Ok(permit) = Arc::clone(&in_flight).try_acquire_owned() else {
+                continue;
+            };
+            let query = buf[..n].to_vec();
+            let forwarder = Arc::clone(&self);
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                let _permit = permit;
+                let Some(mut reply) = forwarder.answer(&query).await else {
+                    return;
+                };
+                if reply.len() > dns::udp_payload_size(&query) {
+                    reply = dns::truncate(&reply);
+                }
+                let _ = socket.send_to(&reply, from).await;
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) {
+        let clients = Arc::new(Semaphore::new(MAX_TCP_CLIENTS));
+        loop {
+            let Ok((stream, from)) = listener.accept().await else {
+                continue;
+            };
+            if !self.is_allowed(from.ip()) {
+                continue;
+            }
+            let Ok(permit) = Arc::clone(&clients).try_acquire_owned() else {
+                continue;
+            };
+            let forwarder = Arc::clone(&self);
+            tokio::spawn(async move {
+                let _permit = permit;
+                let _ = forwarder.serve_tcp_client(stream).await;
+            });
+        }
+    }
+
+    /// Answers length-prefixed queries on one connection until the client
+    /// closes it or goes idle.
+    async fn serve_tcp_client(&self, mut stream: TcpStream) -> io::Result<()> {
+        loop {
+            let len = match time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+                Ok(len) => len?,
+                Err(_) => return Ok(()),
+            };
+            let mut query = vec![0u8; len as usize];
+            stream.read_exact(&mut query).await?;
+            let Some(reply) = self.answer(&query).await else {
+                return Ok(());
+            };
+            let mut message = Vec::with_capacity(reply.len() + 2);
+            message.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+            message.extend_from_slice(&reply);
+            stream.write_all(&message).await?;
+        }
+    }
+
+    /// Works out the reply to `query`, from the overrides, the cache or the
+    /// server. Messages too short to carry an ID get no reply at all.
+    async fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < 12 || query[2] & 0x80 != 0 {
+            return None;
+        }
+        // Only standard queries are forwarded.
+        if query[2] & 0x78 != 0 {
+            return Some(dns::build_reply(query, None, dns::RCODE_NOTIMP, &[], 0));
+        }
+        let Some(question) = dns::parse_question(query) else {
+            return Some(dns::build_reply(query, None, dns::RCODE_FORMERR, &[], 0));
+        };
+        let pinned = self.resolver.pinned(&question.name);
+        if let Some(ips) = pinned.or_else(|| self.overrides.get(&question.name).map(Vec::as_slice)) {
+            // Other record types of a pinned name could point clients
+            // elsewhere, so they get an empty answer.
+            let ips: Vec<IpAddr> = ips
+                .iter()
+                .filter(|ip| match question.qtype {
+                    dns::TYPE_A => ip.is_ipv4(),
+                    dns::TYPE_AAAA => ip.is_ipv6(),
+                    _ => false,
+                })
+                .copied()
+                .collect();
+            return Some(dns::build_reply(query, Some(&question), 0, &ips, OVERRIDE_TTL));
+        }
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        if let Some(mut reply) = self.cached(&question) {
+            dns::set_id(&mut reply, id);
+            return Some(reply);
+        }
+        match self.forward(query, &question).await {
+            Ok(mut reply) => {
+                self.store(&question, &reply);
+                dns::set_id(&mut reply, id);
+                Some(reply)
+            }
+            Err(err) => {
+                // log_error would count this as a failed proxy connection.
+                self.logger
+                    .log_info(&format!("DNS forwarder: {}: {}", question.name, err))
+                    .await;
+                Some(dns::build_reply(query, Some(&question), dns::RCODE_SERVFAIL, &[], 0))
+            }
+        }
+    }
+
+    /// Sends the query on with ID 0, which lets DoH responses be cached on
+    /// the way, and checks that the reply is for the same question.
+    async fn forward(&self, query: &[u8], question: &Question) -> io::Result<Vec<u8>> {
+        let mut upstream = query.to_vec();
+        dns::set_id(&mut upstream, 0);
+        let reply = self.resolver.exchange(&upstream).await?;
+        let matches = reply.len() >= 12
+            && reply[..2] == [0, 0]
+            && reply[2] & 0x80 != 0
+            && dns::parse_question(&reply).is_some_and(|answered| {
+                answered.name == question.name && answered.qtype == question.qtype && answered.qclass == question.qclass
+            });
+        if !matches {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "reply does not match the query"));
+        }
+        Ok(reply)
+    }
+
+    fn cached(&self, question: &Question) -> Option<Vec<u8>> {
+        let key = (question.name.clone(), question.qtype, question.qclass);
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.get(&key)?;
+        let now = Instant::now();
+        if entry.expires <= now {
+            cache.remove(&key);
+            return None;
+        }
+        let mut reply = entry.reply.clone();
+        dns::age_ttls(&mut reply, now.duration_since(entry.stored).as_secs() as u32);
+        Some(reply)
+    }
+
+    /// Keeps a successful reply for its lowest TTL. When the cache is full,
+    /// expired replies are dropped first, then the one closest to expiry.
+    fn store(&self, question: &Question, reply: &[u8]) {
+        if dns::rcode(reply) != 0 {
+            return;
+        }
+        let Some(ttl) = dns::min_ttl(reply).filter(|ttl| *ttl > 0) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() >= CACHE_SIZE {
+            if let Some(key) = cache.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone()) {
+                cache.remove(&key);
+            }
+        }
+        cache.insert(
+            (question.name.clone(), question.qtype, question.qclass),
+            CachedReply {
+                reply: reply.to_vec(),
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+}
+
+/// Parses `DOMAIN=IP[,IP...]` as given to `--dns-override`.
+pub fn parse_override(value: &str) -> Result<(String, Vec<IpAddr>), String> {
+    let (domain, ips) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid DNS override '{}', expected DOMAIN=IP", value))?;
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    if domain.is_empty() {
+        return Err(format!("invalid DNS override '{}', expected DOMAIN=IP", value));
+    }
+    let ips = ips
+        .split(',')
+        .map(|ip| {
+            ip.trim()
+                .parse::<IpAddr>()
+                .map_err(|_| format!("invalid address '{}' in DNS override", ip))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((domain, ips))
+}
+
+/// Parses the comma-separated networks given to `--dns-allow`.
+pub fn parse_allowed(value: &str) -> Result<Vec<Cidr>, String> {
+    value
+        .split(',')
+        .map(|cidr| Cidr::parse(cidr.trim()).map_err(|err| format!("invalid --dns-allow network: {}", err)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarder(overrides: &[&str], allowed: &str) -> Forwarder {
+        let overrides = overrides.iter().map(|value| parse_override(value).unwrap()).collect();
+        let allowed = if allowed.is_empty() {
+            Vec::new()
+        } else {
+            parse_allowed(allowed).unwrap()
+        };
+        let resolver = dns::Resolver::new("system", dns::DohMethod::Get, &[]).unwrap();
+        let logger = Arc::new(Logger::new(&None, &None, &None, true).unwrap());
+        Forwarder::new(resolver, overrides, allowed, logger)
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn serves_local_networks_by_default() {
+        let forwarder = forwarder(&[], "");
+        for client in ["127.0.0.1", "192.168.1.20", "10.1.2.3", "fd00::1", "::ffff:172.16.0.9"] {
+            assert!(forwarder.is_allowed(ip(client)), "{}", client);
+        }
+        for client in ["8.8.8.8", "172.32.0.1", "2001:db8::1"] {
+            assert!(!forwarder.is_allowed(ip(client)), "{}", client);
+        }
+    }
+
+    #[test]
+    fn allow_list_replaces_the_local_networks() {
+        let forwarder = forwarder(&[], "203.0.113.0/24, 2001:db8::/32");
+        assert!(forwarder.is_allowed(ip("203.0.113.7")));
+        assert!(forwarder.is_allowed(ip("2001:db8::1")));
+        assert!(!forwarder.is_allowed(ip("192.168.1.20")));
+        assert!(parse_allowed("10.0.0.0/33").is_err());
+    }
+
+    #[tokio::test]
+    async fn overrides_match_names_exactly() {
+        let forwarder = forwarder(&["example.com=192.0.2.7,2001:db8::7"], "");
+
+        let query = dns::build_query(0x1234, "example.com", dns::TYPE_A).unwrap();
+        let reply = forwarder.answer(&query).await.unwrap();
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        assert_eq!(dns::rcode(&reply), 0);
+        assert_eq!(dns::min_ttl(&reply), Some(OVERRIDE_TTL));
+        assert!(reply.ends_with(&[192, 0, 2, 7]));
+
+        let query = dns::build_query(1, "example.com", dns::TYPE_AAAA).unwrap();
+        let reply = forwarder.answer(&query).await.unwrap();
+        let expected: std::net::Ipv6Addr = "2001:db8::7".parse().unwrap();
+        assert!(reply.ends_with(&expected.octets()));
+
+        // The subdomain is not overridden and goes to the server, which
+        // the system backend cannot be asked directly.
+        let query = dns::build_query(2, "www.example.com", dns::TYPE_A).unwrap();
+        let reply = forwarder.answer(&query).await.unwrap();
+        assert_eq!(dns::rcode(&reply), dns::RCODE_SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn rejects_what_it_does_not_forward() {
+        let forwarder = forwarder(&[], "");
+        assert!(forwarder.answer(&[0u8; 5]).await.is_none());
+
+        let mut query = dns::build_query(3, "example.com", dns::TYPE_A).unwrap();
+        query[2] |= 0x10;
+        let reply = forwarder.answer(&query).await.unwrap();
+        assert_eq!(dns::rcode(&reply), dns::RCODE_NOTIMP);
+    }
+}
@@ -0,0 +1,511 @@
+//! Reading the client's ClientHello across reads and records, and cutting
+//! it into the records and TCP segments a strategy asks for.
+
+use std::io;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time;
+use crate::{tls, CLIENT_HELLO_TIMEOUT, MAX_CLIENT_HELLO_LEN};
+use crate::strategy::{FragmentLayout, FragmentMethod, RecordVersion, SplitPosition, Strategy};
+
+#[derive(Clone)]
+pub struct InitialTlsData {
+    /// Header of the first record as sent by the client.
+    pub head: [u8; tls::RECORD_HEADER_LEN],
+    /// The ClientHello handshake message, reassembled across records.
+    pub hello: Vec<u8>,
+    /// Handshake bytes that followed the ClientHello in its last record.
+    pub extra: Vec<u8>,
+    /// Everything read from the client, byte for byte.
+    raw: Vec<u8>,
+}
+
+/// Reads the client's first flight up to the end of the ClientHello. The
+/// header read has no deadline because browsers often open tunnels ahead of
+/// time; the rest of the hello must arrive within `CLIENT_HELLO_TIMEOUT`.
+/// Every byte read is appended to `raw`, also when reading fails, so that
+/// the caller can still pass it on.
+pub async fn read_initial_tls_data<R: AsyncRead + Unpin>(reader: &mut R, raw: &mut Vec<u8>) -> io::Result<InitialTlsData> {
+    let start = raw.len();
+    read_raw(reader, raw, tls::RECORD_HEADER_LEN).await?;
+    time::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello_records(reader, raw, start))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out reading ClientHello"))?
+}
+
+async fn read_client_hello_records<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    raw: &mut Vec<u8>,
+    start: usize,
+) -> io::Result<InitialTlsData> {
+    let mut head = [0u8; tls::RECORD_HEADER_LEN];
+    head.copy_from_slice(&raw[start..start + tls::RECORD_HEADER_LEN]);
+    let header = read_tls_record(reader, raw, start).await?;
+    let mut hello = raw[start + tls::RECORD_HEADER_LEN..].to_vec();
+    let mut extra = Vec::new();
+
+    if header.content_type != tls::CONTENT_TYPE_HANDSHAKE {
+        return Ok(InitialTlsData {
+            head,
+            hello,
+            extra,
+            raw: raw[start..].to_vec(),
+        });
+    }
+
+    loop {
+        match tls::handshake_length(&hello) {
+            Ok(len) if hello.len() >= len => {
+                extra = hello.split_off(len);
+                break;
+            }
+            Ok(len) if len > MAX_CLIENT_HELLO_LEN => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("ClientHello of {} bytes exceeds the limit", len),
+                ));
+            }
+            Ok(_) | Err(tls::ParseError::Incomplete) => {}
+            // Not a ClientHello: nothing to reassemble, pass it on as is.
+            Err(_) => break,
+        }
+
+        let next = raw.len();
+        read_raw(reader, raw, tls::RECORD_HEADER_LEN).await?;
+        let next_header = read_tls_record(reader, raw, start).await?;
+        if next_header.content_type != tls::CONTENT_TYPE_HANDSHAKE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ClientHello interrupted by a non-handshake record",
+            ));
+        }
+        hello.extend_from_slice(&raw[next + tls::RECORD_HEADER_LEN..]);
+    }
+
+    Ok(InitialTlsData {
+        head,
+        hello,
+        extra,
+        raw: raw[start..].to_vec(),
+    })
+}
+
+/// Parses the record header at the end of `raw` and appends exactly the
+/// declared number of payload bytes. `start` is where the client's first
+/// flight begins in `raw`, for the size limit.
+async fn read_tls_record<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    raw: &mut Vec<u8>,
+    start: usize,
+) -> io::Result<tls::RecordHeader> {
+    let header = tls::RecordHeader::parse(&raw[raw.len() - tls::RECORD_HEADER_LEN..])
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    if raw.len() - start + header.length > MAX_CLIENT_HELLO_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ClientHello exceeds the size limit",
+        ));
+    }
+    read_raw(reader, raw, header.length).await?;
+    Ok(header)
+}
+
+/// Appends exactly `n` bytes from `reader` to `raw`. What was read stays in
+/// `raw` even when the read fails or is cancelled halfway.
+async fn read_raw<R: AsyncRead + Unpin>(reader: &mut R, raw: &mut Vec<u8>, n: usize) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    let mut left = n;
+    while left > 0 {
+        let want = left.min(buf.len());
+        let read = reader.read(&mut buf[..want]).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed inside a TLS record",
+            ));
+        }
+        raw.extend_from_slice(&buf[..read]);
+        left -= read;
+    }
+    Ok(())
+}
+
+/// Random source for fragment layouts: seeded with `--fragment-seed` so a
+/// layout can be reproduced, from entropy otherwise.
+pub fn fragment_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Returns the chunks to write for the client's first flight and, for the
+/// fake method, the decoy that replaces the first chunk on the wire. A
+/// first flight shorter than the decoy is sent without one.
+pub fn prepare_client_hello<R: Rng>(
+    initial: &InitialTlsData,
+    mut strategy: Strategy,
+    fake_sni: &str,
+    rng: &mut R,
+) -> (Option<Vec<u8>>, Vec<Vec<u8>>) {
+    if strategy.method == FragmentMethod::None {
+        return (None, vec![initial.raw.clone()]);
+    }
+    let decoy = if strategy.method == FragmentMethod::Fake {
+        let decoy = tls::build_client_hello(fake_sni);
+        strategy.tcp_split.push(SplitPosition::Offset(decoy.len()));
+        Some(decoy)
+    } else {
+        None
+    };
+    let mut segments = fragment_client_hello(initial, &strategy, rng);
+    let Some(decoy) = decoy else {
+        return (None, segments);
+    };
+    // The decoy stands in for the whole first segment, so that segment has
+    // to be at least as long; the segments it swallows are hidden by it.
+    while segments[0].len() < decoy.len() && segments.len() > 1 {
+        let next = segments.remove(1);
+        segments[0].extend_from_slice(&next);
+    }
+    if segments[0].len() < decoy.len() {
+        return (None, segments);
+    }
+    (Some(decoy), segments)
+}
+
+/// Splits the reassembled ClientHello into records according to the strategy
+/// method and the resulting stream into TCP segments at its split positions.
+/// Each returned chunk is meant to be written to the socket on its own. With
+/// a fragment delay every record also ends up in its own chunk.
+fn fragment_client_hello<R: Rng>(initial: &InitialTlsData, strategy: &Strategy, rng: &mut R) -> Vec<Vec<u8>> {
+    let method = strategy.method;
+    let data = &initial.hello;
+    let sni = tls::parse_client_hello(data)
+        .ok()
+        .and_then(|hello| hello.sni)
+        .map(|sni| sni.name);
+
+    let layout = &strategy.layout;
+    let (ends, version) = match method {
+        FragmentMethod::Random => (fragment_random(data, layout, rng), RecordVersion::Fixed([0x03, 0x04])),
+        FragmentMethod::Sni | FragmentMethod::Fake => (
+            sni.as_ref()
+                .map(|name| fragment_sni(data, name))
+                .unwrap_or_else(|| fragment_random(data, layout, rng)),
+            RecordVersion::Fixed([0x03, 0x04]),
+        ),
+        FragmentMethod::None | FragmentMethod::TcpSplit => (fragment_whole(data), RecordVersion::Copy),
+    };
+
+    let version = strategy.record_version.unwrap_or(version);
+    let original = [initial.head[1], initial.head[2]];
+    let mut stream = build_records(data, &ends, version, original, rng);
+    if !initial.extra.is_empty() {
+        stream.extend_from_slice(&initial.head[..3]);
+        stream.extend_from_slice(&int_to_2_bytes(initial.extra.len()));
+        stream.extend_from_slice(&initial.extra);
+    }
+
+    let mut split = strategy.tcp_split.clone();
+    if method == FragmentMethod::TcpSplit && split.is_empty() {
+        split.push(SplitPosition::Sni);
+    }
+    let mut cuts: Vec<usize> = split
+        .iter()
+        .filter_map(|pos| match pos {
+            SplitPosition::Offset(n) => Some(*n),
+            SplitPosition::Sni => sni.as_ref().map(|name| {
+                stream_offset(&ends, name.start + name.len().div_ceil(2))
+            }),
+        })
+        .collect();
+    if !strategy.timing.is_zero() {
+        cuts.extend(
+            ends.iter()
+                .enumerate()
+                .map(|(i, end)| end + (i + 1) * tls::RECORD_HEADER_LEN),
+        );
+    }
+    cuts.retain(|n| *n > 0 && *n < stream.len());
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let mut segments = Vec::with_capacity(cuts.len() + 1);
+    let mut prev = 0;
+    for cut in cuts.into_iter().chain(std::iter::once(stream.len())) {
+        segments.push(stream[prev..cut].to_vec());
+        prev = cut;
+    }
+    segments
+}
+
+/// Record boundaries are expressed as the payload offsets at which each
+/// record ends; the last entry is always `data.len()`.
+fn fragment_random<R: Rng>(data: &[u8], layout: &FragmentLayout, rng: &mut R) -> Vec<usize> {
+    let window = layout.window.map_or(data.len(), |n| n.min(data.len()));
+    let max_size = layout.max_size.clamp(1, tls::MAX_RECORD_LEN);
+    let min_size = layout.min_size.clamp(1, max_size);
+    let max_fragments = layout.max_fragments.unwrap_or(usize::MAX);
+
+    let mut ends = Vec::new();
+    let mut pos = 0;
+    if let Some(zero) = data[..window].iter().position(|b| *b == 0) {
+        if (min_size..=max_size).contains(&(zero + 1)) && max_fragments > 1 {
+            pos = zero + 1;
+            ends.push(pos);
+        }
+    }
+    // One record is kept in reserve for whatever is left after the loop.
+    while pos < window && ends.len() + 1 < max_fragments {
+        let left = window - pos;
+        pos += if left <= min_size {
+            left
+        } else {
+            // Each record still to come holds at most max_size, so this
+            // one takes at least what they cannot.
+            let later = (max_fragments - ends.len() - 1).saturating_mul(max_size);
+            let high = left.min(max_size);
+            rng.gen_range(left.saturating_sub(later).max(min_size).min(high)..=high)
+        };
+        ends.push(pos);
+    }
+    while pos < data.len() {
+        pos = (pos + tls::MAX_RECORD_LEN).min(data.len());
+        ends.push(pos);
+    }
+    ends
+}
+
+fn fragment_sni(data: &[u8], name: &std::ops::Range<usize>) -> Vec<usize> {
+    let middle = name.start + name.len().div_ceil(2);
+    let mut ends = vec![name.start, middle, name.end, data.len()];
+    ends.dedup();
+    ends
+}
+
+fn fragment_whole(data: &[u8]) -> Vec<usize> {
+    let mut ends: Vec<usize> = (tls::MAX_RECORD_LEN..data.len())
+        .step_by(tls::MAX_RECORD_LEN)
+        .collect();
+    ends.push(data.len());
+    ends
+}
+
+fn build_records<R: Rng>(
+    data: &[u8],
+    ends: &[usize],
+    version: RecordVersion,
+    original: [u8; 2],
+    rng: &mut R,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + ends.len() * tls::RECORD_HEADER_LEN);
+    let mut prev = 0;
+    for &end in ends {
+        let version = match version {
+            RecordVersion::Fixed(v) => v,
+            RecordVersion::Copy => original,
+            RecordVersion::Random => [0x03, rng.gen_range(0x01..=0x04)],
+        };
+        out.extend_from_slice(&[tls::CONTENT_TYPE_HANDSHAKE, version[0], version[1]]);
+        out.extend_from_slice(&int_to_2_bytes(end - prev));
+        out.extend_from_slice(&data[prev..end]);
+        prev = end;
+    }
+    out
+}
+
+/// Maps an offset into the ClientHello payload to the matching offset in the
+/// stream produced by `build_records`.
+fn stream_offset(ends: &[usize], payload_offset: usize) -> usize {
+    let record = ends.iter().filter(|end| **end < payload_offset).count();
+    payload_offset + (record + 1) * tls::RECORD_HEADER_LEN
+}
+
+fn int_to_2_bytes(n: usize) -> [u8; 2] {
+    [(n >> 8) as u8, (n & 0xff) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::FragmentTiming;
+    use crate::DEFAULT_FAKE_SNI;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::Duration;
+
+    async fn initial_tls_data(sni: &str) -> InitialTlsData {
+        let hello = tls::build_client_hello(sni);
+        read_initial_tls_data(&mut hello.as_slice(), &mut Vec::new()).await.unwrap()
+    }
+
+    /// Wraps each chunk of `payload` in a record of `content_type`.
+    fn records(content_type: u8, payload: &[u8], chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for part in payload.chunks(chunk) {
+            out.extend_from_slice(&[content_type, 0x03, 0x01]);
+            out.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            out.extend_from_slice(part);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn reassembles_a_hello_split_across_records() {
+        let hello = tls::build_client_hello("www.example.com")[tls::RECORD_HEADER_LEN..].to_vec();
+        let sent = records(tls::CONTENT_TYPE_HANDSHAKE, &hello, 50);
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn({
+            let sent = sent.clone();
+            async move { client.write_all(&sent).await }
+        });
+        let mut raw = Vec::new();
+        let initial = read_initial_tls_data(&mut server, &mut raw).await.unwrap();
+        writer.await.unwrap().unwrap();
+        assert!(sent.len() > 3 * 50);
+        assert_eq!(initial.hello, hello);
+        assert!(initial.extra.is_empty());
+        assert_eq!(initial.head, sent[..tls::RECORD_HEADER_LEN]);
+        assert_eq!((initial.raw, raw), (sent.clone(), sent));
+    }
+
+    #[tokio::test]
+    async fn refuses_a_hello_over_the_size_limit() {
+        let mut hello = vec![tls::HANDSHAKE_CLIENT_HELLO];
+        hello.extend_from_slice(&((MAX_CLIENT_HELLO_LEN + 1) as u32).to_be_bytes()[1..]);
+        hello.resize(1000, 0);
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(&records(tls::CONTENT_TYPE_HANDSHAKE, &hello, 1000)).await.unwrap();
+        let err = read_initial_tls_data(&mut server, &mut Vec::new()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with("exceeds the limit"), "{}", err);
+
+        // A hello within the limit whose record headers take it over.
+        let mut hello = vec![tls::HANDSHAKE_CLIENT_HELLO];
+        hello.extend_from_slice(&((MAX_CLIENT_HELLO_LEN - 8) as u32).to_be_bytes()[1..]);
+        hello.resize(MAX_CLIENT_HELLO_LEN - 4, 0);
+        let sent = records(tls::CONTENT_TYPE_HANDSHAKE, &hello, 16 * 1024);
+        let (mut client, mut server) = tokio::io::duplex(sent.len());
+        client.write_all(&sent).await.unwrap();
+        let err = read_initial_tls_data(&mut server, &mut Vec::new()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "ClientHello exceeds the size limit");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_on_a_hello_that_stops_coming() {
+        let hello = tls::build_client_hello("www.example.com");
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(&hello[..hello.len() / 2]).await.unwrap();
+        let mut raw = Vec::new();
+        let started = time::Instant::now();
+        let err = read_initial_tls_data(&mut server, &mut raw).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), CLIENT_HELLO_TIMEOUT);
+        // What arrived is kept so that it can still be passed on.
+        assert_eq!(raw, hello[..hello.len() / 2]);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_hello_interrupted_by_another_record() {
+        let hello = tls::build_client_hello("www.example.com")[tls::RECORD_HEADER_LEN..].to_vec();
+        let mut sent = records(tls::CONTENT_TYPE_HANDSHAKE, &hello[..100], 100);
+        sent.extend_from_slice(&records(0x15, &[0x02, 0x28], 2));
+        sent.extend_from_slice(&records(tls::CONTENT_TYPE_HANDSHAKE, &hello[100..], 16 * 1024));
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(&sent).await.unwrap();
+        let mut raw = Vec::new();
+        let err = read_initial_tls_data(&mut server, &mut raw).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(raw, sent[..tls::RECORD_HEADER_LEN * 2 + 100 + 2]);
+    }
+
+    fn fake_strategy() -> Strategy {
+        Strategy {
+            method: FragmentMethod::Fake,
+            timing: FragmentTiming {
+                delay: Duration::from_millis(1),
+                jitter: Duration::ZERO,
+            },
+            ..Strategy::direct()
+        }
+    }
+
+    #[tokio::test]
+    async fn decoy_covers_the_whole_first_segment() {
+        let initial = initial_tls_data("www.example-long-hostname.com").await;
+        let (decoy, segments) =
+            prepare_client_hello(&initial, fake_strategy(), DEFAULT_FAKE_SNI, &mut fragment_rng(Some(1)));
+        let decoy = decoy.expect("no decoy");
+        assert!(segments[0].len() >= decoy.len());
+        assert!(segments.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn first_flight_shorter_than_the_decoy_goes_without_one() {
+        let initial = initial_tls_data("a.io").await;
+        let fake_sni = format!("{0}.{0}.{0}.example", "a".repeat(60));
+        let (decoy, segments) = prepare_client_hello(&initial, fake_strategy(), &fake_sni, &mut fragment_rng(Some(1)));
+        assert!(decoy.is_none());
+        assert!(!segments.is_empty());
+    }
+
+    fn record_sizes(ends: &[usize]) -> Vec<usize> {
+        let mut prev = 0;
+        ends.iter()
+            .map(|&end| {
+                let size = end - prev;
+                prev = end;
+                size
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_records_keep_max_size_within_max_fragments() {
+        let data = vec![1u8; 1000];
+        let layout = FragmentLayout {
+            min_size: 1,
+            max_size: 300,
+            max_fragments: Some(4),
+            window: None,
+        };
+        for seed in 0..200 {
+            let ends = fragment_random(&data, &layout, &mut fragment_rng(Some(seed)));
+            let sizes = record_sizes(&ends);
+            assert_eq!(ends.last(), Some(&data.len()));
+            assert!(sizes.len() <= 4, "{:?}", sizes);
+            assert!(sizes.iter().all(|&size| (1..=300).contains(&size)), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn a_fixed_seed_gives_a_fixed_layout_whose_last_record_fits() {
+        let data = vec![1u8; 1000];
+        let layout = FragmentLayout {
+            min_size: 200,
+            max_size: 300,
+            max_fragments: Some(4),
+            window: None,
+        };
+        let ends = fragment_random(&data, &layout, &mut fragment_rng(Some(7)));
+        assert_eq!(ends, fragment_random(&data, &layout, &mut fragment_rng(Some(7))));
+        let sizes = record_sizes(&ends);
+        assert_eq!(sizes, vec![203, 231, 275, 291]);
+        assert!(sizes[3] <= layout.max_size);
+    }
+
+    #[test]
+    fn max_fragments_wins_over_max_size() {
+        let data = vec![1u8; 1000];
+        let layout = FragmentLayout {
+            min_size: 1,
+            max_size: 100,
+            max_fragments: Some(4),
+            window: None,
+        };
+        let ends = fragment_random(&data, &layout, &mut fragment_rng(Some(1)));
+        assert_eq!(record_sizes(&ends), vec![100, 100, 100, 700]);
+    }
+}
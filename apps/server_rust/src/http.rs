@@ -0,0 +1,664 @@
+//! HTTP/1.x message framing for the plain forward proxy: heads are read with
+//! size limits, bodies are copied exactly as framed, and requests are
+//! rewritten to origin-form without hop-by-hop headers.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Duration};
+
+/// Largest request or response head accepted, including the blank line.
+pub const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Longest chunk-size or trailer line in a chunked body.
+const MAX_LINE_LEN: usize = 4096;
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Headers that only concern a single hop. Those named by `Connection` are
+/// dropped as well. Transfer-Encoding stays, since bodies are relayed with
+/// their original framing.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Header names and values are kept as Latin-1, so that any byte survives
+/// the trip back to the wire unchanged.
+pub type Headers = Vec<(String, String)>;
+
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+pub struct Response {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+/// How the end of a message body is found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Body {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// The body runs until the sender closes the connection.
+    UntilClose,
+}
+
+impl Request {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let start = format!("{} {} {}", self.method, self.target, self.version);
+        serialize(&start, &self.headers)
+    }
+
+    /// Tells whether sending the request twice has the effect of sending it once.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self.method.to_ascii_uppercase().as_str(),
+            "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE"
+        )
+    }
+}
+
+impl Response {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let start = format!("{} {} {}", self.version, self.status, self.reason);
+        serialize(&start, &self.headers)
+    }
+}
+
+/// Buffers reads from `inner`, so that bytes past the end of one message
+/// are kept for the next one.
+pub struct Reader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    idle_timeout: Option<Duration>,
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            idle_timeout: None,
+        }
+    }
+
+    /// Makes every read fail with `ErrorKind::TimedOut` once the peer has
+    /// sent nothing for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Bytes read but not consumed yet.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the stream and whatever was read from it but not consumed.
+    pub fn into_parts(self) -> (R, Vec<u8>) {
+        (self.inner, self.buf)
+    }
+
+    /// Reads a head up to and including its blank line. `None` means the
+    /// peer closed the connection before sending anything.
+    pub async fn read_head(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut scanned: usize = 0;
+        loop {
+            let from = scanned.saturating_sub(3);
+            if let Some(pos) = find(&self.buf[from..], b"\r\n\r\n") {
+                let end = from + pos + 4;
+                if end > MAX_HEAD_LEN {
+                    return Err(invalid("head is too large"));
+                }
+                return Ok(Some(self.buf.drain(..end).collect()));
+            }
+            if self.buf.len() >= MAX_HEAD_LEN {
+                return Err(invalid("head is too large"));
+            }
+            scanned = self.buf.len();
+            if self.fill().await? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(eof("connection closed inside a head"))
+                };
+            }
+        }
+    }
+
+    /// Copies a body framed as `body` to `writer` and returns the number of
+    /// bytes copied.
+    pub async fn copy_body<W: AsyncWrite + Unpin>(&mut self, body: Body, writer: &mut W) -> io::Result<u64> {
+        match body {
+            Body::Empty => Ok(0),
+            Body::Length(len) => self.copy_exact(len, writer).await,
+            Body::UntilClose => {
+                let mut total = 0;
+                loop {
+                    if self.buf.is_empty() && self.fill().await? == 0 {
+                        return Ok(total);
+                    }
+                    writer.write_all(&self.buf).await?;
+                    total += self.buf.len() as u64;
+                    self.buf.clear();
+                }
+            }
+            Body::Chunked => {
+                let mut total = 0;
+                loop {
+                    let line = self.read_line().await?;
+                    let size = parse_chunk_size(&line)?;
+                    writer.write_all(&line).await?;
+                    total += line.len() as u64;
+                    if size == 0 {
+                        break;
+                    }
+                    // The chunk data is followed by its own CRLF.
+                    total += self.copy_exact(size + 2, writer).await?;
+                }
+                loop {
+                    let line = self.read_line().await?;
+                    writer.write_all(&line).await?;
+                    total += line.len() as u64;
+                    if line == b"\r\n" {
+                        return Ok(total);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, len: u64, writer: &mut W) -> io::Result<u64> {
+        let mut left = len;
+        while left > 0 {
+            if self.buf.is_empty() && self.fill().await? == 0 {
+                return Err(eof("connection closed inside a body"));
+            }
+            let take = left.min(self.buf.len() as u64) as usize;
+            writer.write_all(&self.buf[..take]).await?;
+            self.buf.drain(..take);
+            left -= take as u64;
+        }
+        Ok(len)
+    }
+
+    async fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(pos) = find(&self.buf, b"\r\n") {
+                return Ok(self.buf.drain(..pos + 2).collect());
+            }
+            if self.buf.len() > MAX_LINE_LEN {
+                return Err(invalid("chunk line is too long"));
+            }
+            if self.fill().await? == 0 {
+                return Err(eof("connection closed inside a chunked body"));
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK];
+        let read = self.inner.read(&mut chunk);
+        let n = match self.idle_timeout {
+            Some(timeout) => time::timeout(timeout, read)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "peer sent nothing for too long"))??,
+            None => read.await?,
+        };
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+pub fn parse_request(head: &[u8]) -> Result<Request, String> {
+    let (start, headers) = parse_head(head)?;
+    let mut parts = start.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("Invalid request line".to_string());
+    };
+    if method.is_empty() || target.is_empty() {
+        return Err("Invalid request line".to_string());
+    }
+    if !version.starts_with("HTTP/1.") {
+        return Err(format!("Unsupported HTTP version '{}'", version));
+    }
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+pub fn parse_response(head: &[u8]) -> Result<Response, String> {
+    let (start, headers) = parse_head(head)?;
+    let mut parts = start.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err("Invalid status line".to_string());
+    }
+    let status = parts
+        .next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .filter(|s| (100..1000).contains(s))
+        .ok_or_else(|| "Invalid status code".to_string())?;
+    Ok(Response {
+        version: version.to_string(),
+        status,
+        reason: parts.next().unwrap_or("").to_string(),
+        headers,
+    })
+}
+
+fn parse_head(head: &[u8]) -> Result<(String, Headers), String> {
+    let head = head.strip_suffix(b"\r\n\r\n").unwrap_or(head);
+    let mut lines = head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let start = latin1(lines.next().unwrap_or_default());
+
+    let mut headers = Vec::new();
+    for line in lines {
+        if line.first().is_some_and(|b| *b == b' ' || *b == b'\t') {
+            return Err("Obsolete header line folding".to_string());
+        }
+        let pos = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or_else(|| "Invalid header line".to_string())?;
+        let name = &line[..pos];
+        if name.is_empty() || name.iter().any(|b| b.is_ascii_whitespace()) {
+            return Err("Invalid header name".to_string());
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err("Too many headers".to_string());
+        }
+        headers.push((latin1(name), latin1(&line[pos + 1..]).trim().to_string()));
+    }
+    Ok((start, headers))
+}
+
+/// Returns the first value of header `name`.
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Tells whether any `name` header lists `token` among its comma-separated
+/// values.
+pub fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Works out how a request body is framed. A request carrying both
+/// Transfer-Encoding and Content-Length, or conflicting lengths, is refused
+/// rather than guessed at, as the two ends could disagree on where it ends.
+pub fn request_body(headers: &[(String, String)]) -> Result<Body, String> {
+    let lengths = content_lengths(headers)?;
+    if header(headers, "transfer-encoding").is_some() {
+        if !lengths.is_empty() {
+            return Err("Both Transfer-Encoding and Content-Length are set".to_string());
+        }
+        return if is_chunked(headers) {
+            Ok(Body::Chunked)
+        } else {
+            Err("Unsupported Transfer-Encoding".to_string())
+        };
+    }
+    match lengths.as_slice() {
+        [] => Ok(Body::Empty),
+        [first, rest @ ..] if rest.iter().all(|len| len == first) => Ok(Body::Length(*first)),
+        _ => Err("Conflicting Content-Length headers".to_string()),
+    }
+}
+
+/// Works out how the body of a response to `method` is framed.
+pub fn response_body(method: &str, response: &Response) -> Body {
+    if method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&response.status)
+        || response.status == 204
+        || response.status == 304
+    {
+        return Body::Empty;
+    }
+    if header(&response.headers, "transfer-encoding").is_some() {
+        return if is_chunked(&response.headers) {
+            Body::Chunked
+        } else {
+            Body::UntilClose
+        };
+    }
+    match content_lengths(&response.headers).as_deref() {
+        Ok([]) | Err(_) => Body::UntilClose,
+        Ok([first, rest @ ..]) if rest.iter().all(|len| len == first) => Body::Length(*first),
+        Ok(_) => Body::UntilClose,
+    }
+}
+
+/// Tells whether the sender of a message wants the connection kept open
+/// after it.
+pub fn keep_alive(version: &str, headers: &[(String, String)]) -> bool {
+    if has_token(headers, "connection", "close") {
+        return false;
+    }
+    version != "HTTP/1.0" || has_token(headers, "connection", "keep-alive")
+}
+
+/// Returns the protocol a message asks to switch to: its `Upgrade` header,
+/// provided `Connection` lists `upgrade` as well.
+pub fn upgrade(headers: &[(String, String)]) -> Option<&str> {
+    if !has_token(headers, "connection", "upgrade") {
+        return None;
+    }
+    header(headers, "upgrade").filter(|v| !v.is_empty())
+}
+
+/// Splits an absolute-form `http://` target into its authority and its
+/// origin-form path. An origin-form target gives `None`.
+pub fn split_absolute(target: &str) -> Result<Option<(String, String)>, String> {
+    if target.starts_with('/') || target == "*" {
+        return Ok(None);
+    }
+    let Some((scheme, rest)) = target.split_once("://") else {
+        return Err("Invalid request target".to_string());
+    };
+    if !scheme.eq_ignore_ascii_case("http") {
+        return Err(format!("Unsupported scheme '{}'", scheme));
+    }
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if authority.is_empty() {
+        return Err("Missing host in request target".to_string());
+    }
+    let path = rest[end..].split('#').next().unwrap_or("");
+    let path = match path.chars().next() {
+        Some('/') => path.to_string(),
+        Some(_) => format!("/{}", path),
+        None => "/".to_string(),
+    };
+    Ok(Some((authority.to_string(), path)))
+}
+
+/// Drops the hop-by-hop headers, including those the message names in its
+/// `Connection` header.
+pub fn end_to_end(headers: &[(String, String)]) -> Headers {
+    let named: Vec<String> = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("connection") || k.eq_ignore_ascii_case("proxy-connection"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .collect();
+    headers
+        .iter()
+        .filter(|(k, _)| {
+            let k = k.to_ascii_lowercase();
+            !HOP_BY_HOP.contains(&k.as_str()) && !named.contains(&k)
+        })
+        .cloned()
+        .collect()
+}
+
+fn content_lengths(headers: &[(String, String)]) -> Result<Vec<u64>, String> {
+    headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|v| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|_| "Invalid Content-Length".to_string())
+        })
+        .collect()
+}
+
+fn is_chunked(headers: &[(String, String)]) -> bool {
+    let last = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .last();
+    last.is_some_and(|t| t.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    let line = latin1(line);
+    let size = line.trim_end().split(';').next().unwrap_or("").trim();
+    u64::from_str_radix(size, 16)
+        .ok()
+        .filter(|size| *size < u64::MAX / 2)
+        .ok_or_else(|| invalid("invalid chunk size"))
+}
+
+fn serialize(start: &str, headers: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend(start.chars().map(|c| c as u8));
+    out.extend_from_slice(b"\r\n");
+    for (name, value) in headers {
+        out.extend(name.chars().map(|c| c as u8));
+        out.extend_from_slice(b": ");
+        out.extend(value.chars().map(|c| c as u8));
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn eof(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, what.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    async fn copy(data: &[u8], body: Body) -> (io::Result<u64>, Vec<u8>, Vec<u8>) {
+        let mut reader = Reader::new(data);
+        let mut out = Vec::new();
+        let copied = reader.copy_body(body, &mut out).await;
+        (copied, out, reader.buffered().to_vec())
+    }
+
+    #[test]
+    fn only_safe_and_idempotent_methods_are_idempotent() {
+        for (method, idempotent) in [
+            ("GET", true),
+            ("head", true),
+            ("OPTIONS", true),
+            ("TRACE", true),
+            ("PUT", true),
+            ("DELETE", true),
+            ("POST", false),
+            ("PATCH", false),
+            ("CONNECT", false),
+            ("PURGE", false),
+        ] {
+            let request = parse_request(format!("{} / HTTP/1.1\r\n\r\n", method).as_bytes()).unwrap();
+            assert_eq!(request.is_idempotent(), idempotent, "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_heads() {
+        let data: &[u8] = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\n\r\nrest";
+        let mut reader = Reader::new(data);
+        assert_eq!(reader.read_head().await.unwrap().unwrap(), b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(reader.read_head().await.unwrap().unwrap(), b"GET /b HTTP/1.1\r\n\r\n");
+        assert_eq!(reader.buffered(), b"rest");
+        assert_eq!(reader.read_head().await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        assert!(Reader::new(&b""[..]).read_head().await.unwrap().is_none());
+        let huge = vec![b'a'; MAX_HEAD_LEN + 1];
+        let err = Reader::new(huge.as_slice()).read_head().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_peers_time_out() {
+        let (_client, server) = tokio::io::duplex(64);
+        let mut reader = Reader::new(server).with_idle_timeout(Duration::from_secs(5));
+        assert_eq!(reader.read_head().await.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn copies_bodies_as_framed() {
+        let (copied, out, rest) = copy(b"hello world", Body::Length(5)).await;
+        assert_eq!((copied.unwrap(), out.as_slice(), rest.as_slice()), (5, &b"hello"[..], &b" world"[..]));
+
+        let chunked = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let (copied, out, rest) = copy(chunked, Body::Chunked).await;
+        assert_eq!(copied.unwrap(), chunked.len() as u64 - 4);
+        assert_eq!(out, &chunked[..chunked.len() - 4]);
+        assert_eq!(rest, b"next");
+
+        let (copied, out, _) = copy(b"until the end", Body::UntilClose).await;
+        assert_eq!((copied.unwrap(), out.as_slice()), (13, &b"until the end"[..]));
+
+        let (copied, _, _) = copy(b"short", Body::Length(10)).await;
+        assert_eq!(copied.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let (copied, _, _) = copy(b"zz\r\nhello\r\n", Body::Chunked).await;
+        assert_eq!(copied.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_heads() {
+        let request = parse_request(b"GET http://a.test/x HTTP/1.1\r\nHost: a.test\r\nX-Name:  caf\xe9 \r\n\r\n").unwrap();
+        assert_eq!((request.method.as_str(), request.target.as_str()), ("GET", "http://a.test/x"));
+        assert_eq!(header(&request.headers, "x-name"), Some("caf\u{e9}"));
+        assert_eq!(request.to_bytes(), b"GET http://a.test/x HTTP/1.1\r\nHost: a.test\r\nX-Name: caf\xe9\r\n\r\n");
+
+        assert!(parse_request(b"GET /\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/2.0\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nBad Name: a\r\n\r\n").is_err());
+        let many: String = (0..=MAX_HEADERS).map(|i| format!("X-{}: {}\r\n", i, i)).collect();
+        assert!(parse_request(format!("GET / HTTP/1.1\r\n{}\r\n", many).as_bytes()).is_err());
+
+        let response = parse_response(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (404, "Not Found"));
+        assert!(parse_response(b"HTTP/1.1 99 Low\r\n\r\n").is_err());
+        assert!(parse_response(b"ICY 200 OK\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn frames_request_bodies() {
+        assert_eq!(request_body(&headers(&[])), Ok(Body::Empty));
+        assert_eq!(request_body(&headers(&[("Content-Length", "12")])), Ok(Body::Length(12)));
+        assert_eq!(
+            request_body(&headers(&[("Content-Length", "12, 12"), ("content-length", "12")])),
+            Ok(Body::Length(12))
+        );
+        assert_eq!(request_body(&headers(&[("Transfer-Encoding", "gzip, chunked")])), Ok(Body::Chunked));
+        assert!(request_body(&headers(&[("Content-Length", "12"), ("Content-Length", "13")])).is_err());
+        assert!(request_body(&headers(&[("Content-Length", "-1")])).is_err());
+        assert!(request_body(&headers(&[("Transfer-Encoding", "chunked"), ("Content-Length", "3")])).is_err());
+        assert!(request_body(&headers(&[("Transfer-Encoding", "chunked, gzip")])).is_err());
+    }
+
+    #[test]
+    fn frames_response_bodies() {
+        let response = |status, pairs: &[(&str, &str)]| Response {
+            version: "HTTP/1.1".to_string(),
+            status,
+            reason: String::new(),
+            headers: headers(pairs),
+        };
+        let sized = response(200, &[("Content-Length", "7")]);
+        assert_eq!(response_body("GET", &sized), Body::Length(7));
+        assert_eq!(response_body("HEAD", &sized), Body::Empty);
+        for status in [101, 204, 304] {
+            assert_eq!(response_body("GET", &response(status, &[("Content-Length", "7")])), Body::Empty);
+        }
+        assert_eq!(response_body("GET", &response(200, &[("Transfer-Encoding", "chunked")])), Body::Chunked);
+        assert_eq!(response_body("GET", &response(200, &[("Transfer-Encoding", "gzip")])), Body::UntilClose);
+        assert_eq!(response_body("GET", &response(200, &[])), Body::UntilClose);
+        assert_eq!(response_body("GET", &response(200, &[("Content-Length", "x")])), Body::UntilClose);
+    }
+
+    #[test]
+    fn decides_keep_alive() {
+        assert!(keep_alive("HTTP/1.1", &headers(&[])));
+        assert!(!keep_alive("HTTP/1.1", &headers(&[("Connection", "Upgrade, close")])));
+        assert!(!keep_alive("HTTP/1.0", &headers(&[])));
+        assert!(keep_alive("HTTP/1.0", &headers(&[("Connection", "Keep-Alive")])));
+    }
+
+    #[test]
+    fn splits_absolute_targets() {
+        assert_eq!(
+            split_absolute("http://user:pw@a.test:8080/p?q#frag").unwrap(),
+            Some(("a.test:8080".to_string(), "/p?q".to_string()))
+        );
+        assert_eq!(
+            split_absolute("HTTP://a.test?q").unwrap(),
+            Some(("a.test".to_string(), "/?q".to_string()))
+        );
+        assert_eq!(split_absolute("http://a.test").unwrap().unwrap().1, "/");
+        assert_eq!(split_absolute("/index.html").unwrap(), None);
+        assert_eq!(split_absolute("*").unwrap(), None);
+        assert!(split_absolute("https://a.test/").is_err());
+        assert!(split_absolute("http:///path").is_err());
+        assert!(split_absolute("a.test:80").is_err());
+    }
+
+    #[test]
+    fn drops_hop_by_hop_headers() {
+        let kept = end_to_end(&headers(&[
+            ("Host", "a.test"),
+            ("Connection", "keep-alive, X-Secret"),
+            ("Keep-Alive", "timeout=5"),
+            ("Proxy-Authorization", "Basic eA=="),
+            ("x-secret", "1"),
+            ("Transfer-Encoding", "chunked"),
+        ]));
+        assert_eq!(kept, headers(&[("Host", "a.test"), ("Transfer-Encoding", "chunked")]));
+    }
+
+    #[test]
+    fn finds_the_upgrade_protocol() {
+        let asked = headers(&[("Connection", "keep-alive, Upgrade"), ("Upgrade", "websocket")]);
+        assert_eq!(upgrade(&asked), Some("websocket"));
+        assert_eq!(upgrade(&headers(&[("Upgrade", "websocket")])), None);
+        assert_eq!(upgrade(&headers(&[("Connection", "upgrade"), ("Upgrade", "")])), None);
+        assert!(end_to_end(&asked).is_empty());
+    }
+}
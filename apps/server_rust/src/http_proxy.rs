@@ -0,0 +1,825 @@
+//! The plain HTTP side of the proxy: CONNECT requests that open tunnels,
+//! absolute-form requests forwarded with their keep-alive connections and
+//! upgrades, and the header tricks of `--http-evasion`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use chrono::Local;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time;
+use crate::{
+    check_credentials, http, is_auth_enabled, parse_host_port, upstream, Config, ConnectionHandler, ConnectionInfo,
+    REQUEST_HEAD_TIMEOUT,
+};
+use crate::fragment::fragment_rng;
+use crate::strategy::{FragmentMethod, FragmentTiming};
+
+const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const REQUEST_TIMEOUT_RESPONSE: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const SERVER_ERROR_RESPONSE: &[u8] = b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Header tricks applied to plain HTTP requests for blacklisted hosts. All
+/// of them are off unless enabled with `--http-evasion`.
+#[derive(Clone, Copy, Default)]
+pub struct HttpEvasion {
+    /// Send the request line and the Host header in separate TCP segments.
+    split: bool,
+    /// `Host:` becomes `hoSt:`.
+    host_case: bool,
+    /// `Host: example.com` becomes `Host:example.com`.
+    host_nospace: bool,
+    /// `Host: example.com` becomes `Host:  example.com`.
+    host_space: bool,
+    /// `example.com` becomes `example.com.`.
+    host_dot: bool,
+    /// `example.com` becomes `ExAmPlE.CoM`.
+    domain_case: bool,
+}
+
+impl HttpEvasion {
+    pub fn is_empty(&self) -> bool {
+        !(self.split
+            || self.host_case
+            || self.host_nospace
+            || self.host_space
+            || self.host_dot
+            || self.domain_case)
+    }
+}
+
+/// What becomes of the client connection after a plain HTTP exchange.
+#[derive(PartialEq, Eq)]
+enum HttpOutcome {
+    KeepAlive,
+    Close,
+    /// The server switched protocols; both sides are piped from now on.
+    Upgraded,
+}
+
+/// A plain HTTP upstream kept open for further requests to the same host.
+pub struct HttpUpstream {
+    pub host: String,
+    pub port: u16,
+    /// The upstream endpoint the connection goes through, if any.
+    pub endpoint: Option<upstream::Endpoint>,
+    pub reader: http::Reader<TcpStream>,
+}
+
+impl ConnectionHandler {
+    /// Reads and checks the next request head. `None` means the connection
+    /// is done with, and the client has been answered if it needed to be.
+    pub async fn read_http_request(&self, reader: &mut http::Reader<TcpStream>, conn_key: &str) -> Option<http::Request> {
+        let request = self.read_request_head(reader, conn_key).await?;
+        if !is_auth_ok(&self.config, &request.headers) {
+            self.handle_auth_required(reader.get_mut()).await;
+            return None;
+        }
+        Some(request)
+    }
+
+    /// Reads the next request head like `read_http_request`, without asking
+    /// for proxy credentials, which a transparent client cannot send.
+    pub async fn read_request_head(&self, reader: &mut http::Reader<TcpStream>, conn_key: &str) -> Option<http::Request> {
+        let head = match time::timeout(REQUEST_HEAD_TIMEOUT, reader.read_head()).await {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) => {
+                let _ = reader.get_mut().shutdown().await;
+                return None;
+            }
+            // A kept-alive connection that stayed idle is simply closed.
+            Err(_) if reader.buffered().is_empty() => {
+                let _ = reader.get_mut().shutdown().await;
+                return None;
+            }
+            Ok(Err(err)) => {
+                let response = error_response(&err);
+                self.handle_connection_error(reader.get_mut(), conn_key, response, err.to_string())
+                    .await;
+                return None;
+            }
+            Err(_) => {
+                let err = "Timed out reading the request head".to_string();
+                self.handle_connection_error(reader.get_mut(), conn_key, REQUEST_TIMEOUT_RESPONSE, err)
+                    .await;
+                return None;
+            }
+        };
+        let request = match http::parse_request(&head) {
+            Ok(request) => request,
+            Err(err) => {
+                self.handle_connection_error(reader.get_mut(), conn_key, BAD_REQUEST_RESPONSE, err)
+                    .await;
+                return None;
+            }
+        };
+        Some(request)
+    }
+
+    pub async fn handle_connect_request(self: &Arc<Self>, reader: http::Reader<TcpStream>, request: http::Request, conn_key: &str) {
+        let (mut client, early) = reader.into_parts();
+        let (host, port) = parse_host_port(&request.target, 443);
+        if host.is_empty() {
+            let err = "Invalid CONNECT host".to_string();
+            self.handle_connection_error(&mut client, conn_key, BAD_REQUEST_RESPONSE, err)
+                .await;
+            return;
+        }
+
+        self.blacklist_manager.check_domain(&host).await;
+        let conn_info = ConnectionInfo {
+            src_ip: conn_key.to_string(),
+            dst_domain: host.clone(),
+            method: request.method.clone(),
+            start_time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            traffic_in: 0,
+            traffic_out: 0,
+            endpoint: None,
+        };
+        self.active_connections.lock().await.insert(conn_key.to_string(), conn_info);
+
+        let head_len = request.to_bytes().len() as u64;
+        self.statistics.update_traffic(0, head_len).await;
+        self.update_conn_out(conn_key, head_len).await;
+
+        self.handle_https_connection(client, early, host, port, conn_key).await;
+    }
+
+    async fn handle_https_connection(
+        self: &Arc<Self>,
+        mut client: TcpStream,
+        early: Vec<u8>,
+        host: String,
+        port: u16,
+        conn_key: &str,
+    ) {
+        let dst = match self.connect_remote(&host, port, conn_key).await {
+            Ok(s) => s,
+            Err(err) => {
+                self.handle_upstream_error(&mut client, conn_key, &host, port, err).await;
+                return;
+            }
+        };
+
+        let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        if client.write_all(response).await.is_err() {
+            self.active_connections.lock().await.remove(conn_key);
+            return;
+        }
+        let _ = self.statistics.update_traffic(response.len() as u64, 0).await;
+        self.update_conn_in(conn_key, response.len() as u64).await;
+
+        self.handle_tunnel(client, early, dst, &host, port, conn_key).await;
+    }
+
+    /// Serves plain HTTP requests until the client or a response ends the
+    /// connection. Every request goes to its own host, and the upstream
+    /// connection is reused only while requests stay on the same host.
+    pub async fn handle_http_connection(
+        self: &Arc<Self>,
+        mut client: http::Reader<TcpStream>,
+        first: http::Request,
+        mut upstream: Option<HttpUpstream>,
+        pinned: Option<SocketAddr>,
+        conn_key: &str,
+    ) {
+        let mut request = first;
+        loop {
+            match self.forward_http_request(&mut client, &mut upstream, request, pinned, conn_key).await {
+                HttpOutcome::KeepAlive => {}
+                HttpOutcome::Close => break,
+                HttpOutcome::Upgraded => {
+                    if let Some(dst) = upstream {
+                        self.handle_upgraded(client, dst.reader, conn_key).await;
+                    }
+                    return;
+                }
+            }
+            let next = match pinned {
+                Some(_) => self.read_request_head(&mut client, conn_key).await,
+                None => self.read_http_request(&mut client, conn_key).await,
+            };
+            request = match next {
+                // A transparent client talks to the server, which has no
+                // tunnels to offer.
+                Some(request) if request.method == "CONNECT" && pinned.is_some() => break,
+                Some(request) if request.method == "CONNECT" => {
+                    return self.handle_connect_request(client, request, conn_key).await;
+                }
+                Some(request) => request,
+                None => return,
+            };
+        }
+        let _ = client.get_mut().shutdown().await;
+    }
+
+    /// Pipes an upgraded connection both ways. Bytes that either side sent
+    /// right behind the HTTP exchange are passed on first.
+    async fn handle_upgraded(&self, client: http::Reader<TcpStream>, dst: http::Reader<TcpStream>, conn_key: &str) {
+        let (client, client_early) = client.into_parts();
+        let (dst, dst_early) = dst.into_parts();
+        let (client_reader, mut client_writer) = client.into_split();
+        let (dst_reader, mut dst_writer) = dst.into_split();
+        if !client_early.is_empty() {
+            if dst_writer.write_all(&client_early).await.is_err() {
+                self.active_connections.lock().await.remove(conn_key);
+                return;
+            }
+            self.statistics.update_traffic(0, client_early.len() as u64).await;
+            self.update_conn_out(conn_key, client_early.len() as u64).await;
+        }
+        if !dst_early.is_empty() {
+            if client_writer.write_all(&dst_early).await.is_err() {
+                self.active_connections.lock().await.remove(conn_key);
+                return;
+            }
+            self.statistics.update_traffic(dst_early.len() as u64, 0).await;
+            self.update_conn_in(conn_key, dst_early.len() as u64).await;
+        }
+        self.setup_piping(client_reader, client_writer, dst_reader, dst_writer, conn_key)
+            .await;
+    }
+
+    /// Sends one request to its host and relays the response, or the 101
+    /// that switches the connection to another protocol. A `pinned` address
+    /// takes every request, whatever its Host.
+    async fn forward_http_request(
+        &self,
+        client: &mut http::Reader<TcpStream>,
+        upstream: &mut Option<HttpUpstream>,
+        request: http::Request,
+        pinned: Option<SocketAddr>,
+        conn_key: &str,
+    ) -> HttpOutcome {
+        let conn_info = ConnectionInfo {
+            src_ip: conn_key.to_string(),
+            dst_domain: String::new(),
+            method: request.method.clone(),
+            start_time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            traffic_in: 0,
+            traffic_out: 0,
+            endpoint: None,
+        };
+        self.active_connections.lock().await.insert(conn_key.to_string(), conn_info);
+
+        let (outgoing, body) = match origin_form_request(&request) {
+            Ok(v) => v,
+            Err(err) => {
+                self.handle_connection_error(client.get_mut(), conn_key, BAD_REQUEST_RESPONSE, err)
+                    .await;
+                return HttpOutcome::Close;
+            }
+        };
+        let authority = http::header(&outgoing.headers, "host").unwrap_or_default();
+        let (host, port) = parse_host_port(authority, 80);
+        if let Some(info) = self.active_connections.lock().await.get_mut(conn_key) {
+            info.dst_domain = host.clone();
+        }
+
+        let evasion = self.config.http_evasion;
+        let target = self.route_target(&host, &host, port, conn_key).await;
+        let strategy = self.blacklist_manager.strategy_for(&target).await;
+        let evade = !evasion.is_empty() && strategy.method != FragmentMethod::None;
+        self.statistics.increment_total_connections().await;
+        if evade {
+            self.statistics.increment_blocked_connections().await;
+        } else {
+            self.statistics.increment_allowed_connections().await;
+        }
+        let head = outgoing.to_bytes();
+        let segments = if evade {
+            apply_http_evasion(&head, &evasion)
+        } else {
+            vec![head]
+        };
+
+        // A reused connection may have been closed by the server meanwhile;
+        // an idempotent request without a body is then sent again on a new one.
+        let mut retry = body == http::Body::Empty && request.is_idempotent();
+        let response = loop {
+            if !matches!(upstream, Some(u) if pinned.is_some() || (u.host == host && u.port == port)) {
+                retry = false;
+                let connected = match pinned {
+                    Some(addr) => self.connect_remote(&addr.ip().to_string(), addr.port(), conn_key).await,
+                    None => self.connect_remote(&host, port, conn_key).await,
+                };
+                match connected {
+                    Ok(stream) => {
+                        let info = self.active_connections.lock().await;
+                        let endpoint = info.get(conn_key).and_then(|info| info.endpoint.clone());
+                        *upstream = Some(HttpUpstream {
+                            host: host.clone(),
+                            port,
+                            endpoint,
+                            reader: http::Reader::new(stream),
+                        })
+                    }
+                    Err(err) => {
+                        self.handle_upstream_error(client.get_mut(), conn_key, &host, port, err)
+                            .await;
+                        return HttpOutcome::Close;
+                    }
+                }
+            }
+            let Some(dst) = upstream.as_mut() else {
+                return HttpOutcome::Close;
+            };
+            if let Some(info) = self.active_connections.lock().await.get_mut(conn_key) {
+                info.endpoint = dst.endpoint.clone();
+            }
+            match self.send_http_request(client, dst, &request, &segments, strategy.timing, conn_key).await {
+                Ok(response) => break response,
+                Err(_) if retry => {
+                    retry = false;
+                    *upstream = None;
+                }
+                Err(err) => {
+                    self.handle_upstream_error(client.get_mut(), conn_key, &host, port, err)
+                        .await;
+                    return HttpOutcome::Close;
+                }
+            }
+        };
+        let Some(dst) = upstream.as_mut() else {
+            return HttpOutcome::Close;
+        };
+
+        if response.status == 101 {
+            return self.switch_protocols(client, dst, &outgoing, response, conn_key).await;
+        }
+
+        let body = http::response_body(&request.method, &response);
+        let server_keep_alive = http::keep_alive(&response.version, &response.headers);
+        let keep_alive = http::keep_alive(&request.version, &request.headers) && body != http::Body::UntilClose;
+        let mut headers = http::end_to_end(&response.headers);
+        if !keep_alive {
+            headers.push(("Connection".to_string(), "close".to_string()));
+        } else if request.version == "HTTP/1.0" {
+            headers.push(("Connection".to_string(), "keep-alive".to_string()));
+        }
+        let head = http::Response { headers, ..response }.to_bytes();
+        let relayed = match client.get_mut().write_all(&head).await {
+            Ok(()) => dst.reader.copy_body(body, client.get_mut()).await,
+            Err(err) => Err(err),
+        };
+        let sent = head.len() as u64 + relayed.as_ref().map_or(0, |n| *n);
+        self.statistics.update_traffic(sent, 0).await;
+        self.update_conn_in(conn_key, sent).await;
+        if relayed.is_err() || !server_keep_alive || body == http::Body::UntilClose {
+            *upstream = None;
+        }
+
+        let info = self.active_connections.lock().await.remove(conn_key);
+        if let Some(info) = info {
+            self.logger.log_access(&info.access_line()).await;
+        }
+        if relayed.is_ok() && keep_alive {
+            HttpOutcome::KeepAlive
+        } else {
+            HttpOutcome::Close
+        }
+    }
+
+    /// Passes a 101 on to the client. The connection info stays registered,
+    /// as the piping that follows logs it when the connection ends.
+    async fn switch_protocols(
+        &self,
+        client: &mut http::Reader<TcpStream>,
+        dst: &HttpUpstream,
+        request: &http::Request,
+        response: http::Response,
+        conn_key: &str,
+    ) -> HttpOutcome {
+        let Some(requested) = http::upgrade(&request.headers) else {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "switching protocols without an upgrade request");
+            self.handle_upstream_error(client.get_mut(), conn_key, &dst.host, dst.port, err)
+                .await;
+            return HttpOutcome::Close;
+        };
+        let protocol = http::header(&response.headers, "upgrade").unwrap_or(requested).to_string();
+        let mut headers = http::end_to_end(&response.headers);
+        push_upgrade(&mut headers, &protocol);
+        let head = http::Response { headers, ..response }.to_bytes();
+        if client.get_mut().write_all(&head).await.is_err() {
+            self.active_connections.lock().await.remove(conn_key);
+            return HttpOutcome::Close;
+        }
+        self.statistics.update_traffic(head.len() as u64, 0).await;
+        self.update_conn_in(conn_key, head.len() as u64).await;
+        HttpOutcome::Upgraded
+    }
+
+    /// Writes the request head segments and body to `dst` and reads the
+    /// final response head. Interim 1xx responses are passed to the client.
+    /// A client waiting for 100-continue gets it right away, so its body
+    /// does not wait on a round trip to the server.
+    async fn send_http_request(
+        &self,
+        client: &mut http::Reader<TcpStream>,
+        dst: &mut HttpUpstream,
+        request: &http::Request,
+        segments: &[Vec<u8>],
+        timing: FragmentTiming,
+        conn_key: &str,
+    ) -> io::Result<http::Response> {
+        let body = http::request_body(&request.headers).map_err(io::Error::other)?;
+        let split = segments.len() > 1;
+        if split {
+            dst.reader.get_mut().set_nodelay(true)?;
+        }
+        let mut rng = fragment_rng(self.config.fragment_seed);
+        let mut sent = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            if i > 0 && !timing.is_zero() {
+                time::sleep(timing.pause(&mut rng)).await;
+            }
+            dst.reader.get_mut().write_all(segment).await?;
+            sent += segment.len() as u64;
+        }
+        if split {
+            dst.reader.get_mut().set_nodelay(false)?;
+        }
+        if body != http::Body::Empty {
+            if expects_continue(request) {
+                client.get_mut().write_all(CONTINUE_RESPONSE).await?;
+            }
+            sent += client.copy_body(body, dst.reader.get_mut()).await?;
+        }
+        self.statistics.update_traffic(0, sent).await;
+        self.update_conn_out(conn_key, sent).await;
+
+        loop {
+            let head = dst
+                .reader
+                .read_head()
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"))?;
+            let response = http::parse_response(&head).map_err(io::Error::other)?;
+            if !(100..200).contains(&response.status) || response.status == 101 {
+                return Ok(response);
+            }
+            client.get_mut().write_all(&head).await?;
+            self.statistics.update_traffic(head.len() as u64, 0).await;
+            self.update_conn_in(conn_key, head.len() as u64).await;
+        }
+    }
+
+    /// Answers a request that cannot be served with `response` and logs why.
+    async fn handle_connection_error(&self, writer: &mut TcpStream, conn_key: &str, response: &[u8], err: String) {
+        let _ = writer.write_all(response).await;
+        self.statistics.update_traffic(response.len() as u64, 0).await;
+        self.statistics.increment_total_connections().await;
+        self.statistics.increment_error_connections().await;
+        self.logger.log_error(&err).await;
+
+        let info = {
+            let mut map = self.active_connections.lock().await;
+            map.remove(conn_key)
+        };
+        if let Some(info) = info {
+            self.logger.log_access(&info.access_line()).await;
+        }
+    }
+
+    /// Answers a failed upstream connect with a 502 or 504 whose body says
+    /// what went wrong.
+    async fn handle_upstream_error(&self, writer: &mut TcpStream, conn_key: &str, host: &str, port: u16, err: io::Error) {
+        let response = gateway_error_response(host, port, &err);
+        let _ = writer.write_all(&response).await;
+        self.statistics.update_traffic(response.len() as u64, 0).await;
+        self.update_conn_in(conn_key, response.len() as u64).await;
+        self.statistics.increment_total_connections().await;
+        self.statistics.increment_error_connections().await;
+        self.logger.log_error(&format!("{}: {}", host, err)).await;
+
+        let info = self.active_connections.lock().await.remove(conn_key);
+        if let Some(info) = info {
+            self.logger.log_access(&info.access_line()).await;
+        }
+    }
+
+    async fn handle_auth_required(&self, writer: &mut TcpStream) {
+        let response = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"NoDPI\"\r\nContent-Length: 0\r\n\r\n";
+        let _ = writer.write_all(response).await;
+        self.statistics.update_traffic(response.len() as u64, 0).await;
+        self.statistics.increment_total_connections().await;
+        self.statistics.increment_error_connections().await;
+        self.logger.log_error("Proxy authentication required").await;
+    }
+}
+
+fn expects_continue(request: &http::Request) -> bool {
+    request.version == "HTTP/1.1" && http::has_token(&request.headers, "expect", "100-continue")
+}
+
+fn push_upgrade(headers: &mut http::Headers, protocol: &str) {
+    headers.push(("Connection".to_string(), "Upgrade".to_string()));
+    headers.push(("Upgrade".to_string(), protocol.to_string()));
+}
+
+/// Rewrites a proxy request for the origin server: the target becomes
+/// origin-form, Host carries the target's authority, and hop-by-hop and
+/// proxy headers are dropped. Also returns how the request body is framed.
+fn origin_form_request(request: &http::Request) -> Result<(http::Request, http::Body), String> {
+    let body = http::request_body(&request.headers)?;
+    let (authority, target) = match http::split_absolute(&request.target)? {
+        Some(v) => v,
+        None => {
+            let host = http::header(&request.headers, "host").ok_or("Missing Host header")?;
+            (host.to_string(), request.target.clone())
+        }
+    };
+    if parse_host_port(&authority, 80).0.is_empty() {
+        return Err("Invalid Host header".to_string());
+    }
+
+    let mut headers = http::end_to_end(&request.headers);
+    // The proxy answers 100-continue itself, see send_http_request.
+    headers.retain(|(k, v)| !(k.eq_ignore_ascii_case("expect") && v.eq_ignore_ascii_case("100-continue")));
+    // Upgrade is hop-by-hop, but the proxy takes part in the switch, so the
+    // request is passed on for the server to accept or ignore.
+    if request.version == "HTTP/1.1" {
+        if let Some(protocol) = http::upgrade(&request.headers) {
+            push_upgrade(&mut headers, protocol);
+        }
+    }
+    match headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case("host")) {
+        Some((_, value)) => *value = authority,
+        None => headers.insert(0, ("Host".to_string(), authority)),
+    }
+    Ok((
+        http::Request {
+            method: request.method.clone(),
+            target,
+            version: request.version.clone(),
+            headers,
+        },
+        body,
+    ))
+}
+
+/// Rewrites the head of a plain HTTP request according to `evasion` and cuts
+/// it into chunks that are meant to be written as separate TCP segments.
+fn apply_http_evasion(data: &[u8], evasion: &HttpEvasion) -> Vec<Vec<u8>> {
+    let head_end = find_subslice(data, b"\r\n\r\n").map(|p| p + 2).unwrap_or(data.len());
+    let mut out = Vec::with_capacity(data.len() + 4);
+    let mut cuts = Vec::new();
+
+    let mut line_start = 0;
+    let mut first = true;
+    while line_start < head_end {
+        let line_end = find_subslice(&data[line_start..head_end], b"\r\n")
+            .map(|p| line_start + p + 2)
+            .unwrap_or(head_end);
+        let line = &data[line_start..line_end];
+        if first {
+            cuts.push(out.len() + line.len() / 2);
+            out.extend_from_slice(line);
+        } else if line.len() > 5 && line[..5].eq_ignore_ascii_case(b"host:") {
+            out.extend_from_slice(if evasion.host_case { b"hoSt:" } else { &line[..5] });
+            let value = String::from_utf8_lossy(&line[5..]);
+            let value = value.trim();
+            if evasion.host_space {
+                out.extend_from_slice(b"  ");
+            } else if !evasion.host_nospace {
+                out.push(b' ');
+            }
+            let host = rewrite_http_host(value, evasion);
+            cuts.push(out.len() + host.len() / 2);
+            out.extend_from_slice(host.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        } else {
+            out.extend_from_slice(line);
+        }
+        first = false;
+        line_start = line_end;
+    }
+    out.extend_from_slice(&data[head_end..]);
+
+    if !evasion.split {
+        return vec![out];
+    }
+    let mut segments = Vec::with_capacity(cuts.len() + 1);
+    let mut prev = 0;
+    for cut in cuts.into_iter().chain(std::iter::once(out.len())) {
+        if cut > prev {
+            segments.push(out[prev..cut].to_vec());
+            prev = cut;
+        }
+    }
+    segments
+}
+
+fn rewrite_http_host(value: &str, evasion: &HttpEvasion) -> String {
+    // Leave IP literals alone, neither trick means anything for them.
+    let (host, port) = match value.rsplit_once(':') {
+        Some((h, p)) if !h.contains(':') && p.parse::<u16>().is_ok() => (h, Some(p)),
+        _ => (value, None),
+    };
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return value.to_string();
+    }
+    let mut host: String = if evasion.domain_case {
+        host.chars()
+            .enumerate()
+            .map(|(i, c)| if i % 2 == 0 { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+            .collect()
+    } else {
+        host.to_string()
+    };
+    if evasion.host_dot && !host.ends_with('.') {
+        host.push('.');
+    }
+    match port {
+        Some(p) => format!("{}:{}", host, p),
+        None => host,
+    }
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+pub fn parse_http_evasion(value: &str) -> Result<HttpEvasion, String> {
+    let mut evasion = HttpEvasion::default();
+    for part in value.split(',') {
+        match part.trim() {
+            "none" => evasion = HttpEvasion::default(),
+            "split" => evasion.split = true,
+            "host-case" => evasion.host_case = true,
+            "host-nospace" => evasion.host_nospace = true,
+            "host-space" => evasion.host_space = true,
+            "host-dot" => evasion.host_dot = true,
+            "domain-case" => evasion.domain_case = true,
+            other => return Err(format!("error: unknown --http-evasion trick '{}'", other)),
+        }
+    }
+    Ok(evasion)
+}
+
+fn is_auth_ok(config: &Config, headers: &[(String, String)]) -> bool {
+    if !is_auth_enabled(config) {
+        return true;
+    }
+    let Some(value) = http::header(headers, "proxy-authorization") else {
+        return false;
+    };
+    let value = value.trim();
+    let basic_prefix = "basic ";
+    if !value.to_ascii_lowercase().starts_with(basic_prefix) {
+        return false;
+    }
+    let b64 = value[basic_prefix.len()..].trim();
+    let decoded = STANDARD.decode(b64).ok();
+    let Some(decoded) = decoded else {
+        return false;
+    };
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut it = decoded.splitn(2, ':');
+    let Some(u) = it.next() else {
+        return false;
+    };
+    let Some(p) = it.next() else {
+        return false;
+    };
+    check_credentials(config, u, p)
+}
+
+/// The response to a client whose request head could not be read.
+fn error_response(err: &io::Error) -> &'static [u8] {
+    match err.kind() {
+        io::ErrorKind::InvalidData => BAD_REQUEST_RESPONSE,
+        io::ErrorKind::TimedOut => REQUEST_TIMEOUT_RESPONSE,
+        _ => SERVER_ERROR_RESPONSE,
+    }
+}
+
+/// Builds the response for a CONNECT or plain request whose upstream could
+/// not be reached: 403 when it is not allowed, 504 for a timeout, 502
+/// otherwise.
+fn gateway_error_response(host: &str, port: u16, err: &io::Error) -> Vec<u8> {
+    let (status, reason) = match err.kind() {
+        io::ErrorKind::PermissionDenied => ("403 Forbidden", "not allowed"),
+        io::ErrorKind::TimedOut => ("504 Gateway Timeout", "connection timed out"),
+        io::ErrorKind::NotFound => ("502 Bad Gateway", "DNS lookup failed"),
+        io::ErrorKind::ConnectionRefused => ("502 Bad Gateway", "connection refused"),
+        io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => {
+            ("502 Bad Gateway", "host unreachable")
+        }
+        _ => ("502 Bad Gateway", "connection failed"),
+    };
+    let body = format!("NoDPI: cannot connect to {}:{}: {}\r\n{}\r\n", host, port, reason, err);
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET /path HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\nbody";
+
+    fn evade(request: &[u8], tricks: &str) -> Vec<Vec<u8>> {
+        apply_http_evasion(request, &parse_http_evasion(tricks).unwrap())
+    }
+
+    fn rewritten_head(request: &[u8], tricks: &str) -> String {
+        let segments = evade(request, tricks);
+        assert_eq!(segments.len(), 1);
+        String::from_utf8(segments[0].clone()).unwrap()
+    }
+
+    #[test]
+    fn rewrites_the_host_header_for_each_trick() {
+        let tail = "\r\nAccept: */*\r\n\r\nbody";
+        for (tricks, host_line) in [
+            ("none", "Host: example.com"),
+            ("host-case", "hoSt: example.com"),
+            ("host-nospace", "Host:example.com"),
+            ("host-space", "Host:  example.com"),
+            ("host-dot", "Host: example.com."),
+            ("domain-case", "Host: ExAmPlE.CoM"),
+            ("host-case,host-nospace,host-dot,domain-case", "hoSt:ExAmPlE.CoM."),
+        ] {
+            assert_eq!(
+                rewritten_head(REQUEST, tricks),
+                format!("GET /path HTTP/1.1\r\n{}{}", host_line, tail),
+                "{}",
+                tricks
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_ports_and_leaves_ip_literals_alone() {
+        let request = b"GET / HTTP/1.1\r\nhost: example.com:8080\r\n\r\n";
+        assert_eq!(
+            rewritten_head(request, "host-dot,domain-case"),
+            "GET / HTTP/1.1\r\nhost: ExAmPlE.CoM.:8080\r\n\r\n"
+        );
+        for host in ["192.0.2.1", "192.0.2.1:8080", "[2001:db8::1]:8080"] {
+            let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            assert_eq!(rewritten_head(request.as_bytes(), "host-dot,domain-case"), request);
+        }
+    }
+
+    #[test]
+    fn splits_inside_the_request_line_and_the_host_name() {
+        let segments = evade(REQUEST, "split");
+        assert_eq!(
+            segments,
+            [
+                b"GET /path ".to_vec(),
+                b"HTTP/1.1\r\nHost: examp".to_vec(),
+                b"le.com\r\nAccept: */*\r\n\r\nbody".to_vec(),
+            ]
+        );
+
+        // The host is cut where it is after the other tricks rewrote it.
+        let segments = evade(REQUEST, "split,host-nospace,host-dot");
+        assert_eq!(segments[1], b"HTTP/1.1\r\nHost:exampl");
+        assert_eq!(segments[2], b"e.com.\r\nAccept: */*\r\n\r\nbody");
+
+        // Without a Host header only the request line is cut.
+        let segments = evade(b"GET / HTTP/1.1\r\n\r\n", "split");
+        assert_eq!(segments, [b"GET / HT".to_vec(), b"TP/1.1\r\n\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn parses_http_evasion_lists() {
+        assert!(parse_http_evasion("none").unwrap().is_empty());
+        assert!(parse_http_evasion("split, host-case").unwrap().split);
+        assert!(parse_http_evasion("host-case,none").unwrap().is_empty());
+        assert_eq!(
+            parse_http_evasion("host-case,bogus").err(),
+            Some("error: unknown --http-evasion trick 'bogus'".to_string())
+        );
+    }
+
+    #[test]
+    fn upstream_failures_map_to_gateway_statuses() {
+        let cases = [
+            (io::ErrorKind::PermissionDenied, "403 Forbidden", "not allowed"),
+            (io::ErrorKind::TimedOut, "504 Gateway Timeout", "connection timed out"),
+            (io::ErrorKind::NotFound, "502 Bad Gateway", "DNS lookup failed"),
+            (io::ErrorKind::ConnectionRefused, "502 Bad Gateway", "connection refused"),
+            (io::ErrorKind::Other, "502 Bad Gateway", "connection failed"),
+        ];
+        for (kind, status, reason) in cases {
+            let response = gateway_error_response("example.com", 443, &io::Error::new(kind, "boom"));
+            let response = String::from_utf8(response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", head);
+            assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())), "{}", head);
+            assert_eq!(body, format!("NoDPI: cannot connect to example.com:443: {}\r\nboom\r\n", reason));
+        }
+    }
+}
@@ -1,17 +1,17 @@
 use chrono::Local;
-use rand::Rng;
 use reqwest::Client;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write as IoWrite};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 #[cfg(any(windows, target_os = "linux"))]
 use std::process::Command;
 use std::sync::Arc;
+use std::task::Poll;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::{Mutex, Notify};
 use tokio::time::{self, Duration, Instant};
@@ -21,18 +21,56 @@ use winreg::enums::*;
 #[cfg(windows)]
 use winreg::RegKey;
 
-use base64::{engine::general_purpose::STANDARD, Engine as _};
 use sha2::{Digest, Sha256};
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod desync;
+mod dns;
+mod explain;
+mod forwarder;
+mod fragment;
+mod http;
+mod http_proxy;
+mod quic;
+mod routing;
+mod socks;
+mod socks_proxy;
+mod strategy;
+mod tls;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod transparent;
+mod upstream;
+
+use fragment::read_initial_tls_data;
+use http_proxy::{parse_http_evasion, HttpEvasion, HttpUpstream};
+use strategy::{
+    find_domain_rule, fragment_method_name, load_strategy_rules, parse_count, parse_fragment_method,
+    parse_record_version, parse_split_positions, parse_strategy_rule, parse_timing_value, rule_domain, FakeTtl,
+    FragmentMethod, Strategy, StrategyCache, StrategyRule,
+};
+
 const VERSION: &str = "2.1";
 const UPDATE_URL: &str = "https://gvcoder09.github.io/nodpi_site/api/v1/update_info.json";
-
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum FragmentMethod {
-    Random,
-    Sni,
-}
-
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client may take to send a whole request head, counted from
+/// the end of the previous exchange on a kept-alive connection.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a client sending a request body may stay silent.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Limit for each connect attempt to an upstream address.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+const DEFAULT_FAKE_TTL: u8 = 8;
+const DEFAULT_FAKE_SNI: &str = "www.iana.org";
+const DEFAULT_STRATEGY_CACHE: &str = "strategy_cache.txt";
+const DEFAULT_STRATEGY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Host names the DNS cache holds at most, unless --dns-cache-size says
+/// otherwise. The system resolver has a cache of its own and tells no TTL,
+/// so with `--dns system` the proxy only caches when --dns-cache-size asks.
+const DEFAULT_DNS_CACHE_SIZE: usize = 1024;
+/// How long a name that does not exist stays cached.
+const DEFAULT_DNS_NEGATIVE_TTL: Duration = Duration::from_secs(30);
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum DomainMatching {
     Strict,
@@ -44,11 +82,47 @@ struct Config {
     host: String,
     port: u16,
     out_host: Option<String>,
+    /// Resolves the hosts outbound connections go to.
+    resolver: dns::Resolver,
+    hosts_file: Option<String>,
+    /// Address of the DNS forwarder for LAN clients, if enabled.
+    dns_listen: Option<SocketAddr>,
+    /// Addresses the forwarder answers with instead of asking the server.
+    dns_overrides: HashMap<String, Vec<IpAddr>>,
+    /// Networks the forwarder answers; empty for the local networks.
+    dns_allow: Vec<routing::Cidr>,
+    /// Upstream that outbound TCP connections are opened through, if any.
+    upstream: Option<upstream::Pool>,
+    /// Upstreams that routes refer to by name.
+    upstreams: HashMap<String, upstream::Pool>,
+    routes_file: Option<String>,
+    /// Port of the transparent listener for connections redirected by the
+    /// firewall, if enabled.
+    transparent_port: Option<u16>,
+    /// Addresses the transparent listener binds; all IPv4 and IPv6
+    /// interfaces when empty.
+    transparent_hosts: Vec<IpAddr>,
+    /// The firewall uses TPROXY rather than REDIRECT.
+    tproxy: bool,
     blacklist_file: String,
-    fragment_method: FragmentMethod,
+    strategy: Strategy,
+    fragment_seed: Option<u64>,
+    /// Per-domain overrides given on the command line; they take precedence
+    /// over the rules file and the rules inside the blacklist. Rules only
+    /// tune how domains the blacklist targets are handled.
+    strategy_rules: HashMap<String, StrategyRule>,
+    rules_file: Option<String>,
+    fake_ttl: FakeTtl,
+    fake_sni: String,
+    http_evasion: HttpEvasion,
+    auto_strategy: bool,
+    strategy_cache_file: String,
+    strategy_ttl: Duration,
     domain_matching: DomainMatching,
     log_access_file: Option<String>,
     log_error_file: Option<String>,
+    /// Where notes that are not errors go, such as retried handshakes.
+    log_info_file: Option<String>,
     no_blacklist: bool,
     auto_blacklist: bool,
     quiet: bool,
@@ -61,6 +135,8 @@ struct Config {
 
 struct Args {
     config: Config,
+    /// ClientHello file or hex string given to the `explain` subcommand.
+    explain: Option<String>,
     install: bool,
     uninstall: bool,
     add_user: Option<String>,
@@ -70,12 +146,18 @@ struct Args {
 struct Logger {
     error: Option<Mutex<File>>,
     access: Option<Mutex<File>>,
+    info: Option<Mutex<File>>,
     quiet: bool,
     error_counter: Mutex<Option<Arc<dyn Fn() + Send + Sync>>>,
 }
 
 impl Logger {
-    fn new(log_access: &Option<String>, log_error: &Option<String>, quiet: bool) -> io::Result<Self> {
+    fn new(
+        log_access: &Option<String>,
+        log_error: &Option<String>,
+        log_info: &Option<String>,
+        quiet: bool,
+    ) -> io::Result<Self> {
         let error = if let Some(path) = log_error {
             Some(Mutex::new(open_append(path)?))
         } else {
@@ -88,9 +170,16 @@ impl Logger {
             None
         };
 
+        let info = if let Some(path) = log_info {
+            Some(Mutex::new(open_append(path)?))
+        } else {
+            None
+        };
+
         Ok(Self {
             error,
             access,
+            info,
             quiet,
             error_counter: Mutex::new(None),
         })
@@ -113,6 +202,14 @@ impl Logger {
         }
     }
 
+    async fn log_info(&self, message: &str) {
+        if let Some(file) = &self.info {
+            let mut f = file.lock().await;
+            let ts = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+            let _ = writeln!(f, "[{}][INFO]: {}", ts, message);
+        }
+    }
+
     async fn log_access(&self, line: &str) {
         if let Some(file) = &self.access {
             let mut f = file.lock().await;
@@ -135,6 +232,29 @@ struct ConnectionInfo {
     start_time: String,
     traffic_in: u64,
     traffic_out: u64,
+    /// The upstream endpoint that served the connection, if any.
+    endpoint: Option<upstream::Endpoint>,
+}
+
+impl ConnectionInfo {
+    /// The access log line. The last column is the upstream endpoint, or
+    /// `-` for a connection made without one.
+    fn access_line(&self) -> String {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.to_string(),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} {} {} {} {} {} {}",
+            self.start_time,
+            self.src_ip,
+            self.method,
+            self.dst_domain,
+            self.traffic_in,
+            self.traffic_out,
+            endpoint
+        )
+    }
 }
 
 struct StatisticsState {
@@ -155,11 +275,14 @@ struct StatisticsState {
 
 struct Statistics {
     inner: Mutex<StatisticsState>,
+    /// The DNS cache, whose hits and misses are shown with the rest.
+    dns_cache: Option<Arc<dns::Cache>>,
 }
 
 impl Statistics {
-    fn new() -> Self {
+    fn new(dns_cache: Option<Arc<dns::Cache>>) -> Self {
         Self {
+            dns_cache,
             inner: Mutex::new(StatisticsState {
                 total_connections: 0,
                 allowed_connections: 0,
@@ -319,6 +442,28 @@ impl Statistics {
             .pad_ansi(col_width)
             .as_str();
 
+        let dns_stat = self.dns_cache.as_ref().map(|cache| {
+            let (hits, misses) = (cache.hits(), cache.misses());
+            let rate = if hits + misses > 0 {
+                hits as f64 * 100.0 / (hits + misses) as f64
+            } else {
+                0.0
+            };
+            format!("\x1b[97mHits: \x1b[92m{}\x1b[0m", hits).pad_ansi(col_width)
+                + "\x1b[97m| "
+                + format!("\x1b[97mMisses: \x1b[93m{}\x1b[0m", misses)
+                    .pad_ansi(col_width)
+                    .as_str()
+                + "\x1b[97m| "
+                + format!("\x1b[97mRate: \x1b[96m{:.0}%\x1b[0m", rate)
+                    .pad_ansi(col_width)
+                    .as_str()
+                + "\x1b[97m| "
+                + format!("\x1b[97mCached: \x1b[96m{}\x1b[0m", cache.len())
+                    .pad_ansi(col_width)
+                    .as_str()
+        });
+
         let title = "STATISTICS";
         let top_border = format!("\x1b[92m{} {} {}\x1b[0m", "═".repeat(36), title, "═".repeat(36));
         let line_conns = format!("\x1b[92m   {:<8}:\x1b[0m {}\x1b[0m", "Conns", conns_stat);
@@ -326,11 +471,18 @@ impl Statistics {
         let line_speed = format!("\x1b[92m   {:<8}:\x1b[0m {}\x1b[0m", "Speed", speed_stat);
         let bottom_border = format!("\x1b[92m{}\x1b[0m", "═".repeat(36 * 2 + title.len() + 2));
 
-        format!("{}\n{}\n{}\n{}\n{}", top_border, line_conns, line_traffic, line_speed, bottom_border)
+        let line_dns = dns_stat
+            .map(|stat| format!("\x1b[92m   {:<8}:\x1b[0m {}\x1b[0m\n", "DNS", stat))
+            .unwrap_or_default();
+
+        format!(
+            "{}\n{}\n{}\n{}\n{}{}",
+            top_border, line_conns, line_traffic, line_speed, line_dns, bottom_border
+        )
     }
 }
 
-enum BlacklistManager {
+enum BlacklistMode {
     None,
     File {
         blocked: Vec<String>,
@@ -340,30 +492,97 @@ enum BlacklistManager {
         blocked: Mutex<Vec<String>>,
         whitelist: Mutex<Vec<String>>,
         blacklist_file: String,
+        /// Resolves the domains the probe requests go to.
+        resolver: dns::Resolver,
     },
 }
 
+struct BlacklistManager {
+    mode: BlacklistMode,
+    routes: routing::Table,
+    rules: HashMap<String, StrategyRule>,
+    /// How rules are matched to domains, as for the blacklist file.
+    domain_matching: DomainMatching,
+    default_strategy: Strategy,
+    discovered: Option<StrategyCache>,
+}
+
 impl BlacklistManager {
     async fn is_blocked(&self, domain: &str) -> bool {
-        match self {
-            BlacklistManager::None => true,
-            BlacklistManager::File {
+        match &self.mode {
+            BlacklistMode::None => true,
+            BlacklistMode::File {
                 blocked,
                 domain_matching,
             } => is_domain_blocked(blocked, *domain_matching, domain),
-            BlacklistManager::Auto { blocked, .. } => {
+            BlacklistMode::Auto { blocked, .. } => {
                 let guard = blocked.lock().await;
                 guard.contains(&domain.to_string())
             }
         }
     }
 
+    /// Resolves how the first flight to `target` should be mangled. Whether
+    /// it is targeted at all is up to the matching route, or to the
+    /// blacklist when no route matches or the route only picks an upstream.
+    /// How a targeted connection is mangled is up to `tuned_strategy`.
+    async fn strategy_for(&self, target: &routing::Target<'_>) -> Strategy {
+        match self.routes.lookup(target) {
+            Some(routing::Action::Direct | routing::Action::Reject) => Strategy::direct(),
+            Some(routing::Action::Fragment(rule)) => self.tuned_strategy(target.domain, Some(rule)).await,
+            Some(routing::Action::Via(_)) | None => {
+                if !self.is_blocked(target.domain).await {
+                    return Strategy::direct();
+                }
+                self.tuned_strategy(target.domain, None).await
+            }
+        }
+    }
+
+    /// The global strategy with the method discovered for `domain`, if any,
+    /// overridden by the rule for the domain and then by the route's.
+    async fn tuned_strategy(&self, domain: &str, route: Option<&StrategyRule>) -> Strategy {
+        let mut strategy = self.default_strategy.clone();
+        if let Some(cache) = &self.discovered {
+            if let Some(method) = cache.get(domain).await {
+                strategy.method = method;
+            }
+        }
+        let mut rule = route.cloned().unwrap_or_default();
+        if let Some(domain_rule) = self.find_rule(domain) {
+            rule.merge(domain_rule);
+        }
+        rule.apply(&strategy)
+    }
+
+    fn find_rule(&self, domain: &str) -> Option<&StrategyRule> {
+        find_domain_rule(&self.rules, self.domain_matching, domain)
+    }
+
+    /// Tells whether the strategy for `target` should be probed:
+    /// discovery is enabled, the blacklist decides for the domain, it is
+    /// blocked, no rule sets its method and the cache has no fresh entry
+    /// for it.
+    async fn needs_discovery(&self, target: &routing::Target<'_>) -> bool {
+        let Some(cache) = &self.discovered else {
+            return false;
+        };
+        if !matches!(self.routes.lookup(target), Some(routing::Action::Via(_)) | None) {
+            return false;
+        }
+        let domain = target.domain;
+        self.find_rule(domain).and_then(|rule| rule.method).is_none()
+            && self.is_blocked(domain).await
+            && cache.is_due(domain).await
+    }
+
     async fn check_domain(&self, domain: &str) {
-        if let BlacklistManager::Auto {
+        if let BlacklistMode::Auto {
             blocked,
             whitelist,
             blacklist_file,
-        } = self
+            resolver,
+        } = &self.mode
         {
             {
                 let b = blocked.lock().await;
@@ -378,11 +597,16 @@ impl BlacklistManager {
                 }
             }
 
-        let client = match Client::builder()
+            let mut builder = Client::builder()
                 .timeout(Duration::from_secs(4))
-                .user_agent("Mozilla/5.0")
-                .build()
-            {
+                .user_agent("Mozilla/5.0");
+            if !resolver.is_system() {
+                match resolver.lookup(domain, 443).await {
+                    Ok(addrs) => builder = builder.resolve_to_addrs(domain, &addrs),
+                    Err(_) => return,
+                }
+            }
+            let client = match builder.build() {
                 Ok(c) => c,
                 Err(_) => return,
             };
@@ -415,6 +639,9 @@ struct ConnectionHandler {
     logger: Arc<Logger>,
     active_connections: Arc<Mutex<HashMap<String, ConnectionInfo>>>,
     tasks: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    /// Hop distances by server address. `None` while the probe runs or
+    /// after it failed, when the default TTL is used.
+    hop_distances: Arc<Mutex<HashMap<IpAddr, Option<u8>>>>,
 }
 
 impl ConnectionHandler {
@@ -431,177 +658,216 @@ impl ConnectionHandler {
             logger,
             active_connections: Arc::new(Mutex::new(HashMap::new())),
             tasks: Mutex::new(Vec::new()),
+            hop_distances: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    async fn handle_connection(&self, mut client: TcpStream) {
+    async fn handle_connection(self: &Arc<Self>, client: TcpStream) {
         let peer = client
             .peer_addr()
             .map(|addr| addr.to_string())
             .unwrap_or_else(|_| "unknown".to_string());
 
-        let mut buf = vec![0u8; 1500];
-        let n = match client.read(&mut buf).await {
-            Ok(n) => n,
-            Err(_) => return,
-        };
-        if n == 0 {
-            let _ = client.shutdown().await;
+        let mut first = [0u8; 1];
+        let Ok(peeked) = time::timeout(REQUEST_HEAD_TIMEOUT, client.peek(&mut first)).await else {
             return;
-        }
-
-        let http_data = buf[..n].to_vec();
-        let (method, host, port, headers) = match parse_http_request(&http_data) {
-            Ok(v) => v,
-            Err(err) => {
-                self.handle_connection_error(&mut client, &peer, err).await;
-                return;
-            }
         };
-
-        if !is_auth_ok(&self.config, &headers) {
-            self.handle_auth_required(&mut client).await;
-            return;
-        }
-
-        if method == "CONNECT" {
-            self.blacklist_manager.check_domain(&host).await;
+        if matches!(peeked, Ok(1)) {
+            match first[0] {
+                socks::VERSION => return self.handle_socks5_connection(client, &peer).await,
+                socks::VERSION_4 => return self.handle_socks4_connection(client, &peer).await,
+                _ => {}
+            }
         }
 
-        let conn_info = ConnectionInfo {
-            src_ip: peer.clone(),
-            dst_domain: host.clone(),
-            method: method.clone(),
-            start_time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-            traffic_in: 0,
-            traffic_out: 0,
+        let mut reader = http::Reader::new(client).with_idle_timeout(CLIENT_IDLE_TIMEOUT);
+        let Some(request) = self.read_http_request(&mut reader, &peer).await else {
+            return;
         };
-        {
-            let mut map = self.active_connections.lock().await;
-            map.insert(peer.clone(), conn_info);
-        }
-
-        let _ = self.statistics.update_traffic(0, http_data.len() as u64).await;
-        self.update_conn_out(&peer, http_data.len() as u64).await;
-
-        if method == "CONNECT" {
-            self.handle_https_connection(client, host, port, &peer).await;
+        if request.method == "CONNECT" {
+            self.handle_connect_request(reader, request, &peer).await;
         } else {
-            self.handle_http_connection(client, http_data, host, port, &peer).await;
+            self.handle_http_connection(reader, request, None, None, &peer).await;
         }
     }
 
-    async fn handle_https_connection(
-        &self,
-        mut client: TcpStream,
-        host: String,
+    /// Runs an established tunnel: a TLS ClientHello from the client goes
+    /// through the blacklist and fragmentation path, anything else (or a
+    /// server that speaks first) is piped as is. `early` holds bytes the
+    /// client sent before the tunnel was up; they open its first flight.
+    async fn handle_tunnel(
+        self: &Arc<Self>,
+        client: TcpStream,
+        early: Vec<u8>,
+        dst: TcpStream,
+        host: &str,
         port: u16,
         conn_key: &str,
     ) {
-        let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
-        if client.write_all(response).await.is_err() {
+        let tls = match early.first() {
+            Some(&first) => first == tls::CONTENT_TYPE_HANDSHAKE,
+            None => client_starts_with_tls(&client, &dst).await,
+        };
+        let (mut client_reader, mut client_writer) = client.into_split();
+        if !tls {
+            self.statistics.increment_total_connections().await;
+            self.statistics.increment_allowed_connections().await;
+            let (dst_reader, mut dst_writer) = dst.into_split();
+            if self.forward_early(&mut dst_writer, &early, conn_key).await {
+                self.setup_piping(client_reader, client_writer, dst_reader, dst_writer, conn_key)
+                    .await;
+            }
             return;
         }
-        let _ = self.statistics.update_traffic(response.len() as u64, 0).await;
-        self.update_conn_in(conn_key, response.len() as u64).await;
 
-        let dst = match connect_with_out_host(&host, port, &self.config.out_host).await {
-            Ok(s) => s,
+        let mut raw = Vec::new();
+        let mut first_flight = early.as_slice().chain(&mut client_reader);
+        let read = read_initial_tls_data(&mut first_flight, &mut raw).await;
+        let rest = first_flight.into_inner().0;
+        let initial = match read {
+            Ok(initial) => initial,
             Err(err) => {
+                // Whatever the client sent instead goes to the server as is.
                 let _ = self.logger.log_error(&format!("{}: {}", host, err)).await;
+                self.statistics.increment_total_connections().await;
+                self.statistics.increment_allowed_connections().await;
+                raw.extend_from_slice(rest);
+                let (dst_reader, mut dst_writer) = dst.into_split();
+                if self.forward_early(&mut dst_writer, &raw, conn_key).await {
+                    self.setup_piping(client_reader, client_writer, dst_reader, dst_writer, conn_key)
+                        .await;
+                }
                 return;
             }
         };
 
-        let (mut client_reader, client_writer) = client.into_split();
-        let (dst_reader, mut dst_writer) = dst.into_split();
-
-        if let Err(err) = self
-            .handle_initial_tls_data(&mut client_reader, &mut dst_writer, &host, conn_key)
-            .await
-        {
-            let _ = self.logger.log_error(&format!("{}: {}", host, err)).await;
-        }
-
-        self.setup_piping(client_reader, client_writer, dst_reader, dst_writer, conn_key)
-            .await;
-    }
-
-    async fn handle_http_connection(
-        &self,
-        mut client: TcpStream,
-        http_data: Vec<u8>,
-        host: String,
-        port: u16,
-        conn_key: &str,
-    ) {
-        let mut dst = match connect_with_out_host(&host, port, &self.config.out_host).await {
-            Ok(s) => s,
+        let mut upstream = match self.handle_initial_tls_data(&initial, dst, host, port, conn_key).await {
+            Ok(upstream) => upstream,
             Err(err) => {
                 let _ = self.logger.log_error(&format!("{}: {}", host, err)).await;
-                let _ = client
-                    .write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")
-                    .await;
+                let info = self.active_connections.lock().await.remove(conn_key);
+                if let Some(info) = info {
+                    self.logger.log_access(&info.access_line()).await;
+                }
                 return;
             }
         };
-
-        if dst.write_all(&http_data).await.is_err() {
+        if !self.forward_early(&mut upstream.writer, rest, conn_key).await {
             return;
         }
 
-        self.statistics.increment_total_connections().await;
-        self.statistics.increment_allowed_connections().await;
+        if !upstream.response.is_empty() {
+            if client_writer.write_all(&upstream.response).await.is_err() {
+                self.active_connections.lock().await.remove(conn_key);
+                return;
+            }
+            self.statistics.update_traffic(upstream.response.len() as u64, 0).await;
+            self.update_conn_in(conn_key, upstream.response.len() as u64).await;
+        }
 
-        let (client_reader, client_writer) = client.into_split();
-        let (dst_reader, dst_writer) = dst.into_split();
-        self.setup_piping(client_reader, client_writer, dst_reader, dst_writer, conn_key)
+        self.setup_piping(client_reader, client_writer, upstream.reader, upstream.writer, conn_key)
             .await;
     }
 
-    async fn handle_initial_tls_data(
-        &self,
-        reader: &mut OwnedReadHalf,
-        writer: &mut OwnedWriteHalf,
-        host: &str,
-        conn_key: &str,
-    ) -> io::Result<()> {
-        let mut head = [0u8; 5];
-        reader.read_exact(&mut head).await?;
-
-        let mut data = vec![0u8; 2048];
-        let n = reader.read(&mut data).await?;
-        data.truncate(n);
+    /// Sends bytes the client sent ahead of the tunnel on to the server.
+    /// Returns false, with the connection forgotten, if the server is gone.
+    async fn forward_early(&self, writer: &mut OwnedWriteHalf, data: &[u8], conn_key: &str) -> bool {
+        if data.is_empty() {
+            return true;
+        }
+        if writer.write_all(data).await.is_err() {
+            self.active_connections.lock().await.remove(conn_key);
+            return false;
+        }
+        self.statistics.update_traffic(0, data.len() as u64).await;
+        self.update_conn_out(conn_key, data.len() as u64).await;
+        true
+    }
 
-        let mut should_fragment = true;
-        if !matches!(&*self.blacklist_manager, BlacklistManager::None) {
-            should_fragment = self.blacklist_manager.is_blocked(host).await;
+    /// Serves a connection the firewall redirected to the transparent
+    /// listener. The upstream is the original destination; the domain used
+    /// for the blacklist comes from the SNI or Host the client sends first.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn handle_transparent_connection(self: &Arc<Self>, client: TcpStream) {
+        let peer = client
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let target = match transparent::original_destination(&client, self.config.tproxy) {
+            Ok(target) => target,
+            Err(err) => {
+                let _ = self
+                    .logger
+                    .log_error(&format!("{}: no original destination: {}", peer, err))
+                    .await;
+                return;
+            }
+        };
+        if Some(target.port()) == self.config.transparent_port && transparent::is_local(target.ip()) {
+            let _ = self
+                .logger
+                .log_error(&format!("{}: not a redirected connection", peer))
+                .await;
+            return;
         }
 
-        if !should_fragment {
-            self.statistics.increment_total_connections().await;
-            self.statistics.increment_allowed_connections().await;
-            let combined = [head.to_vec(), data.clone()].concat();
-            writer.write_all(&combined).await?;
-            self.statistics.update_traffic(0, combined.len() as u64).await;
-            self.update_conn_out(conn_key, combined.len() as u64).await;
-            return Ok(());
+        let ip = target.ip().to_string();
+        let (dst, endpoint) = match self.connect_route(&ip, target.port(), &peer).await {
+            Ok(v) => v,
+            Err(err) => {
+                let _ = self.logger.log_error(&format!("{}: {}", target, err)).await;
+                return;
+            }
+        };
+        let domain = tokio::select! {
+            biased;
+            domain = time::timeout(CLIENT_HELLO_TIMEOUT, transparent::peek_domain(&client)) => {
+                domain.ok().flatten()
+            }
+            _ = dst.readable() => None,
+        };
+        if let Some(domain) = &domain {
+            self.blacklist_manager.check_domain(domain.name()).await;
         }
 
-        self.statistics.increment_total_connections().await;
-        self.statistics.increment_blocked_connections().await;
+        // Plain HTTP takes the proxy's HTTP path, so that --http-evasion
+        // applies, with every request sent to the original destination.
+        if let Some(transparent::Domain::Host(host)) = &domain {
+            if !self.config.http_evasion.is_empty() {
+                let mut client = http::Reader::new(client).with_idle_timeout(CLIENT_IDLE_TIMEOUT);
+                let Some(request) = self.read_request_head(&mut client, &peer).await else {
+                    return;
+                };
+                let upstream = HttpUpstream {
+                    host: host.clone(),
+                    port: target.port(),
+                    endpoint,
+                    reader: http::Reader::new(dst),
+                };
+                self.handle_http_connection(client, request, Some(upstream), Some(target), &peer)
+                    .await;
+                return;
+            }
+        }
+        let host = domain.map_or(ip, |domain| domain.name().to_string());
 
-        let parts = match self.config.fragment_method {
-            FragmentMethod::Random => fragment_random(&data),
-            FragmentMethod::Sni => fragment_sni(&data).unwrap_or_else(|| fragment_random(&data)),
+        let conn_info = ConnectionInfo {
+            src_ip: peer.clone(),
+            dst_domain: host.clone(),
+            method: "TRANSPARENT".to_string(),
+            start_time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            traffic_in: 0,
+            traffic_out: 0,
+            endpoint,
         };
+        self.active_connections.lock().await.insert(peer.clone(), conn_info);
 
-        writer.write_all(&parts).await?;
-        self.statistics.update_traffic(0, parts.len() as u64).await;
-        self.update_conn_out(conn_key, parts.len() as u64).await;
-        Ok(())
+        self.handle_tunnel(client, Vec::new(), dst, &host, target.port(), &peer).await;
     }
 
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    async fn handle_transparent_connection(self: &Arc<Self>, _client: TcpStream) {}
+
     async fn setup_piping(
         &self,
         client_reader: OwnedReadHalf,
@@ -640,41 +906,6 @@ impl ConnectionHandler {
         }
     }
 
-    async fn handle_connection_error(&self, writer: &mut TcpStream, conn_key: &str, err: String) {
-        let response = b"HTTP/1.1 500 Internal Server Error\r\n\r\n";
-        let _ = writer.write_all(response).await;
-        self.statistics.update_traffic(response.len() as u64, 0).await;
-        self.statistics.increment_total_connections().await;
-        self.statistics.increment_error_connections().await;
-        self.logger.log_error(&err).await;
-
-        let info = {
-            let mut map = self.active_connections.lock().await;
-            map.remove(conn_key)
-        };
-        if let Some(info) = info {
-            let line = format!(
-                "{} {} {} {} {} {}",
-                info.start_time,
-                info.src_ip,
-                info.method,
-                info.dst_domain,
-                info.traffic_in,
-                info.traffic_out
-            );
-            self.logger.log_access(&line).await;
-        }
-    }
-
-    async fn handle_auth_required(&self, writer: &mut TcpStream) {
-        let response = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"NoDPI\"\r\nContent-Length: 0\r\n\r\n";
-        let _ = writer.write_all(response).await;
-        self.statistics.update_traffic(response.len() as u64, 0).await;
-        self.statistics.increment_total_connections().await;
-        self.statistics.increment_error_connections().await;
-        self.logger.log_error("Proxy authentication required").await;
-    }
-
     async fn update_conn_in(&self, conn_key: &str, n: u64) {
         let mut map = self.active_connections.lock().await;
         if let Some(info) = map.get_mut(conn_key) {
@@ -689,6 +920,73 @@ impl ConnectionHandler {
         }
     }
 
+    /// Builds what routes are matched against for a connection to `host`.
+    /// When some route matches on IPs and `host` is a name, it is resolved
+    /// so that those routes see its addresses.
+    async fn route_target<'a>(&self, domain: &'a str, host: &str, port: u16, conn_key: &str) -> routing::Target<'a> {
+        let target = routing::Target::new(domain, host, port, conn_key);
+        if !target.ips.is_empty() || !self.blacklist_manager.routes.matches_ips() {
+            return target;
+        }
+        match self.config.resolver.lookup(host, port).await {
+            Ok(addrs) => target.with_addresses(addrs.into_iter().map(|addr| addr.ip())),
+            Err(_) => target,
+        }
+    }
+
+    /// Opens a TCP connection to `host:port` for the client `conn_key`. The
+    /// route picks the upstream, if any, and the endpoint that served the
+    /// connection is noted for the access log. A rejected destination fails
+    /// with `ErrorKind::PermissionDenied`.
+    ///
+    /// This happens before the client sends anything, so for a tunnel to an
+    /// IP the route is picked without the SNI: `domain:` rules cannot send
+    /// it `via` an upstream. They still apply once the ClientHello is read,
+    /// to how it is mangled and to `reject`.
+    async fn connect_remote(&self, host: &str, port: u16, conn_key: &str) -> io::Result<TcpStream> {
+        let (stream, endpoint) = self.connect_route(host, port, conn_key).await?;
+        if let Some(info) = self.active_connections.lock().await.get_mut(conn_key) {
+            info.endpoint = endpoint;
+        }
+        Ok(stream)
+    }
+
+    /// Opens a connection like `connect_remote` and returns the endpoint
+    /// that served it instead of noting it for the access log.
+    async fn connect_route(
+        &self,
+        host: &str,
+        port: u16,
+        conn_key: &str,
+    ) -> io::Result<(TcpStream, Option<upstream::Endpoint>)> {
+        let target = self.route_target(host, host, port, conn_key).await;
+        let pool = match self.blacklist_manager.routes.lookup(&target) {
+            Some(routing::Action::Reject) => return Err(rejected_error()),
+            Some(routing::Action::Via(name)) => self.config.upstreams.get(name),
+            _ => self.config.upstream.as_ref(),
+        };
+        let Some(pool) = pool else {
+            let stream = connect_with_out_host(host, port, &self.config.out_host, &self.config.resolver).await?;
+            return Ok((stream, None));
+        };
+        let (stream, endpoint) = pool
+            .connect(host, port, &self.config.out_host, &self.config.resolver)
+            .await?;
+        Ok((stream, Some(endpoint)))
+    }
+
+    /// Tells whether the client's connection goes through an upstream
+    /// proxy. Its decoy would then expire between us and the proxy, or
+    /// worse reach the proxy and end up in the tunnel, so the fake method
+    /// falls back to `Sni` there.
+    async fn is_proxied(&self, conn_key: &str) -> bool {
+        let info = self.active_connections.lock().await;
+        matches!(
+            info.get(conn_key).and_then(|info| info.endpoint.as_ref()),
+            Some(upstream::Endpoint::Proxy(_))
+        )
+    }
+
     fn clone_for_pipe(&self) -> PipeContext {
         PipeContext {
             statistics: Arc::clone(&self.statistics),
@@ -750,16 +1048,7 @@ async fn pipe_data(
         map.remove(&conn_key)
     };
     if let Some(info) = info {
-        let line = format!(
-            "{} {} {} {} {} {}",
-            info.start_time,
-            info.src_ip,
-            info.method,
-            info.dst_domain,
-            info.traffic_in,
-            info.traffic_out
-        );
-        ctx.logger.log_access(&line).await;
+        ctx.logger.log_access(&info.access_line()).await;
     }
 }
 
@@ -821,10 +1110,9 @@ impl ProxyServer {
             return;
         }
 
-        let update_message = match time::timeout(Duration::from_secs(2), self.check_for_updates()).await {
-            Ok(msg) => msg,
-            Err(_) => None,
-        };
+        let update_message = time::timeout(Duration::from_secs(2), self.check_for_updates())
+            .await
+            .unwrap_or_default();
 
         print!("\x1b]0;NoDPI\x07");
 
@@ -843,9 +1131,9 @@ impl ProxyServer {
         println!("\x1b[91m{}╔{}╗\x1b[0m", " ".repeat(left_padding), "═".repeat(72));
         for line in wrapped {
             println!(
-                "\x1b[91m{}║ {} ║\x1b[0m",
+                "\x1b[91m{}║ {:<70} ║\x1b[0m",
                 " ".repeat(left_padding),
-                format!("{:<70}", line)
+                line
             );
         }
         println!("\x1b[91m{}╚{}╝\x1b[0m", " ".repeat(left_padding), "═".repeat(72));
@@ -866,8 +1154,8 @@ impl ProxyServer {
         );
 
         println!("\x1b[92mVersion: {}\x1b[0m", VERSION);
-        println!("\x1b[97m{}\x1b[0m", "Enjoy watching! / Наслаждайтесь просмотром!");
-        println!("");
+        println!("\x1b[97mEnjoy watching! / Наслаждайтесь просмотром!\x1b[0m");
+        println!();
 
         if let Some(msg) = update_message {
             println!("{}", msg);
@@ -879,22 +1167,90 @@ impl ProxyServer {
             self.config.port,
             Local::now().format("%H:%M on %Y-%m-%d")
         );
+        if let Some(port) = self.config.transparent_port {
+            let addrs: Vec<String> = transparent_addrs(&self.config.transparent_hosts, port)
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect();
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Transparent proxy is running on {} ({})",
+                addrs.join(", "),
+                if self.config.tproxy { "TPROXY" } else { "REDIRECT" }
+            );
+        }
+        if !self.config.resolver.is_system() {
+            println!("\x1b[92m[INFO]:\x1b[97m Host names are resolved with {}", self.config.resolver);
+        }
+        if let Some(path) = &self.config.hosts_file {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Hosts file '{}' pins {} names",
+                path,
+                self.config.resolver.pinned_len()
+            );
+        }
+        if let Some(addr) = self.config.dns_listen {
+            let clients = match self.config.dns_allow.len() {
+                0 => "local networks".to_string(),
+                n => format!("{} allowed networks", n),
+            };
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m DNS forwarder is running on {} (UDP and TCP, {} overrides, answering {})",
+                addr,
+                self.config.dns_overrides.len(),
+                clients
+            );
+        }
+        if let Some(proxy) = &self.config.upstream {
+            println!("\x1b[92m[INFO]:\x1b[97m Outbound connections go through {}", proxy);
+        }
+        let mut names: Vec<&String> = self.config.upstreams.keys().collect();
+        names.sort();
+        for name in names {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Upstream '{}': {}",
+                name, self.config.upstreams[name]
+            );
+        }
+        if let Some(path) = &self.config.routes_file {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Routing table '{}' has {} rules",
+                path,
+                self.blacklist_manager.routes.len()
+            );
+        }
         println!(
             "\x1b[92m[INFO]:\x1b[97m The selected fragmentation method: {}",
-            match self.config.fragment_method {
-                FragmentMethod::Random => "random",
-                FragmentMethod::Sni => "sni",
-            }
+            fragment_method_name(self.config.strategy.method)
         );
+        if !self.config.strategy.timing.is_zero() {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Delay between fragments: {} ms (+ up to {} ms jitter)",
+                self.config.strategy.timing.delay.as_millis(),
+                self.config.strategy.timing.jitter.as_millis()
+            );
+        }
+        if !self.blacklist_manager.rules.is_empty() {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Strategy rules are set for {} domains",
+                self.blacklist_manager.rules.len()
+            );
+        }
+        if let Some(cache) = &self.blacklist_manager.discovered {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Strategy discovery is enabled. {} domains cached in '{}'",
+                cache.len().await,
+                cache.path
+            );
+        }
 
-        println!("");
-        if matches!(&*self.blacklist_manager, BlacklistManager::None) {
+        println!();
+        if matches!(self.blacklist_manager.mode, BlacklistMode::None) {
             println!("\x1b[92m[INFO]:\x1b[97m Blacklist is disabled. All domains will be subject to unblocking.");
-        } else if matches!(&*self.blacklist_manager, BlacklistManager::Auto { .. }) {
+        } else if matches!(self.blacklist_manager.mode, BlacklistMode::Auto { .. }) {
             println!("\x1b[92m[INFO]:\x1b[97m Auto-blacklist is enabled");
         } else {
-            let count = match &*self.blacklist_manager {
-                BlacklistManager::File { blocked, .. } => blocked.len(),
+            let count = match &self.blacklist_manager.mode {
+                BlacklistMode::File { blocked, .. } => blocked.len(),
                 _ => 0,
             };
             println!(
@@ -907,7 +1263,7 @@ impl ProxyServer {
             );
         }
 
-        println!("");
+        println!();
         if let Some(path) = &self.config.log_error_file {
             println!(
                 "\x1b[92m[INFO]:\x1b[97m Error logging is enabled. Path to error log: '{}'",
@@ -926,9 +1282,16 @@ impl ProxyServer {
             println!("\x1b[92m[INFO]:\x1b[97m Access logging is disabled");
         }
 
-        println!("");
+        if let Some(path) = &self.config.log_info_file {
+            println!(
+                "\x1b[92m[INFO]:\x1b[97m Info logging is enabled. Path to info log: '{}'",
+                path
+            );
+        }
+
+        println!();
         println!("\x1b[92m[INFO]:\x1b[97m To stop the proxy, press Ctrl+C twice");
-        println!("");
+        println!();
     }
 
 
@@ -956,10 +1319,28 @@ impl ProxyServer {
             }
         };
 
+        let transparent = self.bind_transparent().await?;
+        if let Some(addr) = self.config.dns_listen {
+            let forwarder = forwarder::Forwarder::new(
+                self.config.resolver.clone(),
+                self.config.dns_overrides.clone(),
+                self.config.dns_allow.clone(),
+                Arc::clone(&self.logger),
+            );
+            if let Err(err) = Arc::new(forwarder).spawn(addr).await {
+                self.logger.error(&format!(
+                    "\x1b[91m[ERROR]: Failed to start the DNS forwarder on {}: {}\x1b[0m",
+                    addr, err
+                ));
+                return Err(err);
+            }
+        }
+
         let handler = Arc::clone(&self.connection_handler);
         tokio::spawn(async move {
             handler.cleanup_tasks().await;
         });
+        self.spawn_health_checks();
 
         if !self.config.quiet {
             let stats = self.statistics.clone();
@@ -972,7 +1353,7 @@ impl ProxyServer {
                         if !config.quiet {
                             let display = stats.get_stats_display().await;
                             println!("{}", display);
-                            print!("\x1b[5A");
+                            print!("\x1b[{}A", display.lines().count());
                         }
                     }
                 }
@@ -993,159 +1374,130 @@ impl ProxyServer {
                         Err(_) => continue,
                     }
                 }
+                res = accept_any(&transparent) => {
+                    match res {
+                        Ok((conn, _)) => {
+                            let handler = Arc::clone(&self.connection_handler);
+                            tokio::spawn(async move {
+                                handler.handle_transparent_connection(conn).await;
+                            });
+                        }
+                        Err(_) => continue,
+                    }
+                }
             }
         }
 
         Ok(())
     }
 
-}
-
-fn parse_http_request(data: &[u8]) -> Result<(String, String, u16, Vec<(String, String)>), String> {
-    let headers: Vec<&[u8]> = data.split(|b| *b == b'\n').collect();
-    if headers.is_empty() {
-        return Err("Missing request line".to_string());
-    }
-    let first_line = headers[0];
-    let parts: Vec<&[u8]> = first_line.split(|b| *b == b' ').collect();
-    if parts.len() < 2 {
-        return Err("Invalid request line".to_string());
-    }
-    let method = String::from_utf8_lossy(parts[0]).to_string();
-    let url = String::from_utf8_lossy(parts[1]).to_string();
-
-    let mut parsed_headers: Vec<(String, String)> = Vec::new();
-    for line in headers.iter().skip(1) {
-        let line = line.strip_suffix(b"\r").unwrap_or(line);
-        if line.is_empty() {
-            break;
-        }
-        if let Some(pos) = line.iter().position(|b| *b == b':') {
-            let (k, v) = line.split_at(pos);
-            let v = &v[1..];
-            let key = String::from_utf8_lossy(k).trim().to_lowercase();
-            let value = String::from_utf8_lossy(v).trim().to_string();
-            parsed_headers.push((key, value));
+    /// Checks the upstream endpoints every HEALTH_CHECK_INTERVAL and logs
+    /// those that go down or come back.
+    fn spawn_health_checks(&self) {
+        let mut pools: Vec<(String, upstream::Pool)> = self
+            .config
+            .upstreams
+            .iter()
+            .map(|(name, pool)| (name.clone(), pool.clone()))
+            .collect();
+        if let Some(pool) = &self.config.upstream {
+            pools.push(("default".to_string(), pool.clone()));
+        }
+        pools.retain(|(_, pool)| pool.needs_checks());
+        if pools.is_empty() {
+            return;
         }
+        let logger = Arc::clone(&self.logger);
+        let out_host = self.config.out_host.clone();
+        let resolver = self.config.resolver.clone();
+        tokio::spawn(async move {
+            loop {
+                for (name, pool) in &pools {
+                    for change in pool.check(&out_host, &resolver).await {
+                        let _ = logger.log_info(&format!("Upstream '{}': {}", name, change)).await;
+                    }
+                }
+                time::sleep(HEALTH_CHECK_INTERVAL).await;
+            }
+        });
     }
 
-    if method == "CONNECT" {
-        let (host, port) = parse_host_port(&url, 443);
-        if host.is_empty() {
-            return Err("Invalid CONNECT host".to_string());
+    /// Binds the transparent listeners, one per address, as the IPv6 ones
+    /// leave IPv4 to their own. Without --transparent-host a host lacking
+    /// IPv6 just goes without that listener.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn bind_transparent(&self) -> io::Result<Vec<TcpListener>> {
+        let Some(port) = self.config.transparent_port else {
+            return Ok(Vec::new());
+        };
+        let mut listeners = Vec::new();
+        for addr in transparent_addrs(&self.config.transparent_hosts, port) {
+            match transparent::bind_listener(addr, self.config.tproxy) {
+                Ok(listener) => listeners.push(listener),
+                Err(err)
+                    if addr.is_ipv6()
+                        && self.config.transparent_hosts.is_empty()
+                        && err.raw_os_error() == Some(libc::EAFNOSUPPORT) => {}
+                Err(err) => {
+                    self.logger.error(&format!(
+                        "\x1b[91m[ERROR]: Failed to start transparent proxy on {}. Error: {}\x1b[0m",
+                        addr, err
+                    ));
+                    let _ = self
+                        .logger
+                        .log_error(&format!("Bind failed on {}: {}", addr, err))
+                        .await;
+                    return Err(err);
+                }
+            }
         }
-        return Ok((method, host, port, parsed_headers));
+        Ok(listeners)
     }
 
-    let host_value = parsed_headers
-        .iter()
-        .find(|(k, _)| k == "host")
-        .map(|(_, v)| v.clone())
-        .ok_or_else(|| "Missing Host header".to_string())?;
-    let (host, port) = parse_host_port(&host_value, 80);
-    if host.is_empty() {
-        return Err("Invalid Host header".to_string());
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    async fn bind_transparent(&self) -> io::Result<Vec<TcpListener>> {
+        Ok(Vec::new())
     }
-
-    Ok((method, host, port, parsed_headers))
 }
 
-fn extract_sni_position(data: &[u8]) -> Option<(usize, usize)> {
-    let mut i = 0usize;
-    while i + 8 < data.len() {
-        if data[i] == 0
-            && data[i + 1] == 0
-            && data[i + 2] == 0
-            && data[i + 4] == 0
-            && data[i + 6] == 0
-            && data[i + 7] == 0
-        {
-            let ext_len = data[i + 3] as usize;
-            let server_name_list_len = data[i + 5] as usize;
-            let server_name_len = data[i + 8] as usize;
-            if ext_len >= server_name_list_len
-                && server_name_list_len >= server_name_len
-                && ext_len - server_name_list_len == 2
-                && server_name_list_len - server_name_len == 3
-            {
-                let start = i + 9;
-                let end = start + server_name_len;
-                if end <= data.len() {
-                    return Some((start, end));
-                }
+/// Accepts on whichever of `listeners` has a connection first, or waits
+/// forever if there are none.
+async fn accept_any(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(res) = listener.poll_accept(cx) {
+                return Poll::Ready(res);
             }
         }
-        i += 1;
-    }
-    None
+        Poll::Pending
+    })
+    .await
 }
 
-fn fragment_random(data: &[u8]) -> Vec<u8> {
-    let mut out = Vec::with_capacity(data.len() * 2);
-    let mut data = data.to_vec();
-    if let Some(pos) = data.iter().position(|b| *b == 0) {
-        out.extend_from_slice(&[0x16, 0x03, 0x04]);
-        out.extend_from_slice(&int_to_2_bytes(pos + 1));
-        out.extend_from_slice(&data[..pos + 1]);
-        data = data[pos + 1..].to_vec();
-    }
-    let mut rng = rand::thread_rng();
-    while !data.is_empty() {
-        let chunk = rng.gen_range(1..=data.len());
-        out.extend_from_slice(&[0x16, 0x03, 0x04]);
-        out.extend_from_slice(&int_to_2_bytes(chunk));
-        out.extend_from_slice(&data[..chunk]);
-        data = data[chunk..].to_vec();
+/// The addresses the transparent listeners bind: `hosts`, or the IPv4 and
+/// IPv6 wildcards, as REDIRECT hands connections to the address of the
+/// interface they came in on.
+fn transparent_addrs(hosts: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    if hosts.is_empty() {
+        let wildcards = [IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)];
+        return wildcards.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
     }
-    out
+    hosts.iter().map(|ip| SocketAddr::new(*ip, port)).collect()
 }
 
-fn fragment_sni(data: &[u8]) -> Option<Vec<u8>> {
-    let mut parts: Vec<Vec<u8>> = Vec::new();
-    if let Some((start, end)) = extract_sni_position(data) {
-        let part_start = &data[..start];
-        let sni_data = &data[start..end];
-        let part_end = &data[end..];
-        let middle = (sni_data.len() + 1) / 2;
-
-        parts.push(
-            [
-                vec![0x16, 0x03, 0x04],
-                int_to_2_bytes(part_start.len()).to_vec(),
-                part_start.to_vec(),
-            ]
-            .concat(),
-        );
-        parts.push(
-            [
-                vec![0x16, 0x03, 0x04],
-                int_to_2_bytes(sni_data[..middle].len()).to_vec(),
-                sni_data[..middle].to_vec(),
-            ]
-            .concat(),
-        );
-        parts.push(
-            [
-                vec![0x16, 0x03, 0x04],
-                int_to_2_bytes(sni_data[middle..].len()).to_vec(),
-                sni_data[middle..].to_vec(),
-            ]
-            .concat(),
-        );
-        parts.push(
-            [
-                vec![0x16, 0x03, 0x04],
-                int_to_2_bytes(part_end.len()).to_vec(),
-                part_end.to_vec(),
-            ]
-            .concat(),
-        );
-    }
-
-    if parts.is_empty() {
-        None
-    } else {
-        Some(parts.concat())
+/// Tells whether the client opens the tunnel with a TLS handshake record.
+/// Gives up as soon as the server sends something first. The server side
+/// is peeked rather than waited on with `readable`, whose readiness may be
+/// left over from the handshake with an upstream proxy.
+async fn client_starts_with_tls(client: &TcpStream, dst: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    let mut server_first = [0u8; 1];
+    tokio::select! {
+        biased;
+        read = client.peek(&mut first) => {
+            matches!(read, Ok(1)) && first[0] == tls::CONTENT_TYPE_HANDSHAKE
+        }
+        _ = dst.peek(&mut server_first) => false,
     }
 }
 
@@ -1181,38 +1533,13 @@ fn parse_host_port(value: &str, default_port: u16) -> (String, u16) {
     (v.to_string(), default_port)
 }
 
-fn is_auth_ok(config: &Config, headers: &[(String, String)]) -> bool {
-    let auth_enabled = config.users_file.is_some()
-        || (config.auth_user.is_some() && config.auth_pass.is_some());
-    if !auth_enabled {
-        return true;
-    }
-    let header = headers
-        .iter()
-        .find(|(k, _)| k == "proxy-authorization")
-        .map(|(_, v)| v.as_str());
-    let Some(value) = header else {
-        return false;
-    };
-    let value = value.trim();
-    let basic_prefix = "basic ";
-    if !value.to_ascii_lowercase().starts_with(basic_prefix) {
-        return false;
-    }
-    let b64 = value[basic_prefix.len()..].trim();
-    let decoded = STANDARD.decode(b64).ok();
-    let Some(decoded) = decoded else {
-        return false;
-    };
-    let decoded = String::from_utf8_lossy(&decoded);
-    let mut it = decoded.splitn(2, ':');
-    let Some(u) = it.next() else {
-        return false;
-    };
-    let Some(p) = it.next() else {
-        return false;
-    };
+fn is_auth_enabled(config: &Config) -> bool {
+    config.users_file.is_some() || (config.auth_user.is_some() && config.auth_pass.is_some())
+}
 
+/// Checks a username and password against the users file, or the single
+/// --auth-user/--auth-pass pair when there is none.
+fn check_credentials(config: &Config, u: &str, p: &str) -> bool {
     if let Some(users) = &config.users {
         let hashed = hash_password(p);
         return users.get(u).map(|v| v == &hashed).unwrap_or(false);
@@ -1224,6 +1551,16 @@ fn is_auth_ok(config: &Config, headers: &[(String, String)]) -> bool {
     u == user && p == pass
 }
 
+/// Checks a bare username, as sent in a SOCKS4 userid, against the users
+/// file or --auth-user.
+fn check_user(config: &Config, u: &str) -> bool {
+    match (&config.users, &config.auth_user) {
+        (Some(users), _) => users.contains_key(u),
+        (None, Some(user)) => u == user,
+        (None, None) => false,
+    }
+}
+
 fn is_domain_blocked(blocked: &[String], domain_matching: DomainMatching, domain: &str) -> bool {
     let domain = domain.replace("www.", "").to_lowercase();
     if domain_matching == DomainMatching::Loose {
@@ -1249,16 +1586,27 @@ fn is_domain_blocked(blocked: &[String], domain_matching: DomainMatching, domain
     false
 }
 
-async fn connect_with_out_host(host: &str, port: u16, out_host: &Option<String>) -> io::Result<TcpStream> {
-    let mut addrs = lookup_host((host, port)).await?;
+/// Connects to `host`, trying each of the addresses `resolver` gives for
+/// it in turn. A failed lookup is reported as `ErrorKind::NotFound` and a
+/// connect that takes longer than CONNECT_TIMEOUT as `ErrorKind::TimedOut`.
+async fn connect_with_out_host(
+    host: &str,
+    port: u16,
+    out_host: &Option<String>,
+    resolver: &dns::Resolver,
+) -> io::Result<TcpStream> {
+    let addrs = resolver.lookup(host, port).await.map_err(|err| {
+        io::Error::new(io::ErrorKind::NotFound, format!("DNS lookup failed: {}", err))
+    })?;
     let out_addr = if let Some(out) = out_host {
-        let mut out_iter = lookup_host((out.as_str(), 0)).await?;
-        out_iter.next()
+        resolver.lookup(out, 0).await?.into_iter().next()
     } else {
         None
     };
 
-    while let Some(addr) = addrs.next() {
+    let mut tried = 0;
+    let mut last_err = None;
+    for addr in addrs {
         if let Some(local) = out_addr {
             if addr.is_ipv4() != local.is_ipv4() {
                 continue;
@@ -1269,19 +1617,41 @@ async fn connect_with_out_host(host: &str, port: u16, out_host: &Option<String>)
                 TcpSocket::new_v6()?
             };
             let bind_addr = SocketAddr::new(local.ip(), 0);
-            socket.bind(bind_addr)?;
-            if let Ok(stream) = socket.connect(addr).await {
-                return Ok(stream);
+            socket.bind(bind_addr).map_err(|err| {
+                io::Error::new(err.kind(), format!("cannot bind to {}: {}", local.ip(), err))
+            })?;
+            tried += 1;
+            match time::timeout(CONNECT_TIMEOUT, socket.connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_err = Some((addr, err)),
+                Err(_) => last_err = Some((addr, io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))),
+            }
+        } else {
+            tried += 1;
+            match time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_err = Some((addr, err)),
+                Err(_) => last_err = Some((addr, io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))),
             }
-        } else if let Ok(stream) = TcpStream::connect(addr).await {
-            return Ok(stream);
         }
     }
 
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "Unable to connect to remote host",
-    ))
+    Err(match (last_err, out_addr) {
+        (Some((addr, err)), _) if tried > 1 => io::Error::new(
+            err.kind(),
+            format!("{} addresses of {} failed, last {}: {}", tried, host, addr, err),
+        ),
+        (Some((addr, err)), _) => io::Error::new(err.kind(), format!("{}: {}", addr, err)),
+        (None, Some(local)) => io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address of the same family as --out-host {}", host, local.ip()),
+        ),
+        (None, None) => io::Error::new(io::ErrorKind::NotFound, format!("DNS lookup for {} returned no addresses", host)),
+    })
+}
+
+fn rejected_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "rejected by a routing rule")
 }
 
 fn format_size(size: u64) -> String {
@@ -1309,10 +1679,6 @@ fn format_speed(speed_bps: f64) -> String {
     format!("{:.0} {}", speed, units[unit])
 }
 
-fn int_to_2_bytes(n: usize) -> [u8; 2] {
-    [(n >> 8) as u8, (n & 0xff) as u8]
-}
-
 fn open_append(path: &str) -> io::Result<File> {
     OpenOptions::new().create(true).append(true).open(path)
 }
@@ -1323,7 +1689,9 @@ fn append_line(path: &str, line: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn load_blacklist(path: &str) -> io::Result<Vec<String>> {
+/// Loads the blacklist. A line may carry strategy rule tokens after the
+/// domain (`example.com method=sni delay=5`), those are returned separately.
+fn load_blacklist(path: &str) -> io::Result<(Vec<String>, HashMap<String, StrategyRule>)> {
     if !Path::new(path).exists() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
@@ -1332,14 +1700,26 @@ fn load_blacklist(path: &str) -> io::Result<Vec<String>> {
     }
     let data = fs::read_to_string(path)?;
     let mut blocked = Vec::new();
-    for line in data.lines() {
+    let mut rules = HashMap::new();
+    for (n, line) in data.lines().enumerate() {
         let line = line.trim();
         if line.len() < 2 || line.starts_with('#') {
             continue;
         }
-        blocked.push(line.to_lowercase().replace("www.", ""));
+        let mut tokens = line.split_whitespace().peekable();
+        let domain = tokens.next().unwrap_or("").to_lowercase().replace("www.", "");
+        if tokens.peek().is_some() {
+            let rule = parse_strategy_rule(tokens).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path, n + 1, err),
+                )
+            })?;
+            rules.insert(rule_domain(&domain), rule);
+        }
+        blocked.push(domain);
     }
-    Ok(blocked)
+    Ok((blocked, rules))
 }
 
 fn wrap_text(text: &str, width: usize) -> Vec<String> {
@@ -1361,15 +1741,44 @@ fn wrap_text(text: &str, width: usize) -> Vec<String> {
     lines
 }
 
-fn parse_args() -> Result<Args, String> {
+fn parse_args(args: Vec<String>) -> Result<Args, String> {
     let mut host = "127.0.0.1".to_string();
     let mut port: u16 = 8881;
     let mut out_host: Option<String> = None;
+    let mut dns_server = "system".to_string();
+    let mut dns_method = dns::DohMethod::Get;
+    let mut dns_bootstrap: Vec<IpAddr> = Vec::new();
+    let mut dns_listen: Option<String> = None;
+    let mut dns_cache_size = None;
+    let mut dns_negative_ttl = DEFAULT_DNS_NEGATIVE_TTL;
+    let mut hosts_file: Option<String> = None;
+    let mut dns_overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    let mut dns_allow: Vec<routing::Cidr> = Vec::new();
+    let mut upstream: Option<upstream::Pool> = None;
+    let mut upstreams: HashMap<String, upstream::Pool> = HashMap::new();
+    let mut upstream_policies: Vec<(Option<String>, upstream::Policy)> = Vec::new();
+    let mut routes_file: Option<String> = None;
+    let mut transparent_port: Option<u16> = None;
+    let mut transparent_hosts: Vec<IpAddr> = Vec::new();
+    let mut tproxy = false;
     let mut blacklist = "blacklist.txt".to_string();
-    let mut fragment_method = FragmentMethod::Random;
+    let mut strategy = Strategy {
+        method: FragmentMethod::Random,
+        ..Strategy::direct()
+    };
+    let mut fragment_seed: Option<u64> = None;
+    let mut strategy_rules: HashMap<String, StrategyRule> = HashMap::new();
+    let mut rules_file: Option<String> = None;
+    let mut fake_ttl = FakeTtl::Fixed(DEFAULT_FAKE_TTL);
+    let mut fake_sni = DEFAULT_FAKE_SNI.to_string();
+    let mut http_evasion = HttpEvasion::default();
+    let mut auto_strategy = false;
+    let mut strategy_cache = DEFAULT_STRATEGY_CACHE.to_string();
+    let mut strategy_ttl = DEFAULT_STRATEGY_TTL;
     let mut domain_matching = DomainMatching::Strict;
     let mut log_access: Option<String> = None;
     let mut log_error: Option<String> = None;
+    let mut log_info: Option<String> = None;
     let mut no_blacklist = false;
     let mut auto_blacklist = false;
     let mut quiet = false;
@@ -1382,8 +1791,8 @@ fn parse_args() -> Result<Args, String> {
     let mut users_file: Option<String> = None;
     let mut add_user: Option<String> = None;
     let mut add_pass: Option<String> = None;
+    let mut explain: Option<String> = None;
 
-    let args: Vec<String> = std::env::args().skip(1).collect();
     let mut i = 0;
     while i < args.len() {
         let arg = &args[i];
@@ -1394,6 +1803,12 @@ fn parse_args() -> Result<Args, String> {
         };
 
         match key {
+            "explain" if i == 0 => {
+                explain = take_value(&args, &mut i, None);
+                if explain.is_none() {
+                    return Err("error: explain requires a ClientHello file or hex string".to_string());
+                }
+            }
             "--host" => {
                 if let Some(v) = take_value(&args, &mut i, inline_value) {
                     host = v;
@@ -1411,6 +1826,116 @@ fn parse_args() -> Result<Args, String> {
                     out_host = Some(v);
                 }
             }
+            "--dns" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    dns_server = v;
+                }
+            }
+            "--dns-method" | "--dns_method" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    dns_method = dns::DohMethod::parse(&v).map_err(|err| format!("error: {}", err))?;
+                }
+            }
+            "--dns-bootstrap" | "--dns_bootstrap" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    for ip in v.split(',') {
+                        let ip = ip
+                            .trim()
+                            .parse::<IpAddr>()
+                            .map_err(|_| format!("error: invalid --dns-bootstrap address '{}'", ip))?;
+                        dns_bootstrap.push(ip);
+                    }
+                }
+            }
+            "--dns-cache-size" | "--dns_cache_size" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    dns_cache_size = Some(
+                        v.parse::<usize>()
+                            .map_err(|_| format!("error: invalid --dns-cache-size value '{}'", v))?,
+                    );
+                }
+            }
+            "--dns-negative-ttl" | "--dns_negative_ttl" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    let secs = v
+                        .parse::<u64>()
+                        .map_err(|_| format!("error: invalid --dns-negative-ttl value '{}'", v))?;
+                    dns_negative_ttl = Duration::from_secs(secs);
+                }
+            }
+            "--hosts" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    hosts_file = Some(v);
+                }
+            }
+            "--dns-listen" | "--dns_listen" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    dns_listen = Some(v);
+                }
+            }
+            "--dns-override" | "--dns_override" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    let (domain, ips) = forwarder::parse_override(&v).map_err(|err| format!("error: {}", err))?;
+                    dns_overrides.insert(domain, ips);
+                }
+            }
+            "--dns-allow" | "--dns_allow" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    dns_allow.extend(forwarder::parse_allowed(&v).map_err(|err| format!("error: {}", err))?);
+                }
+            }
+            "--upstream" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    // NAME=URL,... adds an upstream for routes; without a name
+                    // it is the default one.
+                    match v.split_once('=').filter(|(name, _)| !name.contains(':')) {
+                        Some((name, urls)) => {
+                            let pool = upstream::Pool::parse(urls).map_err(|err| format!("error: {}", err))?;
+                            upstreams.insert(name.to_string(), pool);
+                        }
+                        None => {
+                            upstream = Some(upstream::Pool::parse(&v).map_err(|err| format!("error: {}", err))?);
+                        }
+                    }
+                }
+            }
+            "--upstream-policy" | "--upstream_policy" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    let (name, policy) = match v.split_once('=') {
+                        Some((name, policy)) => (Some(name.to_string()), policy),
+                        None => (None, v.as_str()),
+                    };
+                    let policy = upstream::Policy::parse(policy).map_err(|err| format!("error: {}", err))?;
+                    upstream_policies.push((name, policy));
+                }
+            }
+            "--routes" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    routes_file = Some(v);
+                }
+            }
+            "--transparent-port" | "--transparent_port" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    let p = v
+                        .parse::<u16>()
+                        .map_err(|_| format!("error: invalid --transparent-port value '{}'", v))?;
+                    transparent_port = Some(p);
+                }
+            }
+            "--transparent-host" | "--transparent_host" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    for ip in v.split(',') {
+                        let ip = ip
+                            .trim()
+                            .parse::<IpAddr>()
+                            .map_err(|_| format!("error: invalid --transparent-host address '{}'", ip))?;
+                        transparent_hosts.push(ip);
+                    }
+                }
+            }
+            "--tproxy" => {
+                tproxy = true;
+            }
             "--blacklist" => {
                 if let Some(v) = take_value(&args, &mut i, inline_value) {
                     blacklist = v;
@@ -1425,12 +1950,113 @@ fn parse_args() -> Result<Args, String> {
             }
             "--fragment-method" | "--fragment_method" => {
                 if let Some(v) = take_value(&args, &mut i, inline_value) {
-                    fragment_method = match v.as_str() {
-                        "sni" => FragmentMethod::Sni,
-                        _ => FragmentMethod::Random,
+                    strategy.method = parse_fragment_method(&v).unwrap_or(FragmentMethod::Random);
+                }
+            }
+            "--fragment-delay" | "--fragment_delay" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    match parse_timing_value("--fragment-delay", &v)? {
+                        (Some(domain), d) => strategy_rules.entry(domain).or_default().delay = Some(d),
+                        (None, d) => strategy.timing.delay = d,
+                    }
+                }
+            }
+            "--fragment-jitter" | "--fragment_jitter" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    match parse_timing_value("--fragment-jitter", &v)? {
+                        (Some(domain), d) => strategy_rules.entry(domain).or_default().jitter = Some(d),
+                        (None, d) => strategy.timing.jitter = d,
+                    }
+                }
+            }
+            "--record-version" | "--record_version" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy.record_version =
+                        Some(parse_record_version(&v).map_err(|err| format!("error: {}", err))?);
+                }
+            }
+            "--fragment-seed" | "--fragment_seed" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    fragment_seed = Some(
+                        v.parse::<u64>()
+                            .map_err(|_| format!("error: invalid --fragment-seed value '{}'", v))?,
+                    );
+                }
+            }
+            "--fragment-min-size" | "--fragment_min_size" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy.layout.min_size = parse_count("--fragment-min-size value", &v)
+                        .map_err(|err| format!("error: {}", err))?;
+                }
+            }
+            "--fragment-max-size" | "--fragment_max_size" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy.layout.max_size = parse_count("--fragment-max-size value", &v)
+                        .map_err(|err| format!("error: {}", err))?;
+                }
+            }
+            "--max-fragments" | "--max_fragments" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy.layout.max_fragments = Some(
+                        parse_count("--max-fragments value", &v).map_err(|err| format!("error: {}", err))?,
+                    );
+                }
+            }
+            "--fragment-window" | "--fragment_window" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy.layout.window = Some(
+                        parse_count("--fragment-window value", &v).map_err(|err| format!("error: {}", err))?,
+                    );
+                }
+            }
+            "--rules" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    rules_file = Some(v);
+                }
+            }
+            "--fake-ttl" | "--fake_ttl" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    fake_ttl = match v.as_str() {
+                        "auto" => FakeTtl::Auto,
+                        n => match n.parse::<u8>() {
+                            Ok(ttl) if ttl > 0 => FakeTtl::Fixed(ttl),
+                            _ => return Err(format!("error: invalid --fake-ttl value '{}'", n)),
+                        },
                     };
                 }
             }
+            "--fake-sni" | "--fake_sni" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    fake_sni = v;
+                }
+            }
+            "--http-evasion" | "--http_evasion" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    http_evasion = parse_http_evasion(&v)?;
+                }
+            }
+            "--auto-strategy" | "--auto_strategy" => {
+                auto_strategy = true;
+            }
+            "--strategy-cache" | "--strategy_cache" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy_cache = v;
+                }
+            }
+            "--strategy-ttl" | "--strategy_ttl" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    let hours = v
+                        .parse::<u64>()
+                        .map_err(|_| format!("error: invalid --strategy-ttl value '{}'", v))?;
+                    strategy_ttl = Duration::from_secs(hours.saturating_mul(60 * 60));
+                }
+            }
+            "--tcp-split" | "--tcp_split" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    strategy.tcp_split =
+                        parse_split_positions(&v).map_err(|err| format!("error: {}", err))?;
+                }
+            }
             "--domain-matching" | "--domain_matching" => {
                 if let Some(v) = take_value(&args, &mut i, inline_value) {
                     domain_matching = match v.as_str() {
@@ -1449,6 +2075,11 @@ fn parse_args() -> Result<Args, String> {
                     log_error = Some(v);
                 }
             }
+            "--log-info" | "--log_info" => {
+                if let Some(v) = take_value(&args, &mut i, inline_value) {
+                    log_info = Some(v);
+                }
+            }
             "--auth-user" | "--auth_user" => {
                 if let Some(v) = take_value(&args, &mut i, inline_value) {
                     auth_user = Some(v);
@@ -1505,20 +2136,105 @@ fn parse_args() -> Result<Args, String> {
     if auth_user.is_some() != auth_pass.is_some() {
         return Err("error: --auth-user requires --auth-pass (and vice versa)".to_string());
     }
+    if strategy.method == FragmentMethod::Fake
+        && !cfg!(any(target_os = "linux", target_os = "android"))
+    {
+        return Err("error: --fragment-method fake is only supported on Linux".to_string());
+    }
+    if transparent_port.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+        return Err("error: --transparent-port is only supported on Linux".to_string());
+    }
+    if tproxy && transparent_port.is_none() {
+        return Err("error: --tproxy requires --transparent-port".to_string());
+    }
+    if !transparent_hosts.is_empty() && transparent_port.is_none() {
+        return Err("error: --transparent-host requires --transparent-port".to_string());
+    }
+    if transparent_port == Some(port) {
+        return Err("error: --transparent-port must differ from --port".to_string());
+    }
+    if strategy.layout.min_size > strategy.layout.max_size {
+        return Err("error: --fragment-min-size is larger than --fragment-max-size".to_string());
+    }
+    for (name, policy) in upstream_policies {
+        let pool = match &name {
+            Some(name) => upstreams.get_mut(name),
+            None => upstream.as_mut(),
+        };
+        match pool {
+            Some(pool) => pool.set_policy(policy),
+            None => {
+                return Err(format!(
+                    "error: --upstream-policy names upstream '{}', which no --upstream defines",
+                    name.unwrap_or_default()
+                ))
+            }
+        }
+    }
     if add_user.is_some() != add_pass.is_some() {
         return Err("error: --add-user requires --add-pass (and vice versa)".to_string());
     }
+    let mut resolver =
+        dns::Resolver::new(&dns_server, dns_method, &dns_bootstrap).map_err(|err| format!("error: {}", err))?;
+    let dns_cache_size =
+        dns_cache_size.unwrap_or(if resolver.is_system() { 0 } else { DEFAULT_DNS_CACHE_SIZE });
+    if dns_cache_size > 0 {
+        resolver.set_cache(dns::Cache::new(dns_cache_size, dns_negative_ttl));
+    }
+    if let Some(path) = &hosts_file {
+        let hosts = dns::load_hosts(path).map_err(|err| format!("error: cannot load hosts file: {}", err))?;
+        resolver.set_hosts(hosts);
+    }
+    // A bare port listens on all interfaces, for the LAN; the forwarder
+    // only answers the networks of --dns-allow.
+    let dns_listen = match dns_listen {
+        Some(value) => {
+            let addr = match value.parse::<u16>() {
+                Ok(port) => Some(SocketAddr::from(([0, 0, 0, 0], port))),
+                Err(_) => value.parse::<SocketAddr>().ok(),
+            };
+            Some(addr.ok_or_else(|| format!("error: invalid --dns-listen address '{}'", value))?)
+        }
+        None => None,
+    };
+    if !dns_allow.is_empty() && dns_listen.is_none() {
+        return Err("error: --dns-allow requires --dns-listen".to_string());
+    }
+    if dns_listen.is_some() && resolver.is_system() {
+        return Err("error: --dns-listen requires --dns with a DoH or DoT server".to_string());
+    }
 
     Ok(Args {
         config: Config {
             host,
             port,
             out_host,
+            resolver,
+            hosts_file,
+            dns_listen,
+            dns_overrides,
+            dns_allow,
+            upstream,
+            upstreams,
+            routes_file,
+            transparent_port,
+            transparent_hosts,
+            tproxy,
             blacklist_file: blacklist,
-            fragment_method,
+            strategy,
+            fragment_seed,
+            strategy_rules,
+            rules_file,
+            fake_ttl,
+            fake_sni,
+            http_evasion,
+            auto_strategy,
+            strategy_cache_file: strategy_cache,
+            strategy_ttl,
             domain_matching,
             log_access_file: log_access,
             log_error_file: log_error,
+            log_info_file: log_info,
             no_blacklist,
             auto_blacklist,
             quiet,
@@ -1528,6 +2244,7 @@ fn parse_args() -> Result<Args, String> {
             users_file,
             users: None,
         },
+        explain,
         install,
         uninstall,
         add_user,
@@ -1623,7 +2340,7 @@ fn manage_autostart(install: bool) -> Result<(), String> {
             println!("\x1b[92m[INFO]:\x1b[97m Service removed from autostart");
         }
 
-        return Ok(());
+        Ok(())
     }
 
     #[cfg(not(any(windows, target_os = "linux")))]
@@ -1635,7 +2352,7 @@ fn manage_autostart(install: bool) -> Result<(), String> {
 
 #[tokio::main]
 async fn main() {
-    let args = match parse_args() {
+    let args = match parse_args(std::env::args().skip(1).collect()) {
         Ok(a) => a,
         Err(msg) => {
             eprintln!("{}", msg);
@@ -1643,6 +2360,14 @@ async fn main() {
         }
     };
 
+    if let Some(input) = &args.explain {
+        if let Err(err) = explain::run(input, &args.config).await {
+            eprintln!("\x1b[91m[ERROR]:\x1b[97m {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if let (Some(user), Some(pass)) = (&args.add_user, &args.add_pass) {
         let path = args
             .config
@@ -1669,7 +2394,12 @@ async fn main() {
         return;
     }
 
-    let logger = match Logger::new(&args.config.log_access_file, &args.config.log_error_file, args.config.quiet) {
+    let logger = match Logger::new(
+        &args.config.log_access_file,
+        &args.config.log_error_file,
+        &args.config.log_info_file,
+        args.config.quiet,
+    ) {
         Ok(l) => Arc::new(l),
         Err(err) => {
             eprintln!("\x1b[91m[ERROR]:\x1b[97m {}\x1b[0m", err);
@@ -1677,7 +2407,7 @@ async fn main() {
         }
     };
 
-    let statistics = Arc::new(Statistics::new());
+    let statistics = Arc::new(Statistics::new(args.config.resolver.cache()));
     let stats_clone = Arc::clone(&statistics);
     logger
         .set_error_counter_callback(Arc::new(move || {
@@ -1731,20 +2461,63 @@ async fn main() {
 }
 
 fn create_blacklist_manager(config: &Config) -> io::Result<BlacklistManager> {
-    if config.no_blacklist {
-        return Ok(BlacklistManager::None);
-    }
-    if config.auto_blacklist {
-        return Ok(BlacklistManager::Auto {
+    let mut rules = match &config.rules_file {
+        Some(path) => load_strategy_rules(path)?,
+        None => HashMap::new(),
+    };
+
+    let mode = if config.no_blacklist {
+        BlacklistMode::None
+    } else if config.auto_blacklist {
+        BlacklistMode::Auto {
             blocked: Mutex::new(Vec::new()),
             whitelist: Mutex::new(Vec::new()),
             blacklist_file: config.blacklist_file.clone(),
-        });
+            resolver: config.resolver.clone(),
+        }
+    } else {
+        let (blocked, inline_rules) = load_blacklist(&config.blacklist_file)?;
+        for (domain, rule) in inline_rules {
+            rules.entry(domain).or_default().merge(&rule);
+        }
+        BlacklistMode::File {
+            blocked,
+            domain_matching: config.domain_matching,
+        }
+    };
+
+    for (domain, rule) in &config.strategy_rules {
+        let mut merged = rule.clone();
+        if let Some(existing) = rules.get(domain) {
+            merged.merge(existing);
+        }
+        rules.insert(domain.clone(), merged);
+    }
+
+    let routes = match &config.routes_file {
+        Some(path) => routing::Table::load(path)?,
+        None => routing::Table::default(),
+    };
+    if let Some(name) = routes.upstreams().find(|name| !config.upstreams.contains_key(*name)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("routes refer to upstream '{}', which no --upstream names", name),
+        ));
     }
-    let blocked = load_blacklist(&config.blacklist_file)?;
-    Ok(BlacklistManager::File {
-        blocked,
+
+    let discovered = if config.auto_strategy {
+        Some(StrategyCache::load(&config.strategy_cache_file, config.strategy_ttl)?)
+    } else {
+        None
+    };
+
+    Ok(BlacklistManager {
+        mode,
+        routes,
+        rules,
         domain_matching: config.domain_matching,
+        default_strategy: config.strategy.clone(),
+        discovered,
     })
 }
 
@@ -1843,3 +2616,89 @@ fn visible_len_ansi(s: &str) -> usize {
     }
     count
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("nodpi-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn strategy_for(manager: &BlacklistManager, domain: &str) -> Strategy {
+        let target = routing::Target::new(domain, domain, 443, "127.0.0.1:50000");
+        manager.strategy_for(&target).await
+    }
+
+    #[tokio::test]
+    async fn routes_win_over_domain_rules_over_discovered_methods_over_the_global_strategy() {
+        let now = Local::now().timestamp();
+        let cache = temp_file(
+            "precedence-cache.txt",
+            &format!("found.com sni {0}\nruled.com sni {0}\nrouted.com sni {0}\n", now),
+        );
+        let routes = temp_file("precedence-routes.txt", "domain:routed.com fragment(method=random max-size=50)\n");
+        let rules = ["ruled.com", "routed.com"]
+            .into_iter()
+            .map(|domain| (domain.to_string(), parse_strategy_rule("method=tcp delay=7".split_whitespace()).unwrap()))
+            .collect();
+        let manager = BlacklistManager {
+            mode: BlacklistMode::None,
+            routes: routing::Table::load(&routes).unwrap(),
+            rules,
+            domain_matching: DomainMatching::Strict,
+            default_strategy: Strategy {
+                method: FragmentMethod::Random,
+                ..Strategy::direct()
+            },
+            discovered: Some(StrategyCache::load(&cache, Duration::from_secs(3600)).unwrap()),
+        };
+        fs::remove_file(&cache).unwrap();
+        fs::remove_file(&routes).unwrap();
+
+        let global = strategy_for(&manager, "plain.com").await;
+        assert_eq!(global.method, FragmentMethod::Random);
+        assert!(global.timing.delay.is_zero());
+
+        assert_eq!(strategy_for(&manager, "found.com").await.method, FragmentMethod::Sni);
+
+        let ruled = strategy_for(&manager, "ruled.com").await;
+        assert_eq!(ruled.method, FragmentMethod::TcpSplit);
+        assert_eq!(ruled.timing.delay, Duration::from_millis(7));
+
+        // The route sets the method; the domain rule fills in what it leaves.
+        let routed = strategy_for(&manager, "routed.com").await;
+        assert_eq!(routed.method, FragmentMethod::Random);
+        assert_eq!(routed.layout.max_size, 50);
+        assert_eq!(routed.timing.delay, Duration::from_millis(7));
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn socks4_userids_are_checked_against_the_auth_user() {
+        let config = parse_args(args("--auth-user alice --auth-pass secret")).unwrap().config;
+        assert!(is_auth_enabled(&config));
+        assert!(check_user(&config, "alice"));
+        assert!(!check_user(&config, "bob"));
+        assert!(!check_user(&config, ""));
+    }
+
+    #[test]
+    fn socks4_userids_are_checked_against_the_users_file() {
+        let mut config = parse_args(args("--users-file users.txt --auth-user alice --auth-pass secret"))
+            .unwrap()
+            .config;
+        config.users = Some(Arc::new(HashMap::from([("carol".to_string(), hash_password("pw"))])));
+        assert!(is_auth_enabled(&config));
+        assert!(check_user(&config, "carol"));
+        assert!(!check_user(&config, "alice"));
+    }
+}
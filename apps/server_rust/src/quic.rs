@@ -0,0 +1,336 @@
+//! Just enough of QUIC (RFC 9000, RFC 9001) to read the ClientHello carried
+//! in a client's Initial packets. Initial keys are derived from the
+//! destination connection ID, so no handshake state is needed.
+
+use std::collections::BTreeMap;
+
+use ring::aead::{self, quic, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+
+const VERSION_1: u32 = 0x0000_0001;
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const MAX_CID_LEN: usize = 20;
+const SAMPLE_LEN: usize = 16;
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+
+/// The decrypted contents of a client Initial packet.
+pub struct Initial {
+    pub dcid: Vec<u8>,
+    /// CRYPTO frames as (stream offset, data).
+    pub crypto: Vec<(u64, Vec<u8>)>,
+}
+
+/// Tells whether the first packet in `datagram` is a QUIC v1 Initial.
+pub fn is_initial(datagram: &[u8]) -> bool {
+    datagram.len() > 5
+        && datagram[0] & 0xc0 == 0xc0
+        && (datagram[0] >> 4) & 0x03 == 0
+        && u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]) == VERSION_1
+}
+
+/// Removes header protection from and decrypts the first packet of
+/// `datagram`. Returns `None` for anything that is not a well-formed QUIC v1
+/// client Initial.
+pub fn decrypt_initial(datagram: &[u8]) -> Option<Initial> {
+    if !is_initial(datagram) {
+        return None;
+    }
+    let mut pos = 5;
+    let dcid_len = *datagram.get(pos)? as usize;
+    if dcid_len > MAX_CID_LEN {
+        return None;
+    }
+    let dcid = datagram.get(pos + 1..pos + 1 + dcid_len)?.to_vec();
+    pos += 1 + dcid_len;
+    let scid_len = *datagram.get(pos)? as usize;
+    pos += 1 + scid_len;
+    let token_len = read_varint(datagram, &mut pos)? as usize;
+    pos = pos.checked_add(token_len)?;
+    let length = read_varint(datagram, &mut pos)? as usize;
+    let pn_offset = pos;
+    let end = pn_offset.checked_add(length)?;
+    if end > datagram.len() || length < 4 + SAMPLE_LEN {
+        return None;
+    }
+
+    let keys = InitialKeys::client(&dcid)?;
+    let mut packet = datagram[..end].to_vec();
+    let sample = &packet[pn_offset + 4..pn_offset + 4 + SAMPLE_LEN];
+    let mask = keys.hp.new_mask(sample).ok()?;
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    let mut pn = 0u64;
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+        pn = (pn << 8) | packet[pn_offset + i] as u64;
+    }
+
+    let mut nonce = keys.iv;
+    for (i, b) in pn.to_be_bytes().iter().enumerate() {
+        nonce[4 + i] ^= b;
+    }
+    let (header, payload) = packet.split_at_mut(pn_offset + pn_len);
+    let plain = keys
+        .key
+        .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(&header[..]), payload)
+        .ok()?;
+    Some(Initial {
+        dcid,
+        crypto: parse_crypto_frames(plain)?,
+    })
+}
+
+struct InitialKeys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    hp: quic::HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    fn client(dcid: &[u8]) -> Option<Self> {
+        let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT_V1).extract(dcid);
+        let mut secret = [0u8; 32];
+        expand_label(&initial, b"client in", &mut secret)?;
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+
+        let mut key = [0u8; 16];
+        let mut iv = [0u8; 12];
+        let mut hp = [0u8; 16];
+        expand_label(&secret, b"quic key", &mut key)?;
+        expand_label(&secret, b"quic iv", &mut iv)?;
+        expand_label(&secret, b"quic hp", &mut hp)?;
+        Some(Self {
+            key: LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &key).ok()?),
+            iv,
+            hp: quic::HeaderProtectionKey::new(&quic::AES_128, &hp).ok()?,
+        })
+    }
+}
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-Expand-Label from TLS 1.3 with an empty context.
+fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let info: [&[u8]; 5] = [&len, &label_len, b"tls13 ", label, &[0]];
+    prk.expand(&info, OutputLen(out.len())).ok()?.fill(out).ok()
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *data.get(*pos)?;
+    let len = 1usize << (first >> 6);
+    let bytes = data.get(*pos..*pos + len)?;
+    let mut value = (first & 0x3f) as u64;
+    for b in &bytes[1..] {
+        value = (value << 8) | *b as u64;
+    }
+    *pos += len;
+    Some(value)
+}
+
+/// Collects the CRYPTO frames of a decrypted Initial payload. Frames a
+/// client does not send in Initials make the whole packet unreadable.
+fn parse_crypto_frames(plain: &[u8]) -> Option<Vec<(u64, Vec<u8>)>> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos < plain.len() {
+        match read_varint(plain, &mut pos)? {
+            FRAME_PADDING | FRAME_PING => {}
+            frame @ (FRAME_ACK | FRAME_ACK_ECN) => {
+                read_varint(plain, &mut pos)?;
+                read_varint(plain, &mut pos)?;
+                let ranges = read_varint(plain, &mut pos)?;
+                read_varint(plain, &mut pos)?;
+                for _ in 0..ranges * 2 {
+                    read_varint(plain, &mut pos)?;
+                }
+                if frame == FRAME_ACK_ECN {
+                    for _ in 0..3 {
+                        read_varint(plain, &mut pos)?;
+                    }
+                }
+            }
+            FRAME_CRYPTO => {
+                let offset = read_varint(plain, &mut pos)?;
+                let len = read_varint(plain, &mut pos)? as usize;
+                let data = plain.get(pos..pos.checked_add(len)?)?;
+                frames.push((offset, data.to_vec()));
+                pos += len;
+            }
+            _ => return None,
+        }
+    }
+    Some(frames)
+}
+
+/// Reassembles the client's CRYPTO stream, which may be spread over several
+/// Initial packets and frames in any order.
+#[derive(Default)]
+pub struct CryptoStream {
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl CryptoStream {
+    pub fn add(&mut self, offset: u64, data: Vec<u8>) {
+        self.chunks.insert(offset, data);
+    }
+
+    /// The bytes received without gaps from the start of the stream.
+    pub fn contiguous(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (&offset, data) in &self.chunks {
+            let offset = offset as usize;
+            if offset > out.len() {
+                break;
+            }
+            if offset + data.len() > out.len() {
+                out.extend_from_slice(&data[out.len() - offset..]);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Protects `payload` as a client Initial for `dcid`, the way a client
+    /// would send it.
+    fn seal_initial(dcid: &[u8], pn: u32, payload: &[u8]) -> Vec<u8> {
+        let keys = InitialKeys::client(dcid).unwrap();
+        let mut packet = vec![0xc3];
+        packet.extend_from_slice(&VERSION_1.to_be_bytes());
+        packet.push(dcid.len() as u8);
+        packet.extend_from_slice(dcid);
+        packet.extend_from_slice(&[0x00, 0x00]);
+        let length = 4 + payload.len() + aead::AES_128_GCM.tag_len();
+        packet.extend_from_slice(&(0x4000 | length as u16).to_be_bytes());
+        let pn_offset = packet.len();
+        packet.extend_from_slice(&pn.to_be_bytes());
+
+        let mut nonce = keys.iv;
+        for (i, b) in (pn as u64).to_be_bytes().iter().enumerate() {
+            nonce[4 + i] ^= b;
+        }
+        let mut sealed = payload.to_vec();
+        keys.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(&packet[..]), &mut sealed)
+            .unwrap();
+        packet.extend_from_slice(&sealed);
+
+        let mask = keys.hp.new_mask(&packet[pn_offset + 4..pn_offset + 4 + SAMPLE_LEN]).unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        for i in 0..4 {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+        packet
+    }
+
+    fn crypto_frame(offset: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![FRAME_CRYPTO as u8, offset, 0x40 | (data.len() >> 8) as u8, data.len() as u8];
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn derives_the_rfc_9001_initial_secrets() {
+        let dcid = hex("8394c8f03e515708");
+        let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT_V1).extract(&dcid);
+        let mut secret = [0u8; 32];
+        expand_label(&initial, b"client in", &mut secret).unwrap();
+        assert_eq!(secret[..], hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea"));
+
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+        let mut key = [0u8; 16];
+        let mut iv = [0u8; 12];
+        let mut hp = [0u8; 16];
+        expand_label(&secret, b"quic key", &mut key).unwrap();
+        expand_label(&secret, b"quic iv", &mut iv).unwrap();
+        expand_label(&secret, b"quic hp", &mut hp).unwrap();
+        assert_eq!(key[..], hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(iv[..], hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(hp[..], hex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+
+    #[test]
+    fn decrypts_a_client_initial() {
+        let dcid = hex("8394c8f03e515708");
+        let mut payload = crypto_frame(0, b"client hello");
+        payload.extend_from_slice(&[FRAME_PING as u8]);
+        payload.resize(1000, FRAME_PADDING as u8);
+        let mut datagram = seal_initial(&dcid, 2, &payload);
+        assert!(is_initial(&datagram));
+
+        let initial = decrypt_initial(&datagram).unwrap();
+        assert_eq!(initial.dcid, dcid);
+        assert_eq!(initial.crypto, vec![(0, b"client hello".to_vec())]);
+
+        let last = datagram.len() - 1;
+        datagram[last] ^= 1;
+        assert!(decrypt_initial(&datagram).is_none());
+        assert!(decrypt_initial(&datagram[..40]).is_none());
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        let dcid = hex("0102030405060708");
+        let mut payload = vec![0x08, 0x00];
+        payload.resize(100, 0);
+        assert!(decrypt_initial(&seal_initial(&dcid, 0, &payload)).is_none());
+
+        let mut handshake = seal_initial(&dcid, 0, &crypto_frame(0, b"hello"));
+        handshake[0] = (handshake[0] & 0xcf) | 0x20;
+        assert!(!is_initial(&handshake));
+        assert!(!is_initial(&[0x40, 0x00, 0x00, 0x00, 0x01, 0x00]));
+        assert!(!is_initial(&[0xc0, 0x6b, 0x33, 0x43, 0xcf, 0x00]));
+    }
+
+    #[test]
+    fn reads_varints() {
+        for (bytes, value) in [
+            ("c2197c5eff14e88c", 151_288_809_941_952_652),
+            ("9d7f3e7d", 494_878_333),
+            ("7bbd", 15_293),
+            ("25", 37),
+        ] {
+            let data = hex(bytes);
+            let mut pos = 0;
+            assert_eq!(read_varint(&data, &mut pos), Some(value));
+            assert_eq!(pos, data.len());
+        }
+        assert_eq!(read_varint(&hex("7b"), &mut 0), None);
+    }
+
+    #[test]
+    fn reassembles_the_crypto_stream() {
+        let mut stream = CryptoStream::default();
+        stream.add(6, b"world".to_vec());
+        assert!(stream.contiguous().is_empty());
+        stream.add(0, b"hello ".to_vec());
+        stream.add(20, b"later".to_vec());
+        assert_eq!(stream.contiguous(), b"hello world");
+        stream.add(3, b"lo wor".to_vec());
+        stream.add(11, b"!!!!!!!!!".to_vec());
+        assert_eq!(stream.contiguous(), b"hello world!!!!!!!!!later");
+    }
+}
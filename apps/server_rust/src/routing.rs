@@ -0,0 +1,414 @@
+//! The routing table given with `--routes`. Each line lists matchers and
+//! ends with the action taken for connections that satisfy all of them:
+//!
+//! ```text
+//! domain:example.com                     fragment(method=sni delay=5)
+//! ip:10.0.0.0/8                          direct
+//! port:25                                reject
+//! client:192.168.1.0/24 domain:video.com via(work)
+//! default                                fragment
+//! ```
+//!
+//! Lines are tried in order and the first match wins. Connections that match
+//! no line get the `default` action; without one they are left to the
+//! blacklist, as are connections routed `via` an upstream. A domain is
+//! resolved before `ip:` matchers look at it.
+//!
+//! Routes decide whether a connection is mangled at all; the rules of
+//! `--rules` and the blacklist only tune how. The fields a `fragment` route
+//! sets win over those of the rule for the domain.
+//!
+//! The upstream is picked when the connection is opened. For a tunnel to an
+//! IP that is before the SNI is known, so `domain:` lines only take effect
+//! for it once the ClientHello has been read: they can then still change
+//! how it is mangled or reject it, but not route it `via` an upstream.
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::strategy::{self, StrategyRule};
+
+/// What is known about a connection when it is routed.
+pub struct Target<'a> {
+    /// The name domain matchers and the blacklist go by, the SNI for an IP.
+    pub domain: &'a str,
+    /// The addresses of the destination, if known.
+    pub ips: Vec<IpAddr>,
+    pub port: u16,
+    pub client: Option<IpAddr>,
+}
+
+impl<'a> Target<'a> {
+    pub fn new(domain: &'a str, host: &str, port: u16, client: &str) -> Self {
+        Self {
+            domain,
+            ips: parse_ip(host).or_else(|| parse_ip(domain)).into_iter().collect(),
+            port,
+            client: client.parse::<SocketAddr>().ok().map(|addr| addr.ip().to_canonical()),
+        }
+    }
+
+    /// Adds the addresses a domain target resolved to.
+    pub fn with_addresses(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.ips.extend(ips);
+        self
+    }
+}
+
+#[derive(Clone)]
+pub enum Action {
+    /// Pass the connection through untouched.
+    Direct,
+    /// Mangle the first flight with the global strategy and these overrides.
+    Fragment(StrategyRule),
+    /// Connect through the named upstream.
+    Via(String),
+    Reject,
+}
+
+/// An address block such as `10.0.0.0/8`; a bare address is a block of one.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr = parse_ip(addr).ok_or_else(|| format!("invalid IP address '{}'", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", value))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Matcher {
+    /// The domain or any of its subdomains.
+    Domain(String),
+    Ip(Cidr),
+    Port(u16, u16),
+    Client(Cidr),
+}
+
+impl Matcher {
+    fn parse(kind: &str, value: &str) -> Result<Self, String> {
+        match kind {
+            "domain" => {
+                let domain = value.trim_end_matches('.').to_lowercase();
+                if domain.is_empty() {
+                    return Err("empty domain".to_string());
+                }
+                Ok(Matcher::Domain(domain))
+            }
+            "ip" => Ok(Matcher::Ip(Cidr::parse(value)?)),
+            "client" => Ok(Matcher::Client(Cidr::parse(value)?)),
+            "port" => {
+                let (low, high) = value.split_once('-').unwrap_or((value, value));
+                match (low.parse::<u16>(), high.parse::<u16>()) {
+                    (Ok(low), Ok(high)) if low <= high => Ok(Matcher::Port(low, high)),
+                    _ => Err(format!("invalid port '{}'", value)),
+                }
+            }
+            other => Err(format!("unknown matcher '{}'", other)),
+        }
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        match self {
+            Matcher::Domain(domain) => {
+                let name = target.domain.trim_end_matches('.').to_lowercase();
+                parse_ip(&name).is_none()
+                    && (name == *domain || name.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.')))
+            }
+            Matcher::Ip(cidr) => target.ips.iter().any(|ip| cidr.contains(*ip)),
+            Matcher::Port(low, high) => (*low..=*high).contains(&target.port),
+            Matcher::Client(cidr) => target.client.is_some_and(|ip| cidr.contains(ip)),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rule {
+    matchers: Vec<Matcher>,
+    action: Action,
+}
+
+#[derive(Clone, Default)]
+pub struct Table {
+    rules: Vec<Rule>,
+    default: Option<Action>,
+}
+
+impl Table {
+    /// Loads a routing file.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let data = fs::read_to_string(path)?;
+        let mut table = Table::default();
+        for (n, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            table.add_line(line).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path, n + 1, err),
+                )
+            })?;
+        }
+        Ok(table)
+    }
+
+    fn add_line(&mut self, line: &str) -> Result<(), String> {
+        if let Some(action) = line.strip_prefix("default ") {
+            if self.default.is_some() {
+                return Err("duplicate default".to_string());
+            }
+            self.default = Some(parse_action(action.trim())?);
+            return Ok(());
+        }
+        let mut matchers = Vec::new();
+        let mut rest = line;
+        loop {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let token = &rest[..end];
+            // Actions are written with parentheses or as a bare word, so a
+            // `kind:value` token is always a matcher.
+            let Some((kind, value)) = token.split_once(':').filter(|_| !token.contains('(')) else {
+                break;
+            };
+            matchers.push(Matcher::parse(kind, value)?);
+            rest = rest[end..].trim_start();
+        }
+        if matchers.is_empty() {
+            return Err(format!("expected a matcher, got '{}'", rest));
+        }
+        if rest.is_empty() {
+            return Err("missing action".to_string());
+        }
+        self.rules.push(Rule {
+            matchers,
+            action: parse_action(rest)?,
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Tells whether some line matches on the destination IP.
+    pub fn matches_ips(&self) -> bool {
+        self.rules
+            .iter()
+            .flat_map(|rule| &rule.matchers)
+            .any(|matcher| matches!(matcher, Matcher::Ip(_)))
+    }
+
+    /// The action of the first rule matching `target`, else the default one.
+    pub fn lookup(&self, target: &Target) -> Option<&Action> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matchers.iter().all(|m| m.matches(target)))
+            .map(|rule| &rule.action)
+            .or(self.default.as_ref())
+    }
+
+    /// Names of the upstreams the table routes to.
+    pub fn upstreams(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .map(|rule| &rule.action)
+            .chain(self.default.as_ref())
+            .filter_map(|action| match action {
+                Action::Via(name) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+}
+
+fn parse_action(value: &str) -> Result<Action, String> {
+    let (name, args) = match value.split_once('(') {
+        Some((name, args)) => {
+            let args = args
+                .strip_suffix(')')
+                .ok_or_else(|| format!("missing ')' in '{}'", value))?;
+            (name.trim(), Some(args.trim()))
+        }
+        None => (value, None),
+    };
+    match (name, args) {
+        ("direct", None) => Ok(Action::Direct),
+        ("reject", None) => Ok(Action::Reject),
+        ("fragment", None) => Ok(Action::Fragment(StrategyRule::default())),
+        ("fragment", Some(args)) => Ok(Action::Fragment(strategy::parse_strategy_rule(args.split_whitespace())?)),
+        ("via", Some(upstream)) if !upstream.is_empty() && !upstream.contains(char::is_whitespace) => {
+            Ok(Action::Via(upstream.to_string()))
+        }
+        _ => Err(format!("invalid action '{}'", value)),
+    }
+}
+
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    value.trim_matches(['[', ']']).parse::<IpAddr>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(lines: &[&str]) -> Table {
+        let mut table = Table::default();
+        for line in lines {
+            table.add_line(line).unwrap();
+        }
+        table
+    }
+
+    fn action_name(action: Option<&Action>) -> &str {
+        match action {
+            None => "none",
+            Some(Action::Direct) => "direct",
+            Some(Action::Fragment(_)) => "fragment",
+            Some(Action::Via(name)) => name,
+            Some(Action::Reject) => "reject",
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matches_prefix() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.255.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+
+        let net = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.7")));
+        let host = Cidr::parse("[::1]").unwrap();
+        assert!(host.contains(ip("::1")) && !host.contains(ip("::2")));
+    }
+
+    #[test]
+    fn cidr_rejects_bad_input() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn port_ranges() {
+        let table = table(&["port:8000-8080 reject", "port:25 direct"]);
+        let lookup = |port| action_name(table.lookup(&Target::new("a.com", "a.com", port, "1.2.3.4:5"))).to_string();
+        assert_eq!(lookup(8000), "reject");
+        assert_eq!(lookup(8080), "reject");
+        assert_eq!(lookup(8081), "none");
+        assert_eq!(lookup(25), "direct");
+        assert_eq!(lookup(26), "none");
+
+        let mut bad = Table::default();
+        assert!(bad.add_line("port:90-80 reject").is_err());
+        assert!(bad.add_line("port:70000 reject").is_err());
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let table = table(&[
+            "domain:video.com port:443 via(work)",
+            "domain:video.com reject",
+            "domain:com direct",
+        ]);
+        let lookup = |domain, port| action_name(table.lookup(&Target::new(domain, domain, port, ""))).to_string();
+        assert_eq!(lookup("cdn.video.com", 443), "work");
+        assert_eq!(lookup("video.com", 80), "reject");
+        assert_eq!(lookup("example.com", 443), "direct");
+        assert_eq!(lookup("example.org", 443), "none");
+    }
+
+    #[test]
+    fn domain_matches_whole_labels() {
+        let table = table(&["domain:example.com. reject"]);
+        let lookup = |domain| action_name(table.lookup(&Target::new(domain, domain, 443, ""))).to_string();
+        assert_eq!(lookup("example.com"), "reject");
+        assert_eq!(lookup("WWW.Example.com."), "reject");
+        assert_eq!(lookup("badexample.com"), "none");
+        assert_eq!(lookup("93.184.216.34"), "none");
+    }
+
+    #[test]
+    fn default_applies_when_nothing_matches() {
+        let table = table(&["client:192.168.1.0/24 direct", "default fragment(method=sni)"]);
+        let from = |client| action_name(table.lookup(&Target::new("a.com", "a.com", 443, client))).to_string();
+        assert_eq!(from("192.168.1.20:5000"), "direct");
+        assert_eq!(from("[::ffff:192.168.1.20]:5000"), "direct");
+        assert_eq!(from("10.0.0.1:5000"), "fragment");
+        assert_eq!(from("not an address"), "fragment");
+
+        let mut duplicate = table.clone();
+        assert!(duplicate.add_line("default direct").is_err());
+    }
+
+    #[test]
+    fn ip_matchers_see_resolved_addresses() {
+        let table = table(&["ip:10.0.0.0/8 direct", "default reject"]);
+        let name = Target::new("intranet.example", "intranet.example", 443, "");
+        assert_eq!(action_name(table.lookup(&name)), "reject");
+        let resolved = Target::new("intranet.example", "intranet.example", 443, "").with_addresses([ip("10.4.0.1")]);
+        assert_eq!(action_name(table.lookup(&resolved)), "direct");
+        let literal = Target::new("[::1]", "10.9.9.9", 443, "");
+        assert_eq!(action_name(table.lookup(&literal)), "direct");
+        assert!(table.matches_ips());
+    }
+
+    #[test]
+    fn parses_actions() {
+        assert!(matches!(parse_action("direct"), Ok(Action::Direct)));
+        assert!(matches!(parse_action("via(work)"), Ok(Action::Via(name)) if name == "work"));
+        assert!(parse_action("via()").is_err());
+        assert!(parse_action("via(a b)").is_err());
+        assert!(parse_action("fragment(method=sni").is_err());
+        assert!(parse_action("allow").is_err());
+
+        let mut table = Table::default();
+        assert!(table.add_line("domain:a.com").is_err());
+        assert!(table.add_line("direct").is_err());
+        assert!(table.add_line("host:a.com direct").is_err());
+        assert_eq!(table.len(), 0);
+        assert_eq!(table.upstreams().count(), 0);
+    }
+}
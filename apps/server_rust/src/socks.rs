@@ -0,0 +1,411 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+
+pub const VERSION: u8 = 0x05;
+pub const VERSION_4: u8 = 0x04;
+
+pub const AUTH_NONE: u8 = 0x00;
+pub const AUTH_PASSWORD: u8 = 0x02;
+pub const AUTH_UNACCEPTABLE: u8 = 0xff;
+/// Version of the username/password subnegotiation (RFC 1929).
+pub const PASSWORD_VERSION: u8 = 0x01;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+pub const REPLY4_GRANTED: u8 = 0x5a;
+pub const REPLY4_REJECTED: u8 = 0x5b;
+/// The userid does not match, reported as an identd mismatch.
+pub const REPLY4_BAD_USER: u8 = 0x5d;
+/// Longest userid or SOCKS4a host name accepted.
+const MAX_V4_STRING: usize = 255;
+
+#[derive(Clone, Debug)]
+pub enum Address {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl Address {
+    pub fn host(&self) -> String {
+        match self {
+            Address::Ip(ip) => ip.to_string(),
+            Address::Domain(domain) => domain.clone(),
+        }
+    }
+}
+
+pub struct Request {
+    pub command: u8,
+    pub address: Address,
+    pub port: u16,
+}
+
+/// Reads the client greeting and returns the offered auth methods.
+pub async fn read_greeting<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(invalid("not a SOCKS5 greeting"));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    reader.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
+/// Reads an RFC 1929 username/password request.
+pub async fn read_credentials<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(String, String)> {
+    if reader.read_u8().await? != PASSWORD_VERSION {
+        return Err(invalid("bad username/password version"));
+    }
+    let user = read_string(reader).await?;
+    let pass = read_string(reader).await?;
+    Ok((user, pass))
+}
+
+/// Reads the request that follows authentication. An unknown address type
+/// is reported as `ErrorKind::Unsupported`.
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Request> {
+    let mut head = [0u8; 4];
+    reader.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(invalid("bad request version"));
+    }
+    let address = match head[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets).await?;
+            Address::Ip(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets).await?;
+            Address::Ip(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        ATYP_DOMAIN => Address::Domain(read_string(reader).await?),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unsupported address type",
+            ))
+        }
+    };
+    let port = reader.read_u16().await?;
+    Ok(Request {
+        command: head[1],
+        address,
+        port,
+    })
+}
+
+/// Reads a SOCKS4 request and its userid. A destination of 0.0.0.x with a
+/// non-zero x is the SOCKS4a form, with the host name after the userid.
+pub async fn read_request_v4<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<(Request, String)> {
+    let mut head = [0u8; 8];
+    reader.read_exact(&mut head).await?;
+    if head[0] != VERSION_4 {
+        return Err(invalid("not a SOCKS4 request"));
+    }
+    let port = u16::from_be_bytes([head[2], head[3]]);
+    let ip = Ipv4Addr::new(head[4], head[5], head[6], head[7]);
+    let user = read_nul_string(reader).await?;
+    let address = if head[4..7] == [0, 0, 0] && head[7] != 0 {
+        Address::Domain(read_nul_string(reader).await?)
+    } else {
+        Address::Ip(IpAddr::V4(ip))
+    };
+    Ok((
+        Request {
+            command: head[1],
+            address,
+            port,
+        },
+        user,
+    ))
+}
+
+/// Encodes a SOCKS4 reply. Only an IPv4 bound address fits; others are
+/// sent as zeros.
+pub fn reply_v4(code: u8, bound: SocketAddr) -> [u8; 8] {
+    let ip = match bound.ip() {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(_) => [0; 4],
+    };
+    let port = bound.port().to_be_bytes();
+    [0x00, code, port[0], port[1], ip[0], ip[1], ip[2], ip[3]]
+}
+
+/// Encodes a reply carrying `bound` as the bound address.
+pub fn reply(code: u8, bound: SocketAddr) -> Vec<u8> {
+    let mut out = vec![VERSION, code, 0x00];
+    push_socket_addr(&mut out, bound);
+    out
+}
+
+/// Encodes a request for `address`, as sent to an upstream SOCKS5 proxy.
+pub fn request(command: u8, address: &Address, port: u16) -> io::Result<Vec<u8>> {
+    let mut out = vec![VERSION, command, 0x00];
+    match address {
+        Address::Ip(ip) => {
+            push_socket_addr(&mut out, SocketAddr::new(*ip, port));
+            return Ok(out);
+        }
+        Address::Domain(domain) => {
+            let len = u8::try_from(domain.len()).map_err(|_| invalid("host name is too long"))?;
+            out.push(ATYP_DOMAIN);
+            out.push(len);
+            out.extend_from_slice(domain.as_bytes());
+        }
+    }
+    out.extend_from_slice(&port.to_be_bytes());
+    Ok(out)
+}
+
+/// Encodes an RFC 1929 username/password request.
+pub fn credentials(user: &str, pass: &str) -> io::Result<Vec<u8>> {
+    let user_len = u8::try_from(user.len()).map_err(|_| invalid("username is too long"))?;
+    let pass_len = u8::try_from(pass.len()).map_err(|_| invalid("password is too long"))?;
+    let mut out = vec![PASSWORD_VERSION, user_len];
+    out.extend_from_slice(user.as_bytes());
+    out.push(pass_len);
+    out.extend_from_slice(pass.as_bytes());
+    Ok(out)
+}
+
+/// Reads the reply of an upstream proxy. It has the layout of a request,
+/// with the reply code where the command would be.
+pub async fn read_reply<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(u8, Address, u16)> {
+    let reply = read_request(reader).await?;
+    Ok((reply.command, reply.address, reply.port))
+}
+
+/// Turns a failure reply from an upstream proxy into the matching error.
+pub fn reply_error(code: u8) -> io::Error {
+    let (kind, what) = match code {
+        REPLY_NETWORK_UNREACHABLE => (io::ErrorKind::NetworkUnreachable, "network unreachable"),
+        REPLY_HOST_UNREACHABLE => (io::ErrorKind::HostUnreachable, "host unreachable"),
+        REPLY_CONNECTION_REFUSED => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        REPLY_TTL_EXPIRED => (io::ErrorKind::TimedOut, "TTL expired"),
+        REPLY_NOT_ALLOWED => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        REPLY_COMMAND_NOT_SUPPORTED => (io::ErrorKind::Unsupported, "command not supported"),
+        REPLY_ADDRESS_NOT_SUPPORTED => (io::ErrorKind::Unsupported, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy replied {:#04x}: {}", code, what))
+}
+
+/// Splits a datagram received on a UDP ASSOCIATE relay into its
+/// destination and payload. Fragmented datagrams are not supported.
+pub fn parse_udp_datagram(datagram: &[u8]) -> Option<(Address, u16, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    let (address, pos) = match datagram[3] {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+            (Address::Ip(IpAddr::V4(Ipv4Addr::from(octets))), 8)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+            (Address::Ip(IpAddr::V6(Ipv6Addr::from(octets))), 20)
+        }
+        ATYP_DOMAIN => {
+            let len = *datagram.get(4)? as usize;
+            let name = std::str::from_utf8(datagram.get(5..5 + len)?).ok()?;
+            (Address::Domain(name.to_string()), 5 + len)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([*datagram.get(pos)?, *datagram.get(pos + 1)?]);
+    Some((address, port, &datagram[pos + 2..]))
+}
+
+/// Wraps `payload` received from `from` for delivery to the client.
+pub fn udp_datagram(from: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 22);
+    out.extend_from_slice(&[0x00, 0x00, 0x00]);
+    push_socket_addr(&mut out, from);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn push_socket_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Picks the reply code that best describes a failed connect.
+pub fn connect_error_reply(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::PermissionDenied => REPLY_NOT_ALLOWED,
+        io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut | io::ErrorKind::NotFound => {
+            REPLY_HOST_UNREACHABLE
+        }
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+async fn read_string<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let len = reader.read_u8().await? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|_| invalid("string is not UTF-8"))
+}
+
+async fn read_nul_string<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut buf = Vec::new();
+    reader.take(MAX_V4_STRING as u64 + 1).read_until(0, &mut buf).await?;
+    match buf.pop() {
+        Some(0) => {}
+        _ if buf.len() >= MAX_V4_STRING => return Err(invalid("string is too long")),
+        _ => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "string is not terminated")),
+    }
+    String::from_utf8(buf).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_greeting_credentials_and_request() {
+        let mut data: &[u8] = &[VERSION, 2, AUTH_NONE, AUTH_PASSWORD];
+        assert_eq!(read_greeting(&mut data).await.unwrap(), vec![AUTH_NONE, AUTH_PASSWORD]);
+        let mut data: &[u8] = &[VERSION_4, 1, 0];
+        assert_eq!(read_greeting(&mut data).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let encoded = credentials("user", "pässword").unwrap();
+        let (user, pass) = read_credentials(&mut encoded.as_slice()).await.unwrap();
+        assert_eq!((user.as_str(), pass.as_str()), ("user", "pässword"));
+        assert!(credentials(&"u".repeat(256), "").is_err());
+
+        for address in [
+            Address::Ip("192.0.2.1".parse().unwrap()),
+            Address::Ip("2001:db8::1".parse().unwrap()),
+            Address::Domain("example.com".to_string()),
+        ] {
+            let encoded = request(CMD_CONNECT, &address, 443).unwrap();
+            let request = read_request(&mut encoded.as_slice()).await.unwrap();
+            assert_eq!(request.command, CMD_CONNECT);
+            assert_eq!(request.address.host(), address.host());
+            assert_eq!(request.port, 443);
+        }
+        assert!(request(CMD_CONNECT, &Address::Domain("a".repeat(256)), 443).is_err());
+
+        let mut data: &[u8] = &[VERSION, CMD_CONNECT, 0, 0x05, 1, 2, 3, 4, 0, 80];
+        assert_eq!(read_request(&mut data).await.err().map(|err| err.kind()), Some(io::ErrorKind::Unsupported));
+        let mut data: &[u8] = &[VERSION, CMD_CONNECT, 0, ATYP_IPV4, 1, 2];
+        assert_eq!(read_request(&mut data).await.err().map(|err| err.kind()), Some(io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn encodes_replies() {
+        let bound: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+        assert_eq!(reply(REPLY_SUCCEEDED, bound), [VERSION, 0, 0, ATYP_IPV4, 192, 0, 2, 1, 0x04, 0x38]);
+        let bound: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let encoded = reply(REPLY_HOST_UNREACHABLE, bound);
+        assert_eq!(encoded.len(), 22);
+        let (code, address, port) = read_reply(&mut encoded.as_slice()).await.unwrap();
+        assert_eq!((code, address.host(), port), (REPLY_HOST_UNREACHABLE, "2001:db8::1".to_string(), 53));
+    }
+
+    #[test]
+    fn wraps_and_unwraps_udp_datagrams() {
+        let from: SocketAddr = "198.51.100.7:443".parse().unwrap();
+        let datagram = udp_datagram(from, b"payload");
+        let (address, port, payload) = parse_udp_datagram(&datagram).unwrap();
+        assert_eq!((address.host(), port, payload), (from.ip().to_string(), 443, &b"payload"[..]));
+
+        let mut named = vec![0, 0, 0, ATYP_DOMAIN, 11];
+        named.extend_from_slice(b"example.com\x01\xbbdata");
+        let (address, port, payload) = parse_udp_datagram(&named).unwrap();
+        assert_eq!((address.host(), port, payload), ("example.com".to_string(), 443, &b"data"[..]));
+
+        let mut fragment = datagram.clone();
+        fragment[2] = 1;
+        assert!(parse_udp_datagram(&fragment).is_none());
+        assert!(parse_udp_datagram(&datagram[..6]).is_none());
+        assert!(parse_udp_datagram(&named[..9]).is_none());
+    }
+
+    #[test]
+    fn maps_errors_to_reply_codes_and_back() {
+        for code in [
+            REPLY_NETWORK_UNREACHABLE,
+            REPLY_HOST_UNREACHABLE,
+            REPLY_CONNECTION_REFUSED,
+            REPLY_NOT_ALLOWED,
+        ] {
+            assert_eq!(connect_error_reply(&reply_error(code)), code);
+        }
+        assert_eq!(reply_error(REPLY_TTL_EXPIRED).kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reply_error(0x42).kind(), io::ErrorKind::Other);
+        let missing = io::Error::new(io::ErrorKind::NotFound, "no such host");
+        assert_eq!(connect_error_reply(&missing), REPLY_HOST_UNREACHABLE);
+        assert_eq!(connect_error_reply(&io::Error::other("boom")), REPLY_GENERAL_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn reads_socks4_and_socks4a_requests() {
+        let mut data: &[u8] = b"\x04\x01\x01\xbb\xc0\x00\x02\x01alice\x00";
+        let (request, user) = read_request_v4(&mut data).await.unwrap();
+        assert_eq!((request.command, request.address.host(), request.port), (CMD_CONNECT, "192.0.2.1".to_string(), 443));
+        assert_eq!(user, "alice");
+
+        let mut data: &[u8] = b"\x04\x01\x00\x50\x00\x00\x00\x07\x00example.com\x00";
+        let (request, user) = read_request_v4(&mut data).await.unwrap();
+        assert_eq!((request.address.host(), request.port, user.as_str()), ("example.com".to_string(), 80, ""));
+
+        let mut long = b"\x04\x01\x00\x50\xc0\x00\x02\x01".to_vec();
+        long.extend(std::iter::repeat_n(b'a', MAX_V4_STRING + 10));
+        long.push(0);
+        let err = read_request_v4(&mut long.as_slice()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut data: &[u8] = b"\x04\x01\x00\x50\xc0\x00\x02\x01bob";
+        let err = read_request_v4(&mut data).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let mut data: &[u8] = b"\x05\x01\x00\x50\xc0\x00\x02\x01\x00";
+        let err = read_request_v4(&mut data).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encodes_socks4_replies() {
+        let bound: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+        assert_eq!(reply_v4(REPLY4_GRANTED, bound), [0, REPLY4_GRANTED, 0x04, 0x38, 192, 0, 2, 1]);
+        let bound: SocketAddr = "[2001:db8::1]:1080".parse().unwrap();
+        assert_eq!(reply_v4(REPLY4_REJECTED, bound), [0, REPLY4_REJECTED, 0x04, 0x38, 0, 0, 0, 0]);
+        assert_eq!(reply_v4(REPLY4_BAD_USER, bound)[..2], [0, 0x5d]);
+    }
+}
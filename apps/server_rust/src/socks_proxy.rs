@@ -0,0 +1,416 @@
+//! SOCKS4, SOCKS4a and SOCKS5 clients on the proxy port: authentication,
+//! CONNECT tunnels and the UDP relay of SOCKS5 UDP ASSOCIATE.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use chrono::Local;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use crate::{check_credentials, check_user, is_auth_enabled, quic, routing, socks, tls, ConnectionHandler, ConnectionInfo};
+use crate::strategy::FragmentMethod;
+
+/// QUIC handshakes tracked per UDP association before the oldest are forgotten.
+const MAX_QUIC_HANDSHAKES: usize = 64;
+
+/// Per-association state for spotting QUIC handshakes to targeted hosts.
+#[derive(Default)]
+struct QuicFilter {
+    /// CRYPTO streams of handshakes whose ClientHello is still incomplete,
+    /// by destination connection ID.
+    streams: HashMap<Vec<u8>, quic::CryptoStream>,
+    /// Whether the Initials of an already judged handshake are dropped.
+    verdicts: HashMap<Vec<u8>, bool>,
+}
+
+impl ConnectionHandler {
+    /// Serves a SOCKS5 client: method negotiation, RFC 1929 authentication
+    /// when the proxy requires it, then a CONNECT handled like an HTTP one.
+    pub async fn handle_socks5_connection(self: &Arc<Self>, mut client: TcpStream, peer: &str) {
+        let result = self.socks5_handshake(&mut client).await;
+        let request = match result {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(err) => {
+                let _ = self.logger.log_error(&format!("{}: SOCKS5: {}", peer, err)).await;
+                return;
+            }
+        };
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        // UDP cannot follow TCP through the upstream proxy, and relaying it
+        // directly would leak around it.
+        if request.command == socks::CMD_UDP_ASSOCIATE && self.config.upstream.is_none() {
+            self.handle_socks5_udp(client, peer).await;
+            return;
+        }
+        if request.command != socks::CMD_CONNECT {
+            let _ = client
+                .write_all(&socks::reply(socks::REPLY_COMMAND_NOT_SUPPORTED, unspecified))
+                .await;
+            return;
+        }
+
+        self.handle_socks_connect(client, Vec::new(), peer, request, "SOCKS5", socks::reply)
+            .await;
+    }
+
+    /// Serves a SOCKS4 or SOCKS4a client. SOCKS4 has no password, so while
+    /// authentication is on only the userid is checked.
+    pub async fn handle_socks4_connection(self: &Arc<Self>, client: TcpStream, peer: &str) {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let mut reader = BufReader::new(client);
+        let read = socks::read_request_v4(&mut reader).await;
+        // A client that did not wait for the reply has its bytes passed on.
+        let early = reader.buffer().to_vec();
+        let mut client = reader.into_inner();
+        let (request, user) = match read {
+            Ok(read) => read,
+            Err(err) => {
+                let _ = self.logger.log_error(&format!("{}: SOCKS4: {}", peer, err)).await;
+                return;
+            }
+        };
+        if is_auth_enabled(&self.config) && !check_user(&self.config, &user) {
+            let _ = client
+                .write_all(&socks::reply_v4(socks::REPLY4_BAD_USER, unspecified))
+                .await;
+            self.statistics.increment_total_connections().await;
+            self.statistics.increment_error_connections().await;
+            self.logger.log_error("Proxy authentication required").await;
+            return;
+        }
+        if request.command != socks::CMD_CONNECT {
+            let _ = client
+                .write_all(&socks::reply_v4(socks::REPLY4_REJECTED, unspecified))
+                .await;
+            return;
+        }
+        self.handle_socks_connect(client, early, peer, request, "SOCKS4", |code, bound| {
+            let code = if code == socks::REPLY_SUCCEEDED {
+                socks::REPLY4_GRANTED
+            } else {
+                socks::REPLY4_REJECTED
+            };
+            socks::reply_v4(code, bound).to_vec()
+        })
+        .await;
+    }
+
+    /// Connects to the target of a SOCKS CONNECT and runs the tunnel.
+    /// `encode_reply` turns a SOCKS5 reply code into the client's dialect.
+    async fn handle_socks_connect(
+        self: &Arc<Self>,
+        mut client: TcpStream,
+        early: Vec<u8>,
+        peer: &str,
+        request: socks::Request,
+        protocol: &str,
+        encode_reply: impl Fn(u8, SocketAddr) -> Vec<u8>,
+    ) {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let host = request.address.host();
+        let port = request.port;
+        if let socks::Address::Domain(domain) = &request.address {
+            self.blacklist_manager.check_domain(domain).await;
+        }
+        let conn_info = ConnectionInfo {
+            src_ip: peer.to_string(),
+            dst_domain: host.clone(),
+            method: protocol.to_string(),
+            start_time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            traffic_in: 0,
+            traffic_out: 0,
+            endpoint: None,
+        };
+        self.active_connections.lock().await.insert(peer.to_string(), conn_info);
+
+        let dst = match self.connect_remote(&host, port, peer).await {
+            Ok(s) => s,
+            Err(err) => {
+                let reply = encode_reply(socks::connect_error_reply(&err), unspecified);
+                let _ = client.write_all(&reply).await;
+                let _ = self.logger.log_error(&format!("{}: {}", host, err)).await;
+                self.active_connections.lock().await.remove(peer);
+                return;
+            }
+        };
+        let bound = dst.local_addr().unwrap_or(unspecified);
+        let reply = encode_reply(socks::REPLY_SUCCEEDED, bound);
+        if client.write_all(&reply).await.is_err() {
+            self.active_connections.lock().await.remove(peer);
+            return;
+        }
+        self.statistics.update_traffic(reply.len() as u64, 0).await;
+        self.update_conn_in(peer, reply.len() as u64).await;
+
+        self.handle_tunnel(client, early, dst, &host, port, peer).await;
+    }
+
+    /// Relays datagrams for a UDP ASSOCIATE until its control connection
+    /// closes. QUIC Initials whose SNI is targeted are dropped, so browsers
+    /// fall back to TCP, where the ClientHello can be fragmented.
+    async fn handle_socks5_udp(&self, mut client: TcpStream, peer: &str) {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let (Ok(local), Ok(client_addr)) = (client.local_addr(), client.peer_addr()) else {
+            return;
+        };
+        let relay = match UdpSocket::bind(SocketAddr::new(local.ip(), 0)).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                let _ = client
+                    .write_all(&socks::reply(socks::REPLY_GENERAL_FAILURE, unspecified))
+                    .await;
+                let _ = self.logger.log_error(&format!("{}: UDP relay: {}", peer, err)).await;
+                return;
+            }
+        };
+        let bound = relay.local_addr().unwrap_or(unspecified);
+        if client
+            .write_all(&socks::reply(socks::REPLY_SUCCEEDED, bound))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let conn_info = ConnectionInfo {
+            src_ip: peer.to_string(),
+            dst_domain: "udp".to_string(),
+            method: "SOCKS5".to_string(),
+            start_time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            traffic_in: 0,
+            traffic_out: 0,
+            endpoint: None,
+        };
+        self.active_connections.lock().await.insert(peer.to_string(), conn_info);
+
+        let mut filter = QuicFilter::default();
+        let mut resolved: HashMap<String, IpAddr> = HashMap::new();
+        let mut client_udp: Option<SocketAddr> = None;
+        let (mut outbound_v4, mut outbound_v6): (Option<UdpSocket>, Option<UdpSocket>) = (None, None);
+        let mut control = [0u8; 64];
+        let mut up = vec![0u8; 65535];
+        let mut down_v4 = vec![0u8; 65535];
+        let mut down_v6 = vec![0u8; 65535];
+
+        loop {
+            let down = tokio::select! {
+                read = client.read(&mut control) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        break;
+                    }
+                    continue;
+                }
+                received = relay.recv_from(&mut up) => {
+                    let Ok((n, from)) = received else {
+                        continue;
+                    };
+                    // Only the client that opened the association may use it.
+                    if from.ip() != client_addr.ip() {
+                        continue;
+                    }
+                    client_udp = Some(from);
+                    let Some((address, port, payload)) = socks::parse_udp_datagram(&up[..n]) else {
+                        continue;
+                    };
+                    let name = match &address {
+                        socks::Address::Ip(ip) => ip.to_string(),
+                        socks::Address::Domain(domain) => domain.clone(),
+                    };
+                    let ip = match address {
+                        socks::Address::Ip(ip) => ip,
+                        socks::Address::Domain(domain) => match resolved.get(&domain) {
+                            Some(ip) => *ip,
+                            None => match self
+                                .config
+                                .resolver
+                                .lookup(&domain, port)
+                                .await
+                                .ok()
+                                .and_then(|addrs| addrs.into_iter().next())
+                            {
+                                Some(addr) => {
+                                    resolved.insert(domain, addr.ip());
+                                    addr.ip()
+                                }
+                                None => continue,
+                            },
+                        },
+                    };
+                    let target = routing::Target::new(&name, &ip.to_string(), port, peer);
+                    if !self.relays_udp(&target) {
+                        continue;
+                    }
+                    if self.filter_quic(&mut filter, payload, SocketAddr::new(ip, port), peer).await {
+                        continue;
+                    }
+                    let outbound = if ip.is_ipv4() { &mut outbound_v4 } else { &mut outbound_v6 };
+                    if outbound.is_none() {
+                        *outbound = bind_outbound_udp(&self.config.out_host, ip.is_ipv6()).await.ok();
+                    }
+                    if let Some(socket) = outbound {
+                        if socket.send_to(payload, SocketAddr::new(ip, port)).await.is_ok() {
+                            self.statistics.update_traffic(0, payload.len() as u64).await;
+                            self.update_conn_out(peer, payload.len() as u64).await;
+                        }
+                    }
+                    continue;
+                }
+                received = recv_from_optional(&outbound_v4, &mut down_v4) => {
+                    received.map(|(n, from)| socks::udp_datagram(from, &down_v4[..n]))
+                }
+                received = recv_from_optional(&outbound_v6, &mut down_v6) => {
+                    received.map(|(n, from)| socks::udp_datagram(from, &down_v6[..n]))
+                }
+            };
+            if let (Ok(datagram), Some(to)) = (down, client_udp) {
+                if relay.send_to(&datagram, to).await.is_ok() {
+                    self.statistics.update_traffic(datagram.len() as u64, 0).await;
+                    self.update_conn_in(peer, datagram.len() as u64).await;
+                }
+            }
+        }
+
+        let info = self.active_connections.lock().await.remove(peer);
+        if let Some(info) = info {
+            self.logger.log_access(&info.access_line()).await;
+        }
+    }
+
+    /// Tells whether datagrams to `target` may be relayed. Like UDP
+    /// ASSOCIATE as a whole under a default upstream, those routed `via`
+    /// an upstream are dropped, since relaying them directly would leak
+    /// around it; rejected ones are dropped too.
+    fn relays_udp(&self, target: &routing::Target) -> bool {
+        !matches!(
+            self.blacklist_manager.routes.lookup(target),
+            Some(routing::Action::Via(_) | routing::Action::Reject)
+        )
+    }
+
+    /// Tells whether `payload` is a QUIC Initial of a handshake to a
+    /// targeted host. A ClientHello spread over several Initials is judged
+    /// once complete; the rest of that handshake then shares the verdict.
+    async fn filter_quic(&self, filter: &mut QuicFilter, payload: &[u8], to: SocketAddr, peer: &str) -> bool {
+        let Some(initial) = quic::decrypt_initial(payload) else {
+            return false;
+        };
+        if let Some(&drop) = filter.verdicts.get(&initial.dcid) {
+            return drop;
+        }
+        if filter.streams.len() >= MAX_QUIC_HANDSHAKES {
+            filter.streams.clear();
+        }
+        let stream = filter.streams.entry(initial.dcid.clone()).or_default();
+        for (offset, data) in initial.crypto {
+            stream.add(offset, data);
+        }
+        let hello = stream.contiguous();
+        match tls::handshake_length(&hello) {
+            Ok(len) if hello.len() >= len => {}
+            Ok(_) | Err(tls::ParseError::Incomplete) => return false,
+            Err(_) => {}
+        }
+        filter.streams.remove(&initial.dcid);
+
+        let host = tls::parse_client_hello(&hello)
+            .ok()
+            .and_then(|hello| hello.sni)
+            .map(|sni| sni.host);
+        let drop = match &host {
+            Some(host) => {
+                let target = routing::Target::new(host, &to.ip().to_string(), to.port(), peer);
+                matches!(self.blacklist_manager.routes.lookup(&target), Some(routing::Action::Reject))
+                    || self.blacklist_manager.strategy_for(&target).await.method != FragmentMethod::None
+            }
+            None => false,
+        };
+        if filter.verdicts.len() >= MAX_QUIC_HANDSHAKES {
+            filter.verdicts.clear();
+        }
+        filter.verdicts.insert(initial.dcid, drop);
+
+        self.statistics.increment_total_connections().await;
+        if drop {
+            self.statistics.increment_blocked_connections().await;
+            let _ = self
+                .logger
+                .log_info(&format!(
+                    "{}: dropped QUIC Initial to force a TCP fallback",
+                    host.unwrap_or_default()
+                ))
+                .await;
+        } else {
+            self.statistics.increment_allowed_connections().await;
+        }
+        drop
+    }
+
+    /// Negotiates the auth method and reads the request. `None` means the
+    /// client was turned away and has already been answered.
+    async fn socks5_handshake(&self, client: &mut TcpStream) -> io::Result<Option<socks::Request>> {
+        let methods = socks::read_greeting(client).await?;
+        let method = if is_auth_enabled(&self.config) {
+            socks::AUTH_PASSWORD
+        } else {
+            socks::AUTH_NONE
+        };
+        if !methods.contains(&method) {
+            client
+                .write_all(&[socks::VERSION, socks::AUTH_UNACCEPTABLE])
+                .await?;
+            return Ok(None);
+        }
+        client.write_all(&[socks::VERSION, method]).await?;
+
+        if method == socks::AUTH_PASSWORD {
+            let (user, pass) = socks::read_credentials(client).await?;
+            if !check_credentials(&self.config, &user, &pass) {
+                client.write_all(&[socks::PASSWORD_VERSION, 0x01]).await?;
+                self.statistics.increment_total_connections().await;
+                self.statistics.increment_error_connections().await;
+                self.logger.log_error("Proxy authentication required").await;
+                return Ok(None);
+            }
+            client.write_all(&[socks::PASSWORD_VERSION, 0x00]).await?;
+        }
+
+        match socks::read_request(client).await {
+            Ok(request) => Ok(Some(request)),
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+                client
+                    .write_all(&socks::reply(socks::REPLY_ADDRESS_NOT_SUPPORTED, unspecified))
+                    .await?;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Binds a UDP socket for relaying to IPv4 or IPv6 destinations, on the
+/// --out-host address when one is set.
+async fn bind_outbound_udp(out_host: &Option<String>, ipv6: bool) -> io::Result<UdpSocket> {
+    let local = match out_host {
+        Some(out) => lookup_host((out.as_str(), 0))
+            .await?
+            .find(|addr| addr.is_ipv6() == ipv6)
+            .map(|addr| addr.ip()),
+        None => None,
+    };
+    let unspecified = if ipv6 {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    UdpSocket::bind(SocketAddr::new(local.unwrap_or(unspecified), 0)).await
+}
+
+/// Receives from `socket`, or waits forever if it has not been bound yet.
+async fn recv_from_optional(socket: &Option<UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,1040 @@
+//! How the first flight to a targeted domain is mangled: the strategy
+//! types and rule files, the cache of methods discovered per domain, and
+//! the handlers that send the ClientHello, retry it and discover what works.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use chrono::Local;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+use crate::{rejected_error, routing, tls, upstream, ConnectionHandler, DomainMatching, DEFAULT_FAKE_TTL};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::desync;
+use crate::fragment::{fragment_rng, prepare_client_hello, InitialTlsData};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const HOP_PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+const STRATEGY_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long the server has to answer a ClientHello before the pipes take over.
+const FIRST_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before probing again a domain none of the methods worked for.
+const DISCOVERY_RETRY: Duration = Duration::from_secs(10 * 60);
+
+/// Methods tried, in order, when discovering the strategy for a domain.
+const DISCOVERY_METHODS: &[FragmentMethod] = &[
+    FragmentMethod::None,
+    FragmentMethod::Random,
+    FragmentMethod::Sni,
+    FragmentMethod::TcpSplit,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    FragmentMethod::Fake,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FragmentMethod {
+    /// Forward the ClientHello untouched.
+    None,
+    Random,
+    Sni,
+    /// Keep the ClientHello in one record and only split it into TCP segments.
+    TcpSplit,
+    /// Like `Sni`, preceded by a low-TTL decoy ClientHello (Linux only).
+    Fake,
+}
+
+/// How the first flight to a targeted domain gets mangled.
+#[derive(Clone)]
+pub struct Strategy {
+    pub method: FragmentMethod,
+    pub tcp_split: Vec<SplitPosition>,
+    pub timing: FragmentTiming,
+    /// Version of the fragment records; `None` keeps the method's default.
+    pub record_version: Option<RecordVersion>,
+    pub layout: FragmentLayout,
+}
+
+impl Strategy {
+    pub fn direct() -> Self {
+        Self {
+            method: FragmentMethod::None,
+            tcp_split: Vec::new(),
+            timing: FragmentTiming::default(),
+            record_version: None,
+            layout: FragmentLayout::default(),
+        }
+    }
+}
+
+/// Per-domain overrides of the global `Strategy`.
+#[derive(Clone, Default)]
+pub struct StrategyRule {
+    pub method: Option<FragmentMethod>,
+    tcp_split: Option<Vec<SplitPosition>>,
+    pub delay: Option<Duration>,
+    pub jitter: Option<Duration>,
+    record_version: Option<RecordVersion>,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    max_fragments: Option<usize>,
+    window: Option<usize>,
+}
+
+impl StrategyRule {
+    pub fn apply(&self, base: &Strategy) -> Strategy {
+        Strategy {
+            method: self.method.unwrap_or(base.method),
+            tcp_split: self.tcp_split.clone().unwrap_or_else(|| base.tcp_split.clone()),
+            timing: FragmentTiming {
+                delay: self.delay.unwrap_or(base.timing.delay),
+                jitter: self.jitter.unwrap_or(base.timing.jitter),
+            },
+            record_version: self.record_version.or(base.record_version),
+            layout: FragmentLayout {
+                min_size: self.min_size.unwrap_or(base.layout.min_size),
+                max_size: self.max_size.unwrap_or(base.layout.max_size),
+                max_fragments: self.max_fragments.or(base.layout.max_fragments),
+                window: self.window.or(base.layout.window),
+            },
+        }
+    }
+
+    /// Fills the fields unset here with the ones from `other`.
+    pub fn merge(&mut self, other: &StrategyRule) {
+        self.method = self.method.or(other.method);
+        if self.tcp_split.is_none() {
+            self.tcp_split = other.tcp_split.clone();
+        }
+        self.delay = self.delay.or(other.delay);
+        self.jitter = self.jitter.or(other.jitter);
+        self.record_version = self.record_version.or(other.record_version);
+        self.min_size = self.min_size.or(other.min_size);
+        self.max_size = self.max_size.or(other.max_size);
+        self.max_fragments = self.max_fragments.or(other.max_fragments);
+        self.window = self.window.or(other.window);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FakeTtl {
+    Fixed(u8),
+    /// One less than the hop distance found by probing the destination.
+    Auto,
+}
+
+/// A point in the outgoing ClientHello stream where a new TCP segment starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitPosition {
+    /// The middle of the SNI host name.
+    Sni,
+    /// A byte offset from the start of the (possibly fragmented) stream.
+    Offset(usize),
+}
+
+/// Version bytes written into the header of each fragment record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordVersion {
+    Fixed([u8; 2]),
+    /// The version of the client's own first record.
+    Copy,
+    /// A random TLS 1.0-1.3 version, drawn for every record.
+    Random,
+}
+
+/// Limits on the records produced by the random method.
+#[derive(Clone, Copy)]
+pub struct FragmentLayout {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub max_fragments: Option<usize>,
+    /// How many leading bytes of the ClientHello get fragmented.
+    pub window: Option<usize>,
+}
+
+impl Default for FragmentLayout {
+    fn default() -> Self {
+        Self {
+            min_size: 1,
+            max_size: tls::MAX_RECORD_LEN,
+            max_fragments: None,
+            window: None,
+        }
+    }
+}
+
+/// Pause between writing ClientHello fragments.
+#[derive(Clone, Copy, Default)]
+pub struct FragmentTiming {
+    pub delay: Duration,
+    pub jitter: Duration,
+}
+
+impl FragmentTiming {
+    pub fn is_zero(&self) -> bool {
+        self.delay.is_zero() && self.jitter.is_zero()
+    }
+
+    /// The delay plus a jitter drawn from the connection's fragment RNG.
+    pub fn pause(&self, rng: &mut impl Rng) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let jitter_ms = rng.gen_range(0..=self.jitter.as_millis() as u64);
+        self.delay + Duration::from_millis(jitter_ms)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CachedStrategy {
+    method: FragmentMethod,
+    /// Unix time of the probe that found `method`.
+    found_at: i64,
+}
+
+/// Fragmentation methods found to work for each domain, kept in a file.
+pub struct StrategyCache {
+    pub path: String,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedStrategy>>,
+    failures: Mutex<HashMap<String, Instant>>,
+    /// One lock per domain, so that a domain has one discovery at a time.
+    probing: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Held while the file is written.
+    writing: Mutex<()>,
+}
+
+impl StrategyCache {
+    /// Loads lines of the form `domain method timestamp`.
+    pub fn load(path: &str, ttl: Duration) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        if Path::new(path).exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let mut tokens = line.split_whitespace();
+                let (Some(domain), Some(method), Some(found_at)) =
+                    (tokens.next(), tokens.next(), tokens.next())
+                else {
+                    continue;
+                };
+                if let (Ok(method), Ok(found_at)) = (parse_fragment_method(method), found_at.parse()) {
+                    entries.insert(domain.to_string(), CachedStrategy { method, found_at });
+                }
+            }
+        }
+        Ok(Self {
+            path: path.to_string(),
+            ttl,
+            entries: Mutex::new(entries),
+            failures: Mutex::new(HashMap::new()),
+            probing: Mutex::new(HashMap::new()),
+            writing: Mutex::new(()),
+        })
+    }
+
+    pub async fn len(&self) -> usize {
+        self.entries.lock().await.len()
+    }
+
+    /// Returns the cached method for `domain`, even if it is due for a new probe.
+    pub async fn get(&self, domain: &str) -> Option<FragmentMethod> {
+        self.entries.lock().await.get(domain).map(|entry| entry.method)
+    }
+
+    /// Tells whether `domain` has no usable entry and was not probed in vain recently.
+    pub async fn is_due(&self, domain: &str) -> bool {
+        if let Some(entry) = self.entries.lock().await.get(domain) {
+            let age = Local::now().timestamp().saturating_sub(entry.found_at);
+            if age >= 0 && (age as u64) < self.ttl.as_secs() {
+                return false;
+            }
+        }
+        match self.failures.lock().await.get(domain) {
+            Some(failed_at) => failed_at.elapsed() >= DISCOVERY_RETRY,
+            None => true,
+        }
+    }
+
+    /// Records `method` for `domain` and rewrites the file.
+    async fn store(&self, domain: &str, method: FragmentMethod) -> io::Result<()> {
+        self.failures.lock().await.remove(domain);
+        let _writing = self.writing.lock().await;
+        let data = {
+            let mut entries = self.entries.lock().await;
+            entries.insert(
+                domain.to_string(),
+                CachedStrategy {
+                    method,
+                    found_at: Local::now().timestamp(),
+                },
+            );
+            let mut domains: Vec<&String> = entries.keys().collect();
+            domains.sort();
+            let mut data = String::new();
+            for domain in domains {
+                let entry = entries[domain];
+                data.push_str(&format!(
+                    "{} {} {}\n",
+                    domain,
+                    fragment_method_name(entry.method),
+                    entry.found_at
+                ));
+            }
+            data
+        };
+        tokio::fs::write(&self.path, data).await
+    }
+
+    async fn mark_failed(&self, domain: &str) {
+        self.failures.lock().await.insert(domain.to_string(), Instant::now());
+    }
+
+    async fn discovery_lock(&self, domain: &str) -> Arc<Mutex<()>> {
+        let mut probing = self.probing.lock().await;
+        Arc::clone(probing.entry(domain.to_string()).or_default())
+    }
+}
+
+/// The server side of a tunnel whose ClientHello went through.
+pub struct Upstream {
+    pub reader: OwnedReadHalf,
+    pub writer: OwnedWriteHalf,
+    /// What the server sent back first, still to be forwarded to the client.
+    pub response: Vec<u8>,
+}
+
+impl ConnectionHandler {
+    /// Forwards the client's ClientHello, retrying a targeted one that the
+    /// server rejects with the next fallback method.
+    pub async fn handle_initial_tls_data(
+        self: &Arc<Self>,
+        initial: &InitialTlsData,
+        dst: TcpStream,
+        host: &str,
+        port: u16,
+        conn_key: &str,
+    ) -> io::Result<Upstream> {
+        let domain = self.matching_domain(host, port, initial, conn_key).await;
+        let target = self.route_target(&domain, host, port, conn_key).await;
+        if matches!(self.blacklist_manager.routes.lookup(&target), Some(routing::Action::Reject)) {
+            return Err(rejected_error());
+        }
+
+        if self.blacklist_manager.needs_discovery(&target).await {
+            self.spawn_discovery(&domain, host, port, initial, conn_key);
+        }
+        let mut strategy = self.blacklist_manager.strategy_for(&target).await;
+
+        self.statistics.increment_total_connections().await;
+        if strategy.method == FragmentMethod::None {
+            self.statistics.increment_allowed_connections().await;
+        } else {
+            self.statistics.increment_blocked_connections().await;
+        }
+
+        let targeted = strategy.method != FragmentMethod::None;
+        let mut dst = dst;
+        let mut failed = Vec::new();
+        loop {
+            let proxied = self.is_proxied(conn_key).await;
+            if proxied && strategy.method == FragmentMethod::Fake {
+                strategy.method = FragmentMethod::Sni;
+            }
+            let (mut dst_reader, mut dst_writer) = dst.into_split();
+            let sent = self.send_client_hello(&mut dst_writer, initial, strategy.clone()).await;
+            if let Ok(sent) = sent {
+                self.statistics.update_traffic(0, sent as u64).await;
+                self.update_conn_out(conn_key, sent as u64).await;
+            }
+            if !targeted {
+                sent?;
+                return Ok(Upstream {
+                    reader: dst_reader,
+                    writer: dst_writer,
+                    response: Vec::new(),
+                });
+            }
+            let answer = match sent {
+                Ok(_) => read_first_response(&mut dst_reader).await,
+                Err(err) => Err(err),
+            };
+
+            let err = match answer {
+                Ok(response) => {
+                    if !failed.is_empty() {
+                        self.note_retry_success(&domain, &failed, strategy.method).await;
+                    }
+                    return Ok(Upstream {
+                        reader: dst_reader,
+                        writer: dst_writer,
+                        response,
+                    });
+                }
+                Err(err) => err,
+            };
+
+            failed.push(strategy.method);
+            let Some(next) = next_fallback(strategy.method, &failed, proxied) else {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("ClientHello rejected with every method, last error: {}", err),
+                ));
+            };
+            let _ = self
+                .logger
+                .log_info(&format!(
+                    "{}: ClientHello with {} failed ({}), retrying with {}",
+                    domain,
+                    fragment_method_name(strategy.method),
+                    err,
+                    fragment_method_name(next)
+                ))
+                .await;
+            strategy.method = next;
+            dst = self.connect_remote(host, port, conn_key).await?;
+        }
+    }
+
+    /// Picks the name the blacklist is checked against: the SNI or the CONNECT target.
+    async fn matching_domain(&self, host: &str, port: u16, initial: &InitialTlsData, conn_key: &str) -> String {
+        let sni = tls::parse_client_hello(&initial.hello)
+            .ok()
+            .and_then(|hello| hello.sni)
+            .map(|sni| sni.host);
+        let Some(sni) = sni.filter(|sni| !sni.eq_ignore_ascii_case(host)) else {
+            return host.to_string();
+        };
+        if let Some(info) = self.active_connections.lock().await.get_mut(conn_key) {
+            info.dst_domain = sni.clone();
+        }
+        if host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() {
+            self.blacklist_manager.check_domain(&sni).await;
+            return sni;
+        }
+
+        let _ = self
+            .logger
+            .log_info(&format!(
+                "{}: ClientHello is for '{}', not the requested host",
+                host, sni
+            ))
+            .await;
+        let target = self.route_target(host, host, port, conn_key).await;
+        if self.blacklist_manager.strategy_for(&target).await.method != FragmentMethod::None {
+            host.to_string()
+        } else {
+            sni
+        }
+    }
+
+    async fn note_retry_success(&self, host: &str, failed: &[FragmentMethod], method: FragmentMethod) {
+        let failed: Vec<&str> = failed.iter().map(|m| fragment_method_name(*m)).collect();
+        let _ = self
+            .logger
+            .log_info(&format!(
+                "{}: connected with {} after {} failed",
+                host,
+                fragment_method_name(method),
+                failed.join(", ")
+            ))
+            .await;
+        if let Some(cache) = &self.blacklist_manager.discovered {
+            if let Err(err) = cache.store(host, method).await {
+                let _ = self
+                    .logger
+                    .log_error(&format!("Failed to save strategy cache: {}", err))
+                    .await;
+            }
+        }
+    }
+
+    /// Writes the client's first flight mangled according to `strategy`.
+    async fn send_client_hello(
+        &self,
+        writer: &mut OwnedWriteHalf,
+        initial: &InitialTlsData,
+        strategy: Strategy,
+    ) -> io::Result<usize> {
+        let timing = strategy.timing;
+        let mut rng = fragment_rng(self.config.fragment_seed);
+        let (decoy, segments) = prepare_client_hello(initial, strategy, &self.config.fake_sni, &mut rng);
+        // Each segment, and the decoy, has to leave in a packet of its own.
+        let split = segments.len() > 1 || decoy.is_some();
+        if split {
+            writer.as_ref().set_nodelay(true)?;
+        }
+        let mut sent = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            if i > 0 && !timing.is_zero() {
+                time::sleep(timing.pause(&mut rng)).await;
+            }
+            match &decoy {
+                Some(decoy) if i == 0 => self.send_decoy(writer, decoy, segment).await?,
+                _ => writer.write_all(segment).await?,
+            }
+            sent += segment.len();
+        }
+        if split {
+            writer.as_ref().set_nodelay(false)?;
+        }
+        Ok(sent)
+    }
+
+    /// Starts discovering the strategy for `domain` in the background.
+    fn spawn_discovery(
+        self: &Arc<Self>,
+        domain: &str,
+        host: &str,
+        port: u16,
+        initial: &InitialTlsData,
+        conn_key: &str,
+    ) {
+        let handler = Arc::clone(self);
+        let (domain, host, conn_key) = (domain.to_string(), host.to_string(), conn_key.to_string());
+        let initial = initial.clone();
+        tokio::spawn(async move {
+            let target = handler.route_target(&domain, &host, port, &conn_key).await;
+            handler.discover_strategy(&target, &host, &initial, &conn_key).await;
+        });
+    }
+
+    /// Replays the ClientHello to `host` once per discovery method and caches
+    /// the first one answered with a ServerHello.
+    async fn discover_strategy(
+        &self,
+        target: &routing::Target<'_>,
+        host: &str,
+        initial: &InitialTlsData,
+        conn_key: &str,
+    ) {
+        let domain = target.domain;
+        let manager = &self.blacklist_manager;
+        let Some(cache) = &manager.discovered else {
+            return;
+        };
+        let lock = cache.discovery_lock(domain).await;
+        let Ok(_guard) = lock.try_lock() else {
+            return;
+        };
+        // Another discovery may have finished meanwhile.
+        if !manager.needs_discovery(target).await {
+            return;
+        }
+
+        let base = manager.strategy_for(target).await;
+        for &method in DISCOVERY_METHODS {
+            let candidate = Strategy {
+                method,
+                ..base.clone()
+            };
+            match self.probe_strategy(host, target.port, initial, &candidate, conn_key).await {
+                Ok(true) => {
+                    let _ = self
+                        .logger
+                        .log_info(&format!("{}: discovered {}", domain, fragment_method_name(method)))
+                        .await;
+                    if let Err(err) = cache.store(domain, method).await {
+                        let _ = self
+                            .logger
+                            .log_error(&format!("Failed to save strategy cache: {}", err))
+                            .await;
+                    }
+                    return;
+                }
+                Ok(false) => continue,
+                Err(err) => {
+                    let _ = self
+                        .logger
+                        .log_error(&format!("{}: strategy discovery aborted: {}", domain, err))
+                        .await;
+                    return;
+                }
+            }
+        }
+
+        cache.mark_failed(domain).await;
+        let _ = self
+            .logger
+            .log_error(&format!("{}: no fragmentation method got a ServerHello", domain))
+            .await;
+    }
+
+    /// Tells whether a ServerHello comes back to the ClientHello sent with `strategy`.
+    async fn probe_strategy(
+        &self,
+        host: &str,
+        port: u16,
+        initial: &InitialTlsData,
+        strategy: &Strategy,
+        conn_key: &str,
+    ) -> io::Result<bool> {
+        let (dst, endpoint) = time::timeout(
+            STRATEGY_PROBE_TIMEOUT,
+            self.connect_route(host, port, conn_key),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+        if strategy.method == FragmentMethod::Fake && matches!(endpoint, Some(upstream::Endpoint::Proxy(_))) {
+            return Ok(false);
+        }
+        let (mut reader, mut writer) = dst.into_split();
+        if self.send_client_hello(&mut writer, initial, strategy.clone()).await.is_err() {
+            return Ok(false);
+        }
+        let mut head = [0u8; tls::RECORD_HEADER_LEN + 1];
+        let answer = time::timeout(STRATEGY_PROBE_TIMEOUT, reader.read_exact(&mut head)).await;
+        Ok(matches!(answer, Ok(Ok(_))) && tls::is_server_hello(&head))
+    }
+
+    /// Sends `decoy` with a TTL low enough to expire before the server.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn send_decoy(&self, writer: &OwnedWriteHalf, decoy: &[u8], real: &[u8]) -> io::Result<()> {
+        let mut fake = decoy.to_vec();
+        fake.resize(real.len(), 0);
+        let ttl = self.fake_ttl(writer.as_ref()).await;
+        desync::send_fake(writer.as_ref(), &fake, real, ttl).await
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    async fn send_decoy(&self, _writer: &OwnedWriteHalf, _decoy: &[u8], _real: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "fake fragment method is only available on Linux",
+        ))
+    }
+
+    /// The TTL for decoys to the peer of `stream`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn fake_ttl(&self, stream: &TcpStream) -> u8 {
+        if let FakeTtl::Fixed(ttl) = self.config.fake_ttl {
+            return ttl;
+        }
+        let Ok(peer) = stream.peer_addr() else {
+            return DEFAULT_FAKE_TTL;
+        };
+        let mut distances = self.hop_distances.lock().await;
+        match distances.get(&peer.ip()) {
+            Some(Some(hops)) => return hops.saturating_sub(1).max(1),
+            Some(None) => return DEFAULT_FAKE_TTL,
+            None => {
+                distances.insert(peer.ip(), None);
+            }
+        }
+        drop(distances);
+
+        let local = stream.local_addr().ok().map(|addr| addr.ip());
+        let distances = Arc::clone(&self.hop_distances);
+        let logger = Arc::clone(&self.logger);
+        tokio::spawn(async move {
+            match desync::probe_hops(peer, local, HOP_PROBE_TIMEOUT).await {
+                Some(hops) => {
+                    distances.lock().await.insert(peer.ip(), Some(hops));
+                }
+                None => {
+                    logger
+                        .log_error(&format!("{}: hop probing failed, using TTL {}", peer, DEFAULT_FAKE_TTL))
+                        .await;
+                }
+            }
+        });
+        DEFAULT_FAKE_TTL
+    }
+}
+
+/// The first fragmenting method after `current` in the discovery order that
+/// has not failed yet.
+fn next_fallback(current: FragmentMethod, failed: &[FragmentMethod], proxied: bool) -> Option<FragmentMethod> {
+    let start = DISCOVERY_METHODS.iter().position(|&method| method == current).map_or(0, |i| i + 1);
+    DISCOVERY_METHODS[start..]
+        .iter()
+        .chain(&DISCOVERY_METHODS[..start])
+        .copied()
+        .find(|&method| {
+            method != FragmentMethod::None
+                && !(proxied && method == FragmentMethod::Fake)
+                && !failed.contains(&method)
+        })
+}
+
+/// Waits for the server's first bytes after the ClientHello.
+async fn read_first_response(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 1500];
+    match time::timeout(FIRST_RESPONSE_TIMEOUT, reader.read(&mut buf)).await {
+        Err(_) => Ok(Vec::new()),
+        Ok(Ok(0)) => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before the server answered",
+        )),
+        Ok(Ok(n)) => {
+            buf.truncate(n);
+            Ok(buf)
+        }
+        Ok(Err(err)) => Err(err),
+    }
+}
+
+/// The key rules are stored under: lowercase, without a leading `www.`.
+pub fn rule_domain(domain: &str) -> String {
+    let domain = domain.to_lowercase();
+    match domain.strip_prefix("www.") {
+        Some(rest) => rest.to_string(),
+        None => domain,
+    }
+}
+
+/// Looks up the rule for `domain` or, failing that, its closest parent.
+pub fn find_domain_rule<'a>(
+    rules: &'a HashMap<String, StrategyRule>,
+    domain_matching: DomainMatching,
+    domain: &str,
+) -> Option<&'a StrategyRule> {
+    if rules.is_empty() {
+        return None;
+    }
+    let domain = rule_domain(domain);
+    let parts: Vec<&str> = domain.split('.').collect();
+    let found = (0..parts.len()).find_map(|i| rules.get(&parts[i..].join(".")));
+    if found.is_some() || domain_matching == DomainMatching::Strict {
+        return found;
+    }
+    rules
+        .iter()
+        .filter(|(key, _)| domain.contains(key.as_str()))
+        .max_by_key(|(key, _)| key.len())
+        .map(|(_, rule)| rule)
+}
+
+pub fn parse_fragment_method(value: &str) -> Result<FragmentMethod, String> {
+    match value {
+        "none" => Ok(FragmentMethod::None),
+        "random" => Ok(FragmentMethod::Random),
+        "sni" => Ok(FragmentMethod::Sni),
+        "tcp" | "tcp-split" => Ok(FragmentMethod::TcpSplit),
+        "fake" => Ok(FragmentMethod::Fake),
+        other => Err(format!("unknown fragment method '{}'", other)),
+    }
+}
+
+pub fn fragment_method_name(method: FragmentMethod) -> &'static str {
+    match method {
+        FragmentMethod::None => "none",
+        FragmentMethod::Random => "random",
+        FragmentMethod::Sni => "sni",
+        FragmentMethod::TcpSplit => "tcp-split",
+        FragmentMethod::Fake => "fake",
+    }
+}
+
+pub fn parse_record_version(value: &str) -> Result<RecordVersion, String> {
+    match value {
+        "copy" => return Ok(RecordVersion::Copy),
+        "random" => return Ok(RecordVersion::Random),
+        _ => {}
+    }
+    let hex = value.trim_start_matches("0x");
+    match u16::from_str_radix(hex, 16) {
+        Ok(v) if hex.len() == 4 && v >> 8 == 0x03 => Ok(RecordVersion::Fixed(v.to_be_bytes())),
+        _ => Err(format!("invalid record version '{}'", value)),
+    }
+}
+
+pub fn parse_count(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid {} '{}'", name, value)),
+    }
+}
+
+/// Parses the `key=value` tokens of a rule line.
+pub fn parse_strategy_rule<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<StrategyRule, String> {
+    let mut rule = StrategyRule::default();
+    for token in tokens {
+        let Some((key, value)) = token.split_once('=') else {
+            return Err(format!("expected key=value, got '{}'", token));
+        };
+        match key {
+            "method" => rule.method = Some(parse_fragment_method(value)?),
+            "split" => rule.tcp_split = Some(parse_split_positions(value)?),
+            "delay" | "jitter" => {
+                let ms = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid {} '{}'", key, value))?;
+                let d = Some(Duration::from_millis(ms));
+                if key == "delay" {
+                    rule.delay = d;
+                } else {
+                    rule.jitter = d;
+                }
+            }
+            "version" => rule.record_version = Some(parse_record_version(value)?),
+            "min-size" => rule.min_size = Some(parse_count(key, value)?),
+            "max-size" => rule.max_size = Some(parse_count(key, value)?),
+            "max-fragments" => rule.max_fragments = Some(parse_count(key, value)?),
+            "window" => rule.window = Some(parse_count(key, value)?),
+            other => return Err(format!("unknown rule key '{}'", other)),
+        }
+    }
+    Ok(rule)
+}
+
+/// Loads lines of the form `domain key=value ...`.
+pub fn load_strategy_rules(path: &str) -> io::Result<HashMap<String, StrategyRule>> {
+    let data = fs::read_to_string(path)?;
+    let mut rules = HashMap::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut tokens = line.split_whitespace();
+        let domain = rule_domain(tokens.next().unwrap_or(""));
+        let rule = parse_strategy_rule(tokens).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path, n + 1, err),
+            )
+        })?;
+        rules.insert(domain, rule);
+    }
+    Ok(rules)
+}
+
+pub fn parse_timing_value(flag: &str, value: &str) -> Result<(Option<String>, Duration), String> {
+    let (domain, ms) = match value.split_once('=') {
+        Some((d, ms)) => (Some(rule_domain(d.trim())), ms),
+        None => (None, value),
+    };
+    let ms = ms
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("error: invalid {} value '{}'", flag, value))?;
+    Ok((domain, Duration::from_millis(ms)))
+}
+
+pub fn parse_split_positions(value: &str) -> Result<Vec<SplitPosition>, String> {
+    value
+        .split(',')
+        .map(|part| match part.trim() {
+            "sni" => Ok(SplitPosition::Sni),
+            n => n
+                .parse::<usize>()
+                .map(SplitPosition::Offset)
+                .map_err(|_| format!("invalid split position '{}'", n)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::fragment_rng;
+
+    #[test]
+    fn parses_timing_values() {
+        assert_eq!(parse_timing_value("--fragment-delay", "25"), Ok((None, Duration::from_millis(25))));
+        assert_eq!(
+            parse_timing_value("--fragment-delay", " WWW.Example.com = 40 "),
+            Ok((Some("example.com".to_string()), Duration::from_millis(40)))
+        );
+        let (domain, _) = parse_timing_value("--fragment-delay", "awww.example.com=5").unwrap();
+        assert_eq!(domain.as_deref(), Some("awww.example.com"));
+        for bad in ["", "fast", "-5", "10-50", "1.5", "example.com="] {
+            assert_eq!(
+                parse_timing_value("--fragment-jitter", bad),
+                Err(format!("error: invalid --fragment-jitter value '{}'", bad))
+            );
+        }
+    }
+
+    #[test]
+    fn pause_draws_its_jitter_from_the_fragment_rng() {
+        let timing = FragmentTiming {
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(50),
+        };
+        let pauses = |seed| {
+            let mut rng = fragment_rng(Some(seed));
+            (0..20).map(|_| timing.pause(&mut rng)).collect::<Vec<_>>()
+        };
+        let pauses_7 = pauses(7);
+        assert_eq!(pauses_7, pauses(7));
+        assert_ne!(pauses_7, pauses(8));
+        let range = Duration::from_millis(10)..=Duration::from_millis(60);
+        assert!(pauses_7.iter().all(|pause| range.contains(pause)), "{:?}", pauses_7);
+
+        let fixed = FragmentTiming {
+            delay: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+        };
+        assert_eq!(fixed.pause(&mut fragment_rng(None)), Duration::from_millis(10));
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("nodpi-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn rule(line: &str) -> Result<StrategyRule, String> {
+        parse_strategy_rule(line.split_whitespace())
+    }
+
+    #[test]
+    fn parses_rule_lines() {
+        let parsed = rule("method=tcp split=sni,3 delay=5").unwrap();
+        assert_eq!(parsed.method, Some(FragmentMethod::TcpSplit));
+        assert_eq!(parsed.tcp_split, Some(vec![SplitPosition::Sni, SplitPosition::Offset(3)]));
+        assert_eq!(parsed.delay, Some(Duration::from_millis(5)));
+        assert_eq!(parsed.jitter, None);
+
+        for (line, err) in [
+            ("mode=sni", "unknown rule key 'mode'"),
+            ("method=zigzag", "unknown fragment method 'zigzag'"),
+            ("split=sni,x", "invalid split position 'x'"),
+            ("delay=soon", "invalid delay 'soon'"),
+            ("sni", "expected key=value, got 'sni'"),
+        ] {
+            assert_eq!(rule(line).err().as_deref(), Some(err), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rule_files_name_the_bad_line() {
+        let path = temp_path("rules.txt");
+        fs::write(&path, "# rules\n\nWWW.Example.com method=sni\nexample.org method=zigzag\n").unwrap();
+        let err = load_strategy_rules(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), format!("{}:4: unknown fragment method 'zigzag'", path));
+
+        fs::write(&path, "# rules\n\nWWW.Example.com method=sni\n").unwrap();
+        let rules = load_strategy_rules(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rules.keys().collect::<Vec<_>>(), ["example.com"]);
+    }
+
+    #[test]
+    fn rules_match_subdomains_and_substrings_only_when_loose() {
+        let rules: HashMap<String, StrategyRule> = [
+            ("example.com", "method=sni"),
+            ("cdn.example.com", "method=tcp"),
+            ("video", "method=fake"),
+            ("awww.example.org", "method=none"),
+        ]
+        .into_iter()
+        .map(|(domain, line)| (domain.to_string(), rule(line).unwrap()))
+        .collect();
+        let method = |matching, domain| find_domain_rule(&rules, matching, domain).and_then(|rule| rule.method);
+
+        for matching in [DomainMatching::Strict, DomainMatching::Loose] {
+            assert_eq!(method(matching, "example.com"), Some(FragmentMethod::Sni));
+            assert_eq!(method(matching, "WWW.Example.com"), Some(FragmentMethod::Sni));
+            assert_eq!(method(matching, "a.b.example.com"), Some(FragmentMethod::Sni));
+            assert_eq!(method(matching, "img.cdn.example.com"), Some(FragmentMethod::TcpSplit));
+            // Only a leading www. is dropped.
+            assert_eq!(method(matching, "awww.example.org"), Some(FragmentMethod::None));
+        }
+        assert_eq!(method(DomainMatching::Strict, "notexample.com"), None);
+        assert_eq!(method(DomainMatching::Strict, "video.example.net"), None);
+        assert_eq!(method(DomainMatching::Loose, "notexample.com"), Some(FragmentMethod::Sni));
+        assert_eq!(method(DomainMatching::Loose, "myvideo.net"), Some(FragmentMethod::Fake));
+        assert_eq!(method(DomainMatching::Loose, "example.net"), None);
+    }
+
+    #[test]
+    fn fallbacks_start_after_the_failed_method_and_never_send_the_hello_untouched() {
+        use FragmentMethod::{Fake, Random, Sni, TcpSplit};
+        assert_eq!(next_fallback(Sni, &[Sni], false), Some(TcpSplit));
+        assert_eq!(next_fallback(Random, &[Random], false), Some(Sni));
+        assert_eq!(next_fallback(TcpSplit, &[Sni, TcpSplit], true), Some(Random));
+        assert_eq!(next_fallback(Random, &[Random, Sni, TcpSplit], true), None);
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            assert_eq!(next_fallback(TcpSplit, &[TcpSplit], false), Some(Fake));
+            assert_eq!(next_fallback(Fake, &[Fake], false), Some(Random));
+        }
+    }
+
+    #[test]
+    fn parses_record_versions() {
+        assert!(matches!(parse_record_version("copy"), Ok(RecordVersion::Copy)));
+        assert!(matches!(parse_record_version("random"), Ok(RecordVersion::Random)));
+        assert!(matches!(parse_record_version("0301"), Ok(RecordVersion::Fixed([0x03, 0x01]))));
+        assert!(matches!(parse_record_version("0x0303"), Ok(RecordVersion::Fixed([0x03, 0x03]))));
+        for bad in ["", "303", "00303", "0403", "zz03", "tls1.2"] {
+            assert_eq!(parse_record_version(bad).err(), Some(format!("invalid record version '{}'", bad)));
+        }
+    }
+
+    #[test]
+    fn parses_counts() {
+        assert_eq!(parse_count("max-size", "1"), Ok(1));
+        assert_eq!(parse_count("max-size", "16384"), Ok(16384));
+        for bad in ["0", "-1", "", "2k", " 5"] {
+            assert_eq!(parse_count("max-size", bad), Err(format!("invalid max-size '{}'", bad)));
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_survives_a_reload() {
+        let path = temp_path("cache-reload.txt");
+        let _ = fs::remove_file(&path);
+        let cache = StrategyCache::load(&path, Duration::from_secs(3600)).unwrap();
+        assert_eq!(cache.len().await, 0);
+        cache.store("b.example", FragmentMethod::Sni).await.unwrap();
+        cache.store("a.example", FragmentMethod::TcpSplit).await.unwrap();
+
+        let data = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Vec<&str>> = data.lines().map(|line| line.split(' ').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0][..2], ["a.example", "tcp-split"]);
+        assert_eq!(lines[1][..2], ["b.example", "sni"]);
+        let found_at: i64 = lines[0][2].parse().unwrap();
+        assert!((Local::now().timestamp() - found_at).abs() < 60);
+
+        fs::write(&path, format!("{}broken line\nc.example zigzag 1\nd.example sni soon\n", data)).unwrap();
+        let reloaded = StrategyCache::load(&path, Duration::from_secs(3600)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.len().await, 2);
+        assert_eq!(reloaded.get("a.example").await, Some(FragmentMethod::TcpSplit));
+        assert_eq!(reloaded.get("b.example").await, Some(FragmentMethod::Sni));
+        assert!(!reloaded.is_due("a.example").await);
+        assert!(reloaded.is_due("c.example").await);
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_due_but_still_used() {
+        let path = temp_path("cache-stale.txt");
+        let now = Local::now().timestamp();
+        fs::write(&path, format!("old.example sni {}\nnew.example sni {}\n", now - 7200, now - 60)).unwrap();
+        let cache = StrategyCache::load(&path, Duration::from_secs(3600)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(cache.is_due("old.example").await);
+        assert_eq!(cache.get("old.example").await, Some(FragmentMethod::Sni));
+        assert!(!cache.is_due("new.example").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_discovery_waits_for_the_retry_interval() {
+        let path = temp_path("cache-retry.txt");
+        let cache = StrategyCache::load(&path, Duration::from_secs(3600)).unwrap();
+        assert!(cache.is_due("blocked.example").await);
+        cache.mark_failed("blocked.example").await;
+        assert!(!cache.is_due("blocked.example").await);
+        time::advance(DISCOVERY_RETRY - Duration::from_secs(1)).await;
+        assert!(!cache.is_due("blocked.example").await);
+        time::advance(Duration::from_secs(1)).await;
+        assert!(cache.is_due("blocked.example").await);
+
+        // A method found later ends the back-off.
+        cache.mark_failed("blocked.example").await;
+        cache.store("blocked.example", FragmentMethod::Fake).await.unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!cache.is_due("blocked.example").await);
+    }
+}
@@ -0,0 +1,382 @@
+use rand::Rng;
+use std::fmt;
+use std::ops::Range;
+
+pub const RECORD_HEADER_LEN: usize = 5;
+/// Largest record payload allowed by RFC 8446 for plaintext records.
+pub const MAX_RECORD_LEN: usize = 1 << 14;
+pub const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+pub const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+pub const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+
+pub const EXT_SERVER_NAME: u16 = 0x0000;
+pub const EXT_PADDING: u16 = 0x0015;
+pub const EXT_ALPN: u16 = 0x0010;
+pub const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+pub const EXT_KEY_SHARE: u16 = 0x0033;
+pub const EXT_ECH: u16 = 0xfe0d;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// More bytes are needed before the structure can be parsed.
+    Incomplete,
+    /// The bytes do not form a valid structure.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "incomplete TLS data"),
+            ParseError::Invalid(what) => write!(f, "invalid TLS data: {}", what),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordHeader {
+    pub content_type: u8,
+    pub length: usize,
+}
+
+impl RecordHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, ParseError> {
+        if data.len() < RECORD_HEADER_LEN {
+            return Err(ParseError::Incomplete);
+        }
+        if data[1] != 0x03 {
+            return Err(ParseError::Invalid("record version"));
+        }
+        let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+        if length > MAX_RECORD_LEN {
+            return Err(ParseError::Invalid("record length"));
+        }
+        Ok(Self {
+            content_type: data[0],
+            length,
+        })
+    }
+}
+
+/// A single extension inside the ClientHello. All offsets are relative to
+/// the start of the handshake message (the first byte of the record payload).
+#[derive(Clone, Debug)]
+pub struct Extension {
+    pub ext_type: u16,
+    /// Offset of the extension type field.
+    pub start: usize,
+    /// Offset of the extension body, right after the length field.
+    pub body: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerName {
+    pub extension: Extension,
+    /// Offsets of the host name bytes of the first `host_name` entry.
+    pub name: Range<usize>,
+    pub host: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientHello {
+    /// Length of the whole handshake message including its 4-byte header.
+    pub length: usize,
+    pub legacy_version: [u8; 2],
+    pub session_id: Range<usize>,
+    pub cipher_suites: Range<usize>,
+    pub extensions: Vec<Extension>,
+    pub sni: Option<ServerName>,
+    pub alpn: Option<Extension>,
+    pub supported_versions: Option<Extension>,
+    pub key_share: Option<Extension>,
+    pub padding: Option<Extension>,
+    pub ech: Option<Extension>,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<Range<usize>, ParseError> {
+        let end = self.pos.checked_add(n).ok_or(ParseError::Invalid("length overflow"))?;
+        if end > self.data.len() {
+            return Err(ParseError::Incomplete);
+        }
+        let range = self.pos..end;
+        self.pos = end;
+        Ok(range)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        let r = self.take(1)?;
+        Ok(self.data[r.start])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        let r = self.take(2)?;
+        Ok(u16::from_be_bytes([self.data[r.start], self.data[r.start + 1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, ParseError> {
+        let r = self.take(3)?;
+        let b = &self.data[r];
+        Ok(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+}
+
+/// Returns the declared length of the handshake message at the start of
+/// `data`, including the 4-byte handshake header.
+pub fn handshake_length(data: &[u8]) -> Result<usize, ParseError> {
+    let mut cur = Cursor { data, pos: 0 };
+    if cur.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return Err(ParseError::Invalid("not a ClientHello"));
+    }
+    Ok(cur.u24()? + 4)
+}
+
+/// Tells whether `data` starts with a handshake record carrying a
+/// ServerHello (or a HelloRetryRequest, which shares its type).
+pub fn is_server_hello(data: &[u8]) -> bool {
+    match RecordHeader::parse(data) {
+        Ok(header) => {
+            header.content_type == CONTENT_TYPE_HANDSHAKE
+                && data.get(RECORD_HEADER_LEN) == Some(&HANDSHAKE_SERVER_HELLO)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Parses a ClientHello handshake message. `data` is the reassembled
+/// handshake payload without the TLS record headers.
+pub fn parse_client_hello(data: &[u8]) -> Result<ClientHello, ParseError> {
+    let length = handshake_length(data)?;
+    if data.len() < length {
+        return Err(ParseError::Incomplete);
+    }
+    // Once the declared length is available, running out of bytes means a
+    // nested length field is lying.
+    parse_client_hello_body(&data[..length]).map_err(|err| match err {
+        ParseError::Incomplete => ParseError::Invalid("nested length"),
+        other => other,
+    })
+}
+
+fn parse_client_hello_body(data: &[u8]) -> Result<ClientHello, ParseError> {
+    let length = data.len();
+    let mut cur = Cursor { data, pos: 4 };
+
+    let version = cur.take(2)?;
+    let legacy_version = [data[version.start], data[version.start + 1]];
+    cur.take(32)?;
+    let session_id_len = cur.u8()? as usize;
+    if session_id_len > 32 {
+        return Err(ParseError::Invalid("session id length"));
+    }
+    let session_id = cur.take(session_id_len)?;
+    let suites_len = cur.u16()? as usize;
+    if !suites_len.is_multiple_of(2) {
+        return Err(ParseError::Invalid("cipher suites length"));
+    }
+    let cipher_suites = cur.take(suites_len)?;
+    let compression_len = cur.u8()? as usize;
+    cur.take(compression_len)?;
+
+    let mut hello = ClientHello {
+        length,
+        legacy_version,
+        session_id,
+        cipher_suites,
+        extensions: Vec::new(),
+        sni: None,
+        alpn: None,
+        supported_versions: None,
+        key_share: None,
+        padding: None,
+        ech: None,
+    };
+
+    if cur.pos == length {
+        return Ok(hello);
+    }
+    let extensions_len = cur.u16()? as usize;
+    if cur.pos + extensions_len != length {
+        return Err(ParseError::Invalid("extensions length"));
+    }
+
+    while cur.pos < length {
+        let start = cur.pos;
+        let ext_type = cur.u16()?;
+        let ext_len = cur.u16()? as usize;
+        let body = cur.take(ext_len)?;
+        let ext = Extension {
+            ext_type,
+            start,
+            body: body.start,
+            end: body.end,
+        };
+        match ext_type {
+            EXT_SERVER_NAME => hello.sni = Some(parse_server_name(data, ext.clone())?),
+            EXT_ALPN => hello.alpn = Some(ext.clone()),
+            EXT_SUPPORTED_VERSIONS => hello.supported_versions = Some(ext.clone()),
+            EXT_KEY_SHARE => hello.key_share = Some(ext.clone()),
+            EXT_PADDING => hello.padding = Some(ext.clone()),
+            EXT_ECH => hello.ech = Some(ext.clone()),
+            _ => {}
+        }
+        hello.extensions.push(ext);
+    }
+
+    Ok(hello)
+}
+
+fn parse_server_name(data: &[u8], ext: Extension) -> Result<ServerName, ParseError> {
+    let mut cur = Cursor {
+        data: &data[..ext.end],
+        pos: ext.body,
+    };
+    let list_len = cur.u16()? as usize;
+    if cur.pos + list_len != ext.end {
+        return Err(ParseError::Invalid("server name list length"));
+    }
+    while cur.pos < ext.end {
+        let name_type = cur.u8()?;
+        let name_len = cur.u16()? as usize;
+        let name = cur.take(name_len)?;
+        if name_type == 0 {
+            let host = std::str::from_utf8(&data[name.clone()])
+                .map_err(|_| ParseError::Invalid("server name encoding"))?
+                .to_string();
+            return Ok(ServerName {
+                extension: ext,
+                name,
+                host,
+            });
+        }
+    }
+    Err(ParseError::Invalid("no host_name entry"))
+}
+
+/// Builds a complete, plausible TLS 1.3 ClientHello record for `host`. Used
+/// as a decoy, so it only needs to look right to a middlebox.
+pub fn build_client_hello(host: &str) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let host = host.as_bytes();
+
+    let mut ext = Vec::new();
+    push_extension(&mut ext, EXT_SERVER_NAME, &{
+        let mut body = Vec::new();
+        body.extend_from_slice(&((host.len() + 3) as u16).to_be_bytes());
+        body.push(0);
+        body.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        body.extend_from_slice(host);
+        body
+    });
+    // supported_groups: x25519, secp256r1
+    push_extension(&mut ext, 0x000a, &[0x00, 0x04, 0x00, 0x1d, 0x00, 0x17]);
+    // signature_algorithms: ecdsa_secp256r1_sha256, rsa_pss_rsae_sha256, rsa_pkcs1_sha256
+    push_extension(&mut ext, 0x000d, &[0x00, 0x06, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01]);
+    push_extension(&mut ext, EXT_ALPN, b"\x00\x0c\x02h2\x08http/1.1");
+    push_extension(&mut ext, EXT_SUPPORTED_VERSIONS, &[0x04, 0x03, 0x04, 0x03, 0x03]);
+    // psk_key_exchange_modes: psk_dhe_ke
+    push_extension(&mut ext, 0x002d, &[0x01, 0x01]);
+    push_extension(&mut ext, EXT_KEY_SHARE, &{
+        let mut body = vec![0x00, 0x24, 0x00, 0x1d, 0x00, 0x20];
+        body.extend((0..32).map(|_| rng.gen::<u8>()));
+        body
+    });
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend((0..32).map(|_| rng.gen::<u8>()));
+    hello.push(32);
+    hello.extend((0..32).map(|_| rng.gen::<u8>()));
+    hello.extend_from_slice(&[0x00, 0x08, 0x13, 0x01, 0x13, 0x02, 0x13, 0x03, 0xc0, 0x2b]);
+    hello.extend_from_slice(&[0x01, 0x00]);
+    hello.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&ext);
+
+    let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+    record.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+    record.push(HANDSHAKE_CLIENT_HELLO);
+    record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    record.extend_from_slice(&hello);
+    record
+}
+
+fn push_extension(out: &mut Vec<u8>, ext_type: u16, body: &[u8]) {
+    out.extend_from_slice(&ext_type.to_be_bytes());
+    out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello_payload(host: &str) -> Vec<u8> {
+        build_client_hello(host)[RECORD_HEADER_LEN..].to_vec()
+    }
+
+    #[test]
+    fn parses_a_built_client_hello() {
+        let payload = hello_payload("www.example.com");
+        let hello = parse_client_hello(&payload).unwrap();
+        assert_eq!(hello.length, payload.len());
+        assert_eq!(hello.legacy_version, [0x03, 0x03]);
+        assert_eq!(hello.session_id.len(), 32);
+        assert_eq!(hello.cipher_suites.len(), 8);
+        let sni = hello.sni.unwrap();
+        assert_eq!(sni.host, "www.example.com");
+        assert_eq!(&payload[sni.name], b"www.example.com");
+        assert_eq!(sni.extension.start, hello.extensions[0].start);
+        assert!(hello.alpn.is_some() && hello.supported_versions.is_some() && hello.key_share.is_some());
+        assert!(hello.padding.is_none() && hello.ech.is_none());
+        assert_eq!(hello.extensions.last().unwrap().end, payload.len());
+    }
+
+    #[test]
+    fn tells_incomplete_from_invalid() {
+        let payload = hello_payload("example.com");
+        for len in [0, 3, 40, payload.len() - 1] {
+            assert_eq!(parse_client_hello(&payload[..len]).unwrap_err(), ParseError::Incomplete);
+        }
+        // The declared length is all there, but the extensions claim more.
+        let mut lying = payload.clone();
+        let len = lying.len() - 2;
+        lying.truncate(len);
+        lying[1..4].copy_from_slice(&((len - 4) as u32).to_be_bytes()[1..]);
+        assert!(matches!(parse_client_hello(&lying), Err(ParseError::Invalid(_))));
+
+        let mut server_hello = payload;
+        server_hello[0] = HANDSHAKE_SERVER_HELLO;
+        assert_eq!(
+            handshake_length(&server_hello).unwrap_err(),
+            ParseError::Invalid("not a ClientHello")
+        );
+    }
+
+    #[test]
+    fn parses_record_headers() {
+        let header = RecordHeader::parse(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01, 0x02, 0x00]).unwrap();
+        assert_eq!((header.content_type, header.length), (CONTENT_TYPE_HANDSHAKE, 0x200));
+        assert_eq!(RecordHeader::parse(&[0x16, 0x03, 0x01]).unwrap_err(), ParseError::Incomplete);
+        assert_eq!(
+            RecordHeader::parse(&[0x16, 0x02, 0x00, 0x00, 0x10]).unwrap_err(),
+            ParseError::Invalid("record version")
+        );
+        assert_eq!(
+            RecordHeader::parse(&[0x16, 0x03, 0x03, 0x40, 0x01]).unwrap_err(),
+            ParseError::Invalid("record length")
+        );
+    }
+
+    #[test]
+    fn recognizes_server_hellos() {
+        assert!(is_server_hello(&[0x16, 0x03, 0x03, 0x00, 0x40, HANDSHAKE_SERVER_HELLO]));
+        assert!(!is_server_hello(&[0x16, 0x03, 0x03, 0x00, 0x40, HANDSHAKE_CLIENT_HELLO]));
+        assert!(!is_server_hello(&[0x15, 0x03, 0x03, 0x00, 0x02, HANDSHAKE_SERVER_HELLO]));
+        assert!(!is_server_hello(b"HTTP/1.1 200 OK"));
+    }
+}
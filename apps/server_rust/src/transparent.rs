@@ -0,0 +1,251 @@
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::fd::{AsRawFd, RawFd};
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{self, Duration};
+
+use crate::tls::{self, ParseError};
+
+const LISTEN_BACKLOG: u32 = 1024;
+/// Pause between peeks while the rest of a ClientHello or request head is
+/// still on its way.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Binds the transparent listener on `addr`. With `tproxy` the socket is
+/// marked IP_TRANSPARENT, so it can accept connections addressed to foreign
+/// IPs. An IPv6 socket only takes IPv6, as IPv4 gets a listener of its own.
+pub fn bind_listener(addr: SocketAddr, tproxy: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        let socket = TcpSocket::new_v6()?;
+        set_flag(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+        socket
+    };
+    socket.set_reuseaddr(true)?;
+    if tproxy {
+        let (level, name) = if addr.is_ipv4() {
+            (libc::IPPROTO_IP, libc::IP_TRANSPARENT)
+        } else {
+            (libc::IPPROTO_IPV6, libc::IPV6_TRANSPARENT)
+        };
+        set_flag(socket.as_raw_fd(), level, name)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Recovers where the client was connecting to. TPROXY keeps the original
+/// address as the local one; REDIRECT rewrites it and leaves the original in
+/// conntrack, which SO_ORIGINAL_DST reads back.
+pub fn original_destination(stream: &TcpStream, tproxy: bool) -> io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    if tproxy {
+        return Ok(unmap(local));
+    }
+    let fd = stream.as_raw_fd();
+    match unmap(stream.peer_addr()?) {
+        SocketAddr::V4(_) => original_dst_v4(fd),
+        SocketAddr::V6(_) => original_dst_v6(fd),
+    }
+}
+
+/// Tells whether `ip` belongs to this host. A connection whose original
+/// destination is the listener itself was not redirected, and serving it
+/// would connect back to the listener.
+pub fn is_local(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+/// The domain a redirected connection is for, as its first bytes name it.
+pub enum Domain {
+    /// The SNI of a TLS ClientHello.
+    Sni(String),
+    /// The Host of a plain HTTP request.
+    Host(String),
+}
+
+impl Domain {
+    pub fn name(&self) -> &str {
+        match self {
+            Domain::Sni(name) | Domain::Host(name) => name,
+        }
+    }
+}
+
+/// Peeks at the first bytes from the client, without consuming them, and
+/// returns the SNI of a TLS ClientHello or the Host of an HTTP request.
+/// Resolves to `None` once the data turns out to be neither.
+pub async fn peek_domain(stream: &TcpStream) -> Option<Domain> {
+    let mut buf = vec![0u8; crate::MAX_CLIENT_HELLO_LEN];
+    let mut seen = 0;
+    loop {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        match domain_from(&buf[..n]) {
+            Ok(domain) => return domain,
+            Err(ParseError::Incomplete) if n < buf.len() => {}
+            Err(_) => return None,
+        }
+        if n == seen {
+            time::sleep(PEEK_INTERVAL).await;
+        }
+        seen = n;
+    }
+}
+
+fn domain_from(data: &[u8]) -> Result<Option<Domain>, ParseError> {
+    if data.first() == Some(&tls::CONTENT_TYPE_HANDSHAKE) {
+        Ok(sni_from_records(data)?.map(Domain::Sni))
+    } else {
+        Ok(host_from_http(data)?.map(Domain::Host))
+    }
+}
+
+fn sni_from_records(data: &[u8]) -> Result<Option<String>, ParseError> {
+    let mut payload = Vec::new();
+    let mut pos = 0;
+    loop {
+        let header = tls::RecordHeader::parse(&data[pos..])?;
+        if header.content_type != tls::CONTENT_TYPE_HANDSHAKE {
+            return Err(ParseError::Invalid("not a handshake record"));
+        }
+        let body = pos + tls::RECORD_HEADER_LEN;
+        let end = (body + header.length).min(data.len());
+        payload.extend_from_slice(&data[body..end]);
+        match tls::parse_client_hello(&payload) {
+            Ok(hello) => return Ok(hello.sni.map(|sni| sni.host)),
+            Err(ParseError::Incomplete) if end < data.len() => pos = end,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn host_from_http(data: &[u8]) -> Result<Option<String>, ParseError> {
+    let Some(head_end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+        let token = data.iter().take_while(|b| b.is_ascii_uppercase()).count();
+        return if token == data.len() || data.get(token) == Some(&b' ') {
+            Err(ParseError::Incomplete)
+        } else {
+            Err(ParseError::Invalid("not an HTTP request"))
+        };
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]);
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("host").then(|| value.trim())
+    });
+    Ok(host.map(|host| crate::parse_host_port(host, 80).0))
+}
+
+fn original_dst_v4(fd: RawFd) -> io::Result<SocketAddr> {
+    // SAFETY: sockaddr_in is plain data and valid when zeroed.
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    get_addr(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST, &mut addr)?;
+    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+    Ok(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+}
+
+fn original_dst_v6(fd: RawFd) -> io::Result<SocketAddr> {
+    // SAFETY: sockaddr_in6 is plain data and valid when zeroed.
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    get_addr(fd, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST, &mut addr)?;
+    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+    Ok(SocketAddr::V6(SocketAddrV6::new(
+        ip,
+        u16::from_be(addr.sin6_port),
+        addr.sin6_flowinfo,
+        addr.sin6_scope_id,
+    )))
+}
+
+fn get_addr<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, addr: &mut T) -> io::Result<()> {
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    // SAFETY: addr and len describe a properly sized, writable local.
+    let rc = unsafe { libc::getsockopt(fd, level, name, addr as *mut T as *mut _, &mut len) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_flag(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    // SAFETY: value points to a properly sized local.
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const _,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Turns an IPv4-mapped IPv6 address from a dual-stack socket back into
+/// IPv4, which is what conntrack and the upstream connect expect.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(data: &[u8]) -> Result<Option<String>, ParseError> {
+        domain_from(data).map(|domain| domain.map(|domain| domain.name().to_string()))
+    }
+
+    #[test]
+    fn finds_the_sni_across_records() {
+        let record = tls::build_client_hello("sni.example");
+        assert!(matches!(domain_from(&record), Ok(Some(Domain::Sni(_)))));
+        assert_eq!(name(&record), Ok(Some("sni.example".to_string())));
+        assert_eq!(name(&record[..record.len() - 1]), Err(ParseError::Incomplete));
+
+        // The same hello split over two records.
+        let payload = &record[tls::RECORD_HEADER_LEN..];
+        let mut split = Vec::new();
+        for part in [&payload[..40], &payload[40..]] {
+            split.extend_from_slice(&[tls::CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            split.extend_from_slice(part);
+        }
+        assert_eq!(name(&split), Ok(Some("sni.example".to_string())));
+        assert_eq!(name(&split[..50]), Err(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn finds_the_http_host() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: x\r\nhost: web.example:8080\r\n\r\n";
+        assert!(matches!(domain_from(request), Ok(Some(Domain::Host(_)))));
+        assert_eq!(name(request), Ok(Some("web.example".to_string())));
+        assert_eq!(name(b"GET / HTTP/1.0\r\n\r\n"), Ok(None));
+        assert_eq!(name(b"GE"), Err(ParseError::Incomplete));
+        assert_eq!(name(b"GET / HTTP/1.1\r\nHost: web"), Err(ParseError::Incomplete));
+        assert!(matches!(name(b"SSH-2.0-OpenSSH_9.6\r\n"), Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn unmaps_ipv4_addresses() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:443".parse().unwrap();
+        assert_eq!(unmap(mapped), "192.0.2.1:443".parse::<SocketAddr>().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(unmap(v6), v6);
+    }
+}
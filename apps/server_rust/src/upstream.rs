@@ -0,0 +1,661 @@
+//! Outbound connections through the upstreams given with `--upstream`: HTTP
+//! proxies reached with CONNECT, SOCKS5 proxies, or direct connections from
+//! a given local address. Several endpoints under one name form a pool that
+//! fails over between them or spreads connections across them.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{self, Duration, Instant};
+
+use crate::{dns, http, socks};
+
+/// How long a health check waits for the connect and handshake.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a proxy handshake failed.
+enum Failure {
+    /// The proxy itself is unusable; another endpoint may do better.
+    Endpoint(io::Error),
+    /// The proxy works but could not reach the target.
+    Target(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Endpoint(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Socks5,
+}
+
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    pub scheme: Scheme,
+    pub host: String,
+    pub port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Parses `http://[user:pass@]host:port` or the same with `socks5://` or `socks5h://`.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| format!("invalid upstream '{}'", url))?;
+        let scheme = match scheme.to_ascii_lowercase().as_str() {
+            "http" => Scheme::Http,
+            "socks5" | "socks5h" => Scheme::Socks5,
+            other => return Err(format!("unsupported upstream scheme '{}'", other)),
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (userinfo, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => (Some(userinfo), authority),
+            None => (None, rest),
+        };
+        if authority.contains(['/', '?', '#']) {
+            return Err(format!("upstream '{}' must not have a path", url));
+        }
+        let (host, port) = crate::parse_host_port(authority, 0);
+        if host.is_empty() || port == 0 {
+            return Err(format!("upstream '{}' needs a host and a port", url));
+        }
+        let credentials = match userinfo {
+            Some(userinfo) => {
+                let (user, pass) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                Some((percent_decode(user)?, percent_decode(pass)?))
+            }
+            None => None,
+        };
+        Ok(Self {
+            scheme,
+            host,
+            port,
+            credentials,
+        })
+    }
+
+    /// Runs the handshake that turns `stream` into a tunnel to `host:port`.
+    async fn open(&self, mut stream: TcpStream, host: &str, port: u16) -> Result<TcpStream, Failure> {
+        match self.scheme {
+            Scheme::Http => self.http_connect(&mut stream, host, port).await?,
+            Scheme::Socks5 => self.socks5_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    /// Checks that the proxy speaks its protocol and takes our credentials.
+    async fn probe(&self, mut stream: TcpStream) -> io::Result<()> {
+        match self.scheme {
+            Scheme::Socks5 => self.socks5_greet(&mut stream).await,
+            Scheme::Http => {
+                let mut request = format!("OPTIONS * HTTP/1.1\r\nHost: {}\r\n", self.authority());
+                self.push_authorization(&mut request);
+                request.push_str("Connection: close\r\n\r\n");
+                stream.write_all(request.as_bytes()).await?;
+                let head = read_head(&mut stream).await?;
+                let response = http::parse_response(&head)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("HTTP proxy: {}", err)))?;
+                if response.status == 407 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "HTTP proxy rejected the credentials",
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn authority(&self) -> String {
+        format_authority(&self.host, self.port)
+    }
+
+    fn push_authorization(&self, request: &mut String) {
+        if let Some((user, pass)) = &self.credentials {
+            let token = STANDARD.encode(format!("{}:{}", user, pass));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Failure> {
+        let authority = format_authority(host, port);
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        self.push_authorization(&mut request);
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let head = read_head(stream).await?;
+        let response = http::parse_response(&head)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("HTTP proxy: {}", err)))?;
+        if (200..300).contains(&response.status) {
+            return Ok(());
+        }
+        let kind = match response.status {
+            407 => io::ErrorKind::PermissionDenied,
+            504 => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::Other,
+        };
+        let err = io::Error::new(
+            kind,
+            format!("HTTP proxy replied {} {}", response.status, response.reason),
+        );
+        Err(match response.status {
+            502 | 504 => Failure::Target(err),
+            _ => Failure::Endpoint(err),
+        })
+    }
+
+    async fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Failure> {
+        self.socks5_greet(stream).await?;
+        let address = match host.parse::<IpAddr>() {
+            Ok(ip) => socks::Address::Ip(ip),
+            Err(_) => socks::Address::Domain(host.to_string()),
+        };
+        stream
+            .write_all(&socks::request(socks::CMD_CONNECT, &address, port)?)
+            .await?;
+        let (code, _, _) = socks::read_reply(stream).await?;
+        match code {
+            socks::REPLY_SUCCEEDED => Ok(()),
+            socks::REPLY_NETWORK_UNREACHABLE | socks::REPLY_HOST_UNREACHABLE | socks::REPLY_CONNECTION_REFUSED => {
+                Err(Failure::Target(socks::reply_error(code)))
+            }
+            _ => Err(Failure::Endpoint(socks::reply_error(code))),
+        }
+    }
+
+    /// Sends the SOCKS5 greeting and authenticates if the proxy asks to.
+    async fn socks5_greet(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut greeting = vec![socks::VERSION, 1, socks::AUTH_NONE];
+        if self.credentials.is_some() {
+            greeting[1] = 2;
+            greeting.push(socks::AUTH_PASSWORD);
+        }
+        stream.write_all(&greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != socks::VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (socks::AUTH_NONE, _) => Ok(()),
+            (socks::AUTH_PASSWORD, Some((user, pass))) => {
+                stream.write_all(&socks::credentials(user, pass)?).await?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS5 proxy rejected the credentials",
+                    ));
+                }
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy accepts none of the offered auth methods",
+            )),
+        }
+    }
+}
+
+/// Shows the proxy without its credentials, for the banner and logs.
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.scheme {
+            Scheme::Http => "http",
+            Scheme::Socks5 => "socks5",
+        };
+        write!(f, "{}://{}", scheme, self.authority())
+    }
+}
+
+fn format_authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[derive(Clone)]
+pub enum Endpoint {
+    Proxy(Proxy),
+    /// A direct connection, made from the given local address if any.
+    Direct(Option<String>),
+}
+
+impl Endpoint {
+    /// Parses a proxy URL, `direct`, or `direct://ADDRESS`.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        match url.strip_prefix("direct") {
+            Some("") => Ok(Endpoint::Direct(None)),
+            Some(rest) => match rest.strip_prefix("://") {
+                Some(addr) if addr.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() => {
+                    Ok(Endpoint::Direct(Some(addr.to_string())))
+                }
+                _ => Err(format!("invalid upstream '{}'", url)),
+            },
+            None => Proxy::parse(url).map(Endpoint::Proxy),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Proxy(proxy) => proxy.fmt(f),
+            Endpoint::Direct(None) => write!(f, "direct"),
+            Endpoint::Direct(Some(addr)) => write!(f, "direct://{}", addr),
+        }
+    }
+}
+
+/// How a pool picks among its healthy endpoints.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The first healthy endpoint, in the order given.
+    Failover,
+    RoundRobin,
+    /// The healthy endpoint with the lowest measured connect latency.
+    Fastest,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "failover" => Ok(Policy::Failover),
+            "round-robin" | "rr" => Ok(Policy::RoundRobin),
+            "fastest" => Ok(Policy::Fastest),
+            other => Err(format!("unknown upstream policy '{}'", other)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Policy::Failover => "failover",
+            Policy::RoundRobin => "round-robin",
+            Policy::Fastest => "fastest",
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Health {
+    down: bool,
+    /// Smoothed connect latency, once measured.
+    latency: Option<Duration>,
+}
+
+struct Slot {
+    endpoint: Endpoint,
+    health: Mutex<Health>,
+}
+
+impl Slot {
+    fn health(&self) -> Health {
+        self.health.lock().map(|h| h.clone()).unwrap_or_default()
+    }
+
+    /// Records the outcome of a connect; true when the endpoint changed state.
+    fn record(&self, outcome: Result<Duration, ()>) -> bool {
+        let Ok(mut health) = self.health.lock() else {
+            return false;
+        };
+        let was_down = health.down;
+        match outcome {
+            Ok(sample) => {
+                health.down = false;
+                health.latency = Some(match health.latency {
+                    Some(old) => (old * 3 + sample) / 4,
+                    None => sample,
+                });
+            }
+            Err(()) => health.down = true,
+        }
+        was_down != health.down
+    }
+}
+
+/// The endpoints behind one upstream name. Clones share the health state.
+#[derive(Clone)]
+pub struct Pool {
+    policy: Policy,
+    slots: Arc<Vec<Slot>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Pool {
+    /// Parses a comma-separated list of endpoints.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let slots = spec
+            .split(',')
+            .map(|url| {
+                Ok(Slot {
+                    endpoint: Endpoint::parse(url.trim())?,
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            policy: Policy::Failover,
+            slots: Arc::new(slots),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Indices of the endpoints in the order they are tried, down ones last.
+    fn candidates(&self) -> Vec<usize> {
+        let health: Vec<Health> = self.slots.iter().map(Slot::health).collect();
+        let (mut up, down): (Vec<usize>, Vec<usize>) = (0..self.slots.len()).partition(|i| !health[*i].down);
+        match self.policy {
+            Policy::Failover => {}
+            Policy::RoundRobin => {
+                if !up.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % up.len();
+                    up.rotate_left(start);
+                }
+            }
+            Policy::Fastest => up.sort_by_key(|i| health[*i].latency.unwrap_or_default()),
+        }
+        up.extend(down);
+        up
+    }
+
+    /// Opens a connection to `host:port` through the first endpoint that works.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        out_host: &Option<String>,
+        resolver: &dns::Resolver,
+    ) -> io::Result<(TcpStream, Endpoint)> {
+        let mut last_err = None;
+        for i in self.candidates() {
+            let slot = &self.slots[i];
+            let started = Instant::now();
+            let result = match &slot.endpoint {
+                Endpoint::Direct(bind) => {
+                    let out_host = bind.as_ref().or(out_host.as_ref()).cloned();
+                    match crate::connect_with_out_host(host, port, &out_host, resolver).await {
+                        Err(err) if err.kind() == io::ErrorKind::AddrNotAvailable => Err(err),
+                        other => return other.map(|stream| (stream, slot.endpoint.clone())),
+                    }
+                }
+                Endpoint::Proxy(proxy) => {
+                    crate::connect_with_out_host(&proxy.host, proxy.port, out_host, resolver).await
+                }
+            };
+            let stream = match result {
+                Ok(stream) => stream,
+                Err(err) => {
+                    slot.record(Err(()));
+                    last_err = Some(io::Error::new(err.kind(), format!("upstream {}: {}", slot.endpoint, err)));
+                    continue;
+                }
+            };
+            let latency = started.elapsed();
+            let Endpoint::Proxy(proxy) = &slot.endpoint else {
+                slot.record(Ok(latency));
+                return Ok((stream, slot.endpoint.clone()));
+            };
+            let failure = match time::timeout(crate::CONNECT_TIMEOUT, proxy.open(stream, host, port)).await {
+                Ok(Ok(stream)) => {
+                    slot.record(Ok(latency));
+                    return Ok((stream, slot.endpoint.clone()));
+                }
+                Ok(Err(failure)) => failure,
+                Err(_) => Failure::Endpoint(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")),
+            };
+            match failure {
+                Failure::Target(err) => {
+                    slot.record(Ok(latency));
+                    return Err(io::Error::new(err.kind(), format!("upstream {}: {}", proxy, err)));
+                }
+                Failure::Endpoint(err) => {
+                    slot.record(Err(()));
+                    last_err = Some(io::Error::new(err.kind(), format!("upstream {}: {}", proxy, err)));
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::other("upstream has no endpoints")))
+    }
+
+    /// Probes every endpoint and returns a line for each one that changed state.
+    pub async fn check(&self, out_host: &Option<String>, resolver: &dns::Resolver) -> Vec<String> {
+        let mut changes = Vec::new();
+        for slot in self.slots.iter() {
+            let result = match &slot.endpoint {
+                Endpoint::Proxy(proxy) => {
+                    match time::timeout(HEALTH_CHECK_TIMEOUT, probe(proxy, out_host, resolver)).await {
+                        Ok(Ok(latency)) => Ok(latency),
+                        Ok(Err(err)) => Err(err.to_string()),
+                        Err(_) => Err("health check timed out".to_string()),
+                    }
+                }
+                Endpoint::Direct(Some(addr)) => bind_local(addr).map(|_| Duration::ZERO).map_err(|e| e.to_string()),
+                Endpoint::Direct(None) => continue,
+            };
+            if slot.record(result.clone().map_err(|_| ())) {
+                changes.push(match result {
+                    Ok(latency) => format!("{} is up again ({} ms)", slot.endpoint, latency.as_millis()),
+                    Err(err) => format!("{} is down: {}", slot.endpoint, err),
+                });
+            }
+        }
+        changes
+    }
+
+    /// Tells whether the pool has endpoints worth checking.
+    pub fn needs_checks(&self) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| !matches!(slot.endpoint, Endpoint::Direct(None)))
+    }
+}
+
+/// Lists the endpoints, with the policy when there is a choice.
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, slot) in self.slots.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            slot.endpoint.fmt(f)?;
+        }
+        if self.slots.len() > 1 {
+            write!(f, " ({})", self.policy.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Connects to `proxy` and checks its handshake, returning the connect time.
+async fn probe(proxy: &Proxy, out_host: &Option<String>, resolver: &dns::Resolver) -> io::Result<Duration> {
+    let started = Instant::now();
+    let stream = crate::connect_with_out_host(&proxy.host, proxy.port, out_host, resolver).await?;
+    let latency = started.elapsed();
+    proxy.probe(stream).await?;
+    Ok(latency)
+}
+
+/// Reads the proxy's response head one byte at a time.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == http::MAX_HEAD_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP proxy response is too large"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(head)
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid percent-encoding in '{}'", value))?;
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| format!("'{}' is not UTF-8", value))
+}
+
+/// Checks that `addr` can still be bound locally.
+fn bind_local(addr: &str) -> io::Result<()> {
+    let ip = addr
+        .trim_matches(['[', ']'])
+        .parse::<IpAddr>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address '{}'", addr)))?;
+    let socket = if ip.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn resolver() -> dns::Resolver {
+        dns::Resolver::new("system", dns::DohMethod::Get, &[]).unwrap()
+    }
+
+    /// Starts a fake proxy that answers each connection with `reply`.
+    async fn fake_proxy(reply: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 512];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(reply).await;
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        port
+    }
+
+    /// Starts a SOCKS5 proxy that accepts any CONNECT with `code`.
+    async fn socks5_proxy(code: u8) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).await?;
+                    stream.write_all(&[socks::VERSION, socks::AUTH_NONE]).await?;
+                    socks::read_request(&mut stream).await?;
+                    let address = socks::Address::Ip(IpAddr::from([0, 0, 0, 0]));
+                    stream.write_all(&socks::request(code, &address, 0)?).await?;
+                    let mut buf = [0u8; 512];
+                    let _ = stream.read(&mut buf).await;
+                    io::Result::Ok(())
+                });
+            }
+        });
+        port
+    }
+
+    fn is_down(pool: &Pool, i: usize) -> bool {
+        pool.slots[i].health().down
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_fails_over() {
+        let broken = fake_proxy(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let good = socks5_proxy(socks::REPLY_SUCCEEDED).await;
+        let pool = Pool::parse(&format!("socks5://127.0.0.1:{},socks5://127.0.0.1:{}", broken, good)).unwrap();
+        let (_, endpoint) = pool.connect("192.0.2.1", 443, &None, &resolver()).await.unwrap();
+        assert_eq!(endpoint.to_string(), format!("socks5://127.0.0.1:{}", good));
+        assert!(is_down(&pool, 0));
+        assert!(!is_down(&pool, 1));
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_fail_over() {
+        let denied = fake_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+        let good = fake_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n").await;
+        let pool = Pool::parse(&format!("http://127.0.0.1:{},http://127.0.0.1:{}", denied, good)).unwrap();
+        let (_, endpoint) = pool.connect("192.0.2.1", 443, &None, &resolver()).await.unwrap();
+        assert_eq!(endpoint.to_string(), format!("http://127.0.0.1:{}", good));
+        assert!(is_down(&pool, 0));
+    }
+
+    #[tokio::test]
+    async fn target_errors_do_not_fail_over() {
+        let refused = socks5_proxy(socks::REPLY_CONNECTION_REFUSED).await;
+        let good = socks5_proxy(socks::REPLY_SUCCEEDED).await;
+        let pool = Pool::parse(&format!("socks5://127.0.0.1:{},socks5://127.0.0.1:{}", refused, good)).unwrap();
+        let Err(err) = pool.connect("192.0.2.1", 443, &None, &resolver()).await else {
+            panic!("connected despite the refusal");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(!is_down(&pool, 0));
+
+        let gateway = fake_proxy(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+        let pool = Pool::parse(&format!("http://127.0.0.1:{},socks5://127.0.0.1:{}", gateway, good)).unwrap();
+        assert!(pool.connect("192.0.2.1", 443, &None, &resolver()).await.is_err());
+        assert!(!is_down(&pool, 0));
+    }
+
+    #[tokio::test]
+    async fn health_check_runs_the_handshake() {
+        let denied = fake_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+        let answering = fake_proxy(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let not_socks = fake_proxy(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let socks = socks5_proxy(socks::REPLY_SUCCEEDED).await;
+        let pool = Pool::parse(&format!(
+            "http://127.0.0.1:{},http://127.0.0.1:{},socks5://127.0.0.1:{},socks5://127.0.0.1:{}",
+            denied, answering, not_socks, socks
+        ))
+        .unwrap();
+        let changes = pool.check(&None, &resolver()).await;
+        assert_eq!(changes.len(), 2);
+        assert!(is_down(&pool, 0));
+        assert!(!is_down(&pool, 1));
+        assert!(is_down(&pool, 2));
+        assert!(!is_down(&pool, 3));
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        let endpoint = Endpoint::parse("socks5h://user:p%40ss@[::1]:1080/").unwrap();
+        assert_eq!(endpoint.to_string(), "socks5://[::1]:1080");
+        assert!(matches!(Endpoint::parse("direct://192.0.2.1"), Ok(Endpoint::Direct(Some(_)))));
+        assert!(Endpoint::parse("direct://example.com").is_err());
+        assert!(Endpoint::parse("ftp://host:21").is_err());
+        assert!(Endpoint::parse("http://host").is_err());
+    }
+}